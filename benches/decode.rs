@@ -0,0 +1,79 @@
+// Per-sample decode cost of XORIterator against XORIterator::next_bitwise,
+// the decoder from before the prefix lookup table, which reads the
+// delta-of-delta prefix one bit at a time.
+//
+//     cargo bench --bench decode
+
+use rust_tsz::xor::XORChunk;
+use std::hint::black_box;
+use std::time::Instant;
+
+const SAMPLES: usize = 120;
+const ROUNDS: usize = 20_000;
+const RUNS: usize = 5;
+
+// bitwise decodes the whole chunk the old way and returns the number of samples.
+fn bitwise(chunk: &XORChunk) -> usize {
+    let mut it = chunk.iterator();
+    let mut n = 0;
+    while it.next_bitwise().is_some() {
+        n += 1;
+    }
+    n
+}
+
+fn table(chunk: &XORChunk) -> usize {
+    chunk.iterator().count()
+}
+
+fn build(jitter: impl Fn(usize) -> i64) -> XORChunk {
+    let mut chunk = XORChunk::new();
+    let mut t = 1_700_000_000_000i64;
+    for i in 0..SAMPLES {
+        t += 15_000 + jitter(i);
+        chunk.append(t, (i % 7) as f64 * 1.5).unwrap();
+    }
+    chunk
+}
+
+fn ns_per_sample(f: impl Fn()) -> f64 {
+    // warm up
+    for _ in 0..ROUNDS / 10 {
+        f();
+    }
+    // the fastest of a few runs, the others are mostly noise from elsewhere
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                f();
+            }
+            start.elapsed().as_nanos() as f64 / (ROUNDS * SAMPLES) as f64
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+fn main() {
+    // A cheap deterministic pseudo random sequence, so runs are comparable.
+    let jittery = |i: usize| ((i as i64 * 7919) % 997) - 498;
+    let cases = [("regular", build(|_| 0)), ("jittery", build(jittery))];
+
+    for (name, chunk) in &cases {
+        assert_eq!(table(chunk), SAMPLES);
+        assert_eq!(bitwise(chunk), SAMPLES);
+
+        let old = ns_per_sample(|| {
+            black_box(bitwise(black_box(chunk)));
+        });
+        let new = ns_per_sample(|| {
+            black_box(table(black_box(chunk)));
+        });
+        println!(
+            "{:<8} bitwise {:>6.2} ns/sample  table {:>6.2} ns/sample  speedup {:.2}x",
+            name,
+            old,
+            new,
+            old / new
+        );
+    }
+}
@@ -0,0 +1,777 @@
+// A block is an immutable directory holding the samples of a time range
+// [min_time, max_time):
+//
+//   <ulid>/meta.json  what the block holds, in the format of Prometheus
+//   <ulid>/chunks/    segment files with the chunks (see segment.rs)
+//   <ulid>/index      the series sorted by labels with the refs and time
+//                     ranges of their chunks
+//   <ulid>/tombstones the deleted samples, if any (see tombstones.rs)
+//
+// The index is
+//
+//   [magic u32][version u8][number of series uvarint]
+//   ([labels, see Labels::encode][number of chunks uvarint]
+//    ([chunk ref uvarint][min_time varint][max_time - min_time uvarint])...)...
+//   [crc32c of everything before u32]
+//
+// The ref of a series in a block is its position in the index.
+use crate::bstream::{Bstream, BstreamReader};
+use crate::crc32;
+use crate::head::{self, Head, SeriesRef};
+use crate::index::{IndexReader, Matcher, MemPostings};
+use crate::json::{self, Value};
+use crate::labels::Labels;
+use crate::segment::{self, ChunkRef, SegmentReader, SegmentWriter};
+use crate::series::{SeriesIterator, DEFAULT_SAMPLES_PER_CHUNK};
+use crate::tombstones::{DeletedIterator, Interval, Tombstones, TOMBSTONES_FILENAME};
+use crate::xor::{self, SampleIterator, SealOptions, XORChunk};
+#[cfg(test)]
+use crate::test_dir;
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::fmt::{self, Write as _};
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const META_FILENAME: &str = "meta.json";
+pub const INDEX_FILENAME: &str = "index";
+pub const CHUNKS_DIRNAME: &str = "chunks";
+
+const INDEX_MAGIC: u32 = 0x54534458;
+const INDEX_V1: u8 = 1;
+const META_V1: i64 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Segment(segment::Error),
+    Chunk(xor::Error),
+    Head(head::Error),
+    InvalidMeta,
+    InvalidIndex,
+    InvalidTombstones,
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<segment::Error> for Error {
+    fn from(err: segment::Error) -> Error {
+        Error::Segment(err)
+    }
+}
+
+impl From<xor::Error> for Error {
+    fn from(err: xor::Error) -> Error {
+        Error::Chunk(err)
+    }
+}
+
+impl From<head::Error> for Error {
+    fn from(err: head::Error) -> Error {
+        Error::Head(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "block: {}", err),
+            Error::Segment(err) => write!(f, "block: {}", err),
+            Error::Chunk(err) => write!(f, "block: {}", err),
+            Error::Head(err) => write!(f, "block: {}", err),
+            Error::InvalidMeta => write!(f, "block: invalid {}", META_FILENAME),
+            Error::InvalidIndex => write!(f, "block: invalid index"),
+            Error::InvalidTombstones => write!(f, "block: invalid {}", TOMBSTONES_FILENAME),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// BlockId is a ULID: a 48 bit millisecond timestamp followed by 80 random
+// bits, written in Crockford's base32. Ids of newer blocks sort after older
+// ones; ids made in the same millisecond by a process are increasing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockId(pub u128);
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+impl BlockId {
+    // new returns a new id for the current time.
+    pub fn new() -> BlockId {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        let mut h = RandomState::new().build_hasher();
+        h.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        let hi = h.finish();
+        h.write_u64(ms);
+        let lo = h.finish();
+        let random = ((hi as u128) << 64 | lo as u128) & ((1 << 80) - 1);
+        let id = ((ms & ((1 << 48) - 1)) as u128) << 80 | random;
+        // as monotonic ULIDs do, take the last id plus one if it is larger
+        static LAST: Mutex<u128> = Mutex::new(0);
+        let mut last = LAST.lock().unwrap();
+        *last = id.max(last.wrapping_add(1));
+        BlockId(*last)
+    }
+
+    // parse parses the 26 characters of a ULID.
+    pub fn parse(s: &str) -> Option<BlockId> {
+        if s.len() != 26 {
+            return None;
+        }
+        let mut v: u128 = 0;
+        for (i, c) in s.bytes().enumerate() {
+            let d = CROCKFORD.iter().position(|x| *x == c.to_ascii_uppercase())? as u128;
+            // the first character only holds 3 bits
+            if i == 0 && d > 7 {
+                return None;
+            }
+            v = v << 5 | d;
+        }
+        Some(BlockId(v))
+    }
+
+    // timestamp returns the time the id was created at in milliseconds.
+    pub fn timestamp(&self) -> u64 {
+        (self.0 >> 80) as u64
+    }
+}
+
+impl Default for BlockId {
+    fn default() -> BlockId {
+        BlockId::new()
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for i in (0..26).rev() {
+            let d = (self.0 >> (i * 5)) & 31;
+            write!(f, "{}", CROCKFORD[d as usize] as char)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockStats {
+    pub num_samples: u64,
+    pub num_series: u64,
+    pub num_chunks: u64,
+}
+
+// BlockCompaction says how a block came about. Blocks written from the head
+// have level 1 and themselves as only source, compacted blocks have a level
+// above the highest of the blocks they were made of and all of their sources.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockCompaction {
+    pub level: u32,
+    pub sources: Vec<BlockId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockMeta {
+    pub id: BlockId,
+    pub min_time: i64,
+    pub max_time: i64, // exclusive
+    pub stats: BlockStats,
+    pub compaction: BlockCompaction,
+}
+
+impl BlockMeta {
+    // to_json returns the meta.json of the block, indented with tabs like
+    // Prometheus writes it.
+    pub(crate) fn to_json(&self) -> String {
+        let string = |s: &str| {
+            let mut out = String::new();
+            json::write_string(&mut out, s);
+            out
+        };
+        let sources: Vec<_> = self.compaction.sources.iter().map(|id| format!("\n\t\t\t{}", string(&id.to_string()))).collect();
+        let mut out = String::new();
+        let _ = writeln!(out, "{{");
+        let _ = writeln!(out, "\t\"ulid\": {},", string(&self.id.to_string()));
+        let _ = writeln!(out, "\t\"minTime\": {},", self.min_time);
+        let _ = writeln!(out, "\t\"maxTime\": {},", self.max_time);
+        let _ = writeln!(out, "\t\"stats\": {{");
+        let _ = writeln!(out, "\t\t\"numSamples\": {},", self.stats.num_samples);
+        let _ = writeln!(out, "\t\t\"numSeries\": {},", self.stats.num_series);
+        let _ = writeln!(out, "\t\t\"numChunks\": {}", self.stats.num_chunks);
+        let _ = writeln!(out, "\t}},");
+        let _ = writeln!(out, "\t\"compaction\": {{");
+        let _ = writeln!(out, "\t\t\"level\": {},", self.compaction.level);
+        if sources.is_empty() {
+            let _ = writeln!(out, "\t\t\"sources\": []");
+        } else {
+            let _ = writeln!(out, "\t\t\"sources\": [{}\n\t\t]", sources.join(","));
+        }
+        let _ = writeln!(out, "\t}},");
+        let _ = writeln!(out, "\t\"version\": {}", META_V1);
+        let _ = writeln!(out, "}}");
+        out
+    }
+
+    fn from_json(s: &str) -> Option<BlockMeta> {
+        let v = json::parse(s)?;
+        if v.get("version")?.as_i64()? != META_V1 {
+            return None;
+        }
+        let stats = v.get("stats")?;
+        let compaction = v.get("compaction")?;
+        let count = |k: &str| stats.get(k).and_then(Value::as_u64).unwrap_or(0);
+        Some(BlockMeta {
+            id: BlockId::parse(v.get("ulid")?.as_str()?)?,
+            min_time: v.get("minTime")?.as_i64()?,
+            max_time: v.get("maxTime")?.as_i64()?,
+            stats: BlockStats {
+                num_samples: count("numSamples"),
+                num_series: count("numSeries"),
+                num_chunks: count("numChunks"),
+            },
+            compaction: BlockCompaction {
+                level: u32::try_from(compaction.get("level")?.as_u64()?).ok()?,
+                sources: compaction
+                    .get("sources")
+                    .and_then(Value::as_array)
+                    .unwrap_or(&[])
+                    .iter()
+                    .map(|s| BlockId::parse(s.as_str()?))
+                    .collect::<Option<_>>()?,
+            },
+        })
+    }
+}
+
+// read_meta reads the meta.json of the block in dir.
+pub fn read_meta(dir: impl AsRef<Path>) -> Result<BlockMeta, Error> {
+    let s = fs::read_to_string(dir.as_ref().join(META_FILENAME))?;
+    BlockMeta::from_json(&s).ok_or(Error::InvalidMeta)
+}
+
+// list_blocks returns the blocks in dir with their meta, sorted by min_time.
+// Directories that aren't named after a block id are skipped.
+pub fn list_blocks(dir: impl AsRef<Path>) -> Result<Vec<(PathBuf, BlockMeta)>, Error> {
+    let mut blocks = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let is_block = entry.file_name().to_str().and_then(BlockId::parse).is_some();
+        if is_block && entry.file_type()?.is_dir() {
+            let meta = read_meta(entry.path())?;
+            blocks.push((entry.path(), meta));
+        }
+    }
+    blocks.sort_by_key(|(_, m)| (m.min_time, m.id));
+    Ok(blocks)
+}
+
+// ChunkEntry is what the index knows about a chunk of a series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkEntry {
+    pub chunk_ref: ChunkRef,
+    pub min_time: i64,
+    pub max_time: i64,
+}
+
+type IndexSeries = (Labels, Vec<ChunkEntry>);
+
+fn encode_index(series: &[IndexSeries]) -> Vec<u8> {
+    let mut b = Bstream::new(Vec::new());
+    b.write_aligned_bytes(&INDEX_MAGIC.to_be_bytes());
+    b.write_aligned_bytes(&[INDEX_V1]);
+    b.write_uvarint(series.len() as u64);
+    for (labels, chunks) in series {
+        labels.encode(&mut b);
+        b.write_uvarint(chunks.len() as u64);
+        for c in chunks {
+            b.write_uvarint(c.chunk_ref.0);
+            b.write_varint(c.min_time);
+            b.write_uvarint(c.max_time.wrapping_sub(c.min_time) as u64);
+        }
+    }
+    b.pad_to_byte();
+    let crc = crc32::checksum(b.read_bytes());
+    b.write_aligned_bytes(&crc.to_be_bytes());
+    b.read_bytes().clone()
+}
+
+fn decode_index(b: &[u8]) -> Option<Vec<IndexSeries>> {
+    let (b, crc) = b.split_at(b.len().checked_sub(4)?);
+    if b.len() < 5 || crc32::checksum(b) != u32::from_be_bytes(crc.try_into().ok()?) {
+        return None;
+    }
+    if u32::from_be_bytes(b[..4].try_into().ok()?) != INDEX_MAGIC || b[4] != INDEX_V1 {
+        return None;
+    }
+    let mut br = BstreamReader::new(b);
+    br.seek(5 * 8).ok()?;
+    let n = br.read_uvarint().ok()?;
+    let mut series = Vec::new();
+    for _ in 0..n {
+        let labels = Labels::decode(&mut br, b)?;
+        let nchunks = br.read_uvarint().ok()?;
+        let mut chunks = Vec::new();
+        for _ in 0..nchunks {
+            let chunk_ref = ChunkRef(br.read_uvarint().ok()?);
+            let min_time = br.read_varint().ok()?;
+            let max_time = min_time.wrapping_add(br.read_uvarint().ok()? as i64);
+            chunks.push(ChunkEntry { chunk_ref, min_time, max_time });
+        }
+        series.push((labels, chunks));
+    }
+    Some(series)
+}
+
+// fsync_dir syncs a directory, which makes renames and new files in it durable.
+fn fsync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+// BlockBuilder writes a block into <dir>/<ulid>.tmp and moves it to
+// <dir>/<ulid> when it is finished, so a crash never leaves a partial block
+// behind under a block name. Dropping an unfinished builder removes it.
+pub(crate) struct BlockBuilder {
+    dir: PathBuf,
+    tmp: PathBuf,
+    meta: BlockMeta,
+    chunks: Option<SegmentWriter>,
+    series: Vec<IndexSeries>,
+    samples_per_chunk: u16,
+    seal: Option<SealOptions>,
+}
+
+impl BlockBuilder {
+    // new starts a block. With seal options its chunks are sealed and keep
+    // their footer, see SegmentWriter::with_footers.
+    pub(crate) fn new(dir: &Path, min_time: i64, max_time: i64, samples_per_chunk: u16, seal: Option<SealOptions>) -> Result<BlockBuilder, Error> {
+        let id = BlockId::new();
+        let tmp = dir.join(format!("{}.tmp", id));
+        let chunks = SegmentWriter::new(tmp.join(CHUNKS_DIRNAME))?.with_footers(seal.is_some());
+        Ok(BlockBuilder {
+            dir: dir.to_path_buf(),
+            tmp,
+            meta: BlockMeta {
+                id,
+                min_time,
+                max_time,
+                stats: BlockStats::default(),
+                compaction: BlockCompaction { level: 1, sources: vec![id] },
+            },
+            chunks: Some(chunks),
+            series: Vec::new(),
+            samples_per_chunk: samples_per_chunk.max(1),
+            seal,
+        })
+    }
+
+    pub(crate) fn num_series(&self) -> usize {
+        self.series.len()
+    }
+
+    // add_series writes the samples of a series, sorted by time, into chunks
+    // of up to samples_per_chunk samples. Series without samples are left out.
+    pub(crate) fn add_series(&mut self, labels: &Labels, samples: impl Iterator<Item = (i64, f64)>) -> Result<(), Error> {
+        let mut entries = Vec::new();
+        let mut c = XORChunk::new();
+        for (t, v) in samples {
+            if c.num_samples() >= self.samples_per_chunk {
+                entries.push(self.write_chunk(&mut c)?);
+                c = XORChunk::new();
+            }
+            c.append(t, v)?;
+            self.meta.stats.num_samples += 1;
+        }
+        if c.num_samples() > 0 {
+            entries.push(self.write_chunk(&mut c)?);
+        }
+        if !entries.is_empty() {
+            self.series.push((labels.clone(), entries));
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, c: &mut XORChunk) -> Result<ChunkEntry, Error> {
+        if let Some(opts) = self.seal {
+            c.seal(opts)?;
+        }
+        let chunk_ref = self.chunks.as_mut().unwrap().write_chunk(c)?;
+        self.meta.stats.num_chunks += 1;
+        Ok(ChunkEntry {
+            chunk_ref,
+            min_time: c.min_time(),
+            max_time: c.max_time(),
+        })
+    }
+
+    // finish writes the index and meta.json and moves the block in place.
+    pub(crate) fn finish(mut self, compaction: Option<BlockCompaction>) -> Result<BlockMeta, Error> {
+        if let Some(compaction) = compaction {
+            self.meta.compaction = compaction;
+        }
+        self.chunks.take().unwrap().close()?;
+        self.series.sort_by(|a, b| a.0.cmp(&b.0));
+        self.meta.stats.num_series = self.series.len() as u64;
+
+        let index = File::create(self.tmp.join(INDEX_FILENAME))?;
+        io::Write::write_all(&mut &index, &encode_index(&self.series))?;
+        index.sync_all()?;
+        let meta = File::create(self.tmp.join(META_FILENAME))?;
+        io::Write::write_all(&mut &meta, self.meta.to_json().as_bytes())?;
+        meta.sync_all()?;
+        fsync_dir(&self.tmp)?;
+
+        fs::rename(&self.tmp, self.dir.join(self.meta.id.to_string()))?;
+        fsync_dir(&self.dir)?;
+        Ok(self.meta.clone())
+    }
+}
+
+impl Drop for BlockBuilder {
+    fn drop(&mut self) {
+        if self.tmp.exists() {
+            let _ = fs::remove_dir_all(&self.tmp);
+        }
+    }
+}
+
+// BlockWriter writes blocks into a directory.
+pub struct BlockWriter {
+    dir: PathBuf,
+    samples_per_chunk: u16,
+    seal: Option<SealOptions>,
+}
+
+impl BlockWriter {
+    pub fn new(dir: impl AsRef<Path>) -> BlockWriter {
+        BlockWriter {
+            dir: dir.as_ref().to_path_buf(),
+            samples_per_chunk: DEFAULT_SAMPLES_PER_CHUNK,
+            seal: None,
+        }
+    }
+
+    pub fn with_samples_per_chunk(mut self, samples_per_chunk: u16) -> BlockWriter {
+        self.samples_per_chunk = samples_per_chunk;
+        self
+    }
+
+    // with_seal_options seals the chunks of the blocks with opts and keeps
+    // their footers, which Prometheus can't read.
+    pub fn with_seal_options(mut self, opts: SealOptions) -> BlockWriter {
+        self.seal = Some(opts);
+        self
+    }
+
+    // write_head writes the samples of the head with mint <= t < maxt into a
+    // new block. It returns None without writing anything if there are none.
+    pub fn write_head(&self, head: &Head, mint: i64, maxt: i64) -> Result<Option<BlockMeta>, Error> {
+        fs::create_dir_all(&self.dir)?;
+        let mut b = BlockBuilder::new(&self.dir, mint, maxt, self.samples_per_chunk, self.seal)?;
+        let mut samples = Vec::new();
+        for s in head.all_series() {
+            if s.max_time() < mint || s.min_time() >= maxt {
+                continue;
+            }
+            let snap = s.snapshot()?;
+            samples.clear();
+            // merges the out of order samples in
+            let mut it = snap.iter_range(mint, maxt - 1);
+            samples.extend(&mut it);
+            if let Some(err) = it.err() {
+                return Err(err.clone().into());
+            }
+            b.add_series(s.labels(), samples.iter().copied())?;
+        }
+        if b.num_series() == 0 {
+            return Ok(None);
+        }
+        b.finish(None).map(Some)
+    }
+}
+
+// BlockReader reads a block. The index and the tombstones are loaded into
+// memory and the label index is built from them, chunks are read from the
+// segments when they are needed.
+pub struct BlockReader {
+    dir: PathBuf,
+    meta: BlockMeta,
+    series: Vec<IndexSeries>,
+    postings: MemPostings,
+    tombstones: Tombstones,
+    chunks: SegmentReader,
+}
+
+impl BlockReader {
+    pub fn open(dir: impl AsRef<Path>) -> Result<BlockReader, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        let meta = read_meta(&dir)?;
+        let series = decode_index(&fs::read(dir.join(INDEX_FILENAME))?).ok_or(Error::InvalidIndex)?;
+        let tombstones = match fs::read(dir.join(TOMBSTONES_FILENAME)) {
+            Ok(b) => Tombstones::decode(&b).ok_or(Error::InvalidTombstones)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Tombstones::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let chunks = SegmentReader::open(dir.join(CHUNKS_DIRNAME))?;
+        let mut postings = MemPostings::new();
+        for (i, (labels, _)) in series.iter().enumerate() {
+            postings.add(SeriesRef(i as u64), labels);
+        }
+        Ok(BlockReader { dir, meta, series, postings, tombstones, chunks })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn meta(&self) -> &BlockMeta {
+        &self.meta
+    }
+
+    pub fn num_series(&self) -> usize {
+        self.series.len()
+    }
+
+    // series returns the labels and chunks of a series.
+    pub fn series(&self, sref: SeriesRef) -> Option<(&Labels, &[ChunkEntry])> {
+        self.series.get(sref.0 as usize).map(|(l, c)| (l, c.as_slice()))
+    }
+
+    pub fn series_by_labels(&self, labels: &Labels) -> Option<SeriesRef> {
+        self.series
+            .binary_search_by(|(l, _)| l.cmp(labels))
+            .ok()
+            .map(|i| SeriesRef(i as u64))
+    }
+
+    // series_refs returns the refs of all series, sorted by their labels.
+    pub fn series_refs(&self) -> impl Iterator<Item = SeriesRef> {
+        (0..self.series.len() as u64).map(SeriesRef)
+    }
+
+    // chunk_bytes returns the data of a chunk, see SegmentReader::chunk_bytes.
+    pub fn chunk_bytes(&self, r: ChunkRef) -> Result<Cow<'_, [u8]>, Error> {
+        Ok(self.chunks.chunk_bytes(r)?)
+    }
+
+    pub fn tombstones(&self) -> &Tombstones {
+        &self.tombstones
+    }
+
+    // series_chunks loads the chunks of a series that overlap [mint, maxt].
+    // Chunks that are deleted as a whole are left out, their iterator skips
+    // the other deleted samples.
+    pub fn series_chunks(&self, sref: SeriesRef, mint: i64, maxt: i64) -> Result<SeriesChunks<'_>, Error> {
+        let (_, entries) = self.series(sref).ok_or(Error::Head(head::Error::UnknownSeries(sref)))?;
+        let deleted = self.tombstones.get(sref);
+        let chunks = entries
+            .iter()
+            .filter(|c| c.max_time >= mint && c.min_time <= maxt)
+            .filter(|c| !deleted.iter().any(|iv| iv.contains(c.min_time) && iv.contains(c.max_time)))
+            .map(|c| self.chunk_bytes(c.chunk_ref))
+            .collect::<Result<_, _>>()?;
+        Ok(SeriesChunks { chunks, deleted })
+    }
+
+    // delete marks the samples in [mint, maxt] of the series the matchers
+    // select as deleted and writes the tombstones of the block. It returns
+    // the number of series that had samples in the range.
+    pub fn delete(&mut self, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<usize, Error> {
+        let mut tombstones = self.tombstones.clone();
+        let mut n = 0;
+        for sref in self.select(matchers) {
+            let (_, entries) = self.series(sref).unwrap();
+            if entries.iter().any(|c| c.max_time >= mint && c.min_time <= maxt) {
+                tombstones.add(sref, Interval::new(mint, maxt));
+                n += 1;
+            }
+        }
+        if n == 0 {
+            return Ok(0);
+        }
+        // written aside and moved in place, a crash keeps the old tombstones
+        let tmp = self.dir.join(format!("{}.tmp", TOMBSTONES_FILENAME));
+        let f = File::create(&tmp)?;
+        io::Write::write_all(&mut &f, &tombstones.encode())?;
+        f.sync_all()?;
+        fs::rename(&tmp, self.dir.join(TOMBSTONES_FILENAME))?;
+        fsync_dir(&self.dir)?;
+        self.tombstones = tombstones;
+        Ok(n)
+    }
+}
+
+impl IndexReader for BlockReader {
+    fn postings(&self, name: &str, value: &str) -> Vec<SeriesRef> {
+        self.postings.postings(name, value)
+    }
+
+    fn all_postings(&self) -> Vec<SeriesRef> {
+        self.postings.all_postings()
+    }
+
+    fn label_names(&self) -> Vec<String> {
+        self.postings.label_names()
+    }
+
+    fn label_values(&self, name: &str) -> Vec<String> {
+        self.postings.label_values(name)
+    }
+}
+
+// SeriesChunks are the chunks of a series loaded from a block.
+pub struct SeriesChunks<'a> {
+    chunks: Vec<Cow<'a, [u8]>>,
+    deleted: &'a [Interval],
+}
+
+impl SeriesChunks<'_> {
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    // iter iterates over the samples that aren't deleted.
+    pub fn iter(&self) -> DeletedIterator<'_, SeriesIterator<'_>> {
+        let it = SeriesIterator::new(self.chunks.iter().map(|c| c.as_ref()).collect());
+        DeletedIterator::new(it, self.deleted)
+    }
+}
+
+#[test]
+fn test_block_id() {
+    let id = BlockId::new();
+    let s = id.to_string();
+    assert_eq!(26, s.len());
+    assert_eq!(Some(id), BlockId::parse(&s));
+    assert_eq!(Some(id), BlockId::parse(&s.to_lowercase()));
+    assert!(id.timestamp() > 1_600_000_000_000);
+    assert!(id < BlockId::new());
+    assert_eq!(Some(BlockId(u128::MAX)), BlockId::parse("7ZZZZZZZZZZZZZZZZZZZZZZZZZ"));
+    assert_eq!(None, BlockId::parse("8ZZZZZZZZZZZZZZZZZZZZZZZZZ"));
+    assert_eq!(None, BlockId::parse("01ARZ3NDEKTSV4RRFFQ69G5FAU"));
+}
+
+#[test]
+fn test_block_meta_json() {
+    let meta = BlockMeta {
+        id: BlockId::parse("01ARZ3NDEKTSV4RRFFQ69G5FAV").unwrap(),
+        min_time: -5,
+        max_time: i64::MAX,
+        stats: BlockStats { num_samples: 10, num_series: 2, num_chunks: 3 },
+        compaction: BlockCompaction {
+            level: 2,
+            sources: vec![BlockId::parse("01ARZ3NDEKTSV4RRFFQ69G5FAV").unwrap(), BlockId(7)],
+        },
+    };
+    let s = meta.to_json();
+    assert!(s.starts_with("{\n\t\"ulid\": \"01ARZ3NDEKTSV4RRFFQ69G5FAV\",\n\t\"minTime\": -5,"));
+    assert!(s.contains("\t\t\"sources\": [\n\t\t\t\"01ARZ3NDEKTSV4RRFFQ69G5FAV\",\n\t\t\t\"00000000000000000000000007\"\n\t\t]\n"));
+    assert_eq!(Some(meta.clone()), BlockMeta::from_json(&s));
+    assert_eq!(None, BlockMeta::from_json(&s.replace("\"version\": 1", "\"version\": 2")));
+
+    // integers at the ends of their ranges
+    let big = BlockMeta {
+        min_time: i64::MIN,
+        stats: BlockStats { num_samples: u64::MAX, num_series: u64::MAX - 1, num_chunks: 0 },
+        compaction: BlockCompaction { level: u32::MAX, sources: Vec::new() },
+        ..meta
+    };
+    let s = big.to_json();
+    assert!(s.contains("\"sources\": []\n"));
+    assert_eq!(Some(big), BlockMeta::from_json(&s));
+
+    // fields other tools write are skipped
+    let prom = r#"{"ulid": "01ARZ3NDEKTSV4RRFFQ69G5FAV", "minTime": 0, "maxTime": 7200000,
+        "stats": {"numSamples": 1, "numSeries": 1, "numChunks": 1, "numTombstones": 0},
+        "compaction": {"level": 1, "sources": ["01ARZ3NDEKTSV4RRFFQ69G5FAV"], "deletable": true, "parents": null},
+        "thanos": {"labels": {"a\"b": "c\\d"}}, "version": 1}"#;
+    assert_eq!(1, BlockMeta::from_json(prom).unwrap().stats.num_series);
+}
+
+#[test]
+fn test_block_write_read() {
+    let dir = test_dir("block");
+    let head = Head::new();
+    let series: Vec<_> = (0..5).map(|i| Labels::from_pairs(&[("__name__", "m"), ("i", &i.to_string())])).collect();
+    for t in 0..1000 {
+        for (i, ls) in series.iter().enumerate() {
+            // series i only has samples from 100 * i on
+            if t >= 100 * i as i64 {
+                head.append(ls, t * 10, (t * i as i64) as f64).unwrap();
+            }
+        }
+    }
+
+    let w = BlockWriter::new(&dir).with_samples_per_chunk(50);
+    assert_eq!(None, w.write_head(&head, 20_000, 30_000).unwrap());
+    let meta = w.write_head(&head, 2000, 5000).unwrap().unwrap();
+    assert_eq!((2000, 5000), (meta.min_time, meta.max_time));
+    assert_eq!(BlockStats { num_samples: 300 * 3 + 200 + 100, num_series: 5, num_chunks: 6 * 3 + 4 + 2 }, meta.stats);
+    assert_eq!(BlockCompaction { level: 1, sources: vec![meta.id] }, meta.compaction);
+    // only the finished block is left
+    let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(vec![std::ffi::OsString::from(meta.id.to_string())], names);
+
+    let r = BlockReader::open(dir.join(meta.id.to_string())).unwrap();
+    assert_eq!(&meta, r.meta());
+    assert_eq!(5, r.num_series());
+    for (i, ls) in series.iter().enumerate() {
+        let sref = r.series_by_labels(ls).unwrap();
+        let (got_ls, entries) = r.series(sref).unwrap();
+        assert_eq!(ls, got_ls);
+        assert!(entries.windows(2).all(|w| w[0].max_time < w[1].min_time));
+
+        let chunks = r.series_chunks(sref, i64::MIN, i64::MAX).unwrap();
+        let mut it = chunks.iter();
+        let got: Vec<_> = (&mut it).collect();
+        assert!(it.err().is_none());
+        let want: Vec<_> = (200.max(100 * i as i64)..500).map(|t| (t * 10, (t * i as i64) as f64)).collect();
+        assert_eq!(want, got);
+        // a time range only loads the chunks overlapping it
+        assert_eq!(1, r.series_chunks(sref, 4990, 6000).unwrap().len());
+    }
+    assert_eq!(None, r.series_by_labels(&Labels::from_pairs(&[("__name__", "other")])));
+    use crate::index::{MatchType, Matcher};
+    let sel = r.select(&[Matcher::new(MatchType::Regex, "i", "[13]").unwrap()]);
+    assert_eq!(vec![&series[1], &series[3]], sel.iter().map(|r2| r.series(*r2).unwrap().0).collect::<Vec<_>>());
+
+    // sealed chunks keep their footer on disk
+    let w = BlockWriter::new(&dir).with_samples_per_chunk(50).with_seal_options(SealOptions { index_interval: Some(10), aggregates: true });
+    let sealed = BlockReader::open(dir.join(w.write_head(&head, 2000, 5000).unwrap().unwrap().id.to_string())).unwrap();
+    for ls in &series {
+        let (sref, sealed_ref) = (r.series_by_labels(ls).unwrap(), sealed.series_by_labels(ls).unwrap());
+        let want: Vec<_> = r.series_chunks(sref, i64::MIN, i64::MAX).unwrap().iter().collect();
+        let got: Vec<_> = sealed.series_chunks(sealed_ref, i64::MIN, i64::MAX).unwrap().iter().collect();
+        assert_eq!(want, got);
+        for e in sealed.series(sealed_ref).unwrap().1 {
+            let c = xor::ChunkView::new(sealed.chunk_bytes(e.chunk_ref).unwrap()).unwrap();
+            assert!(c.footer().unwrap().aggregates.is_some());
+            let meta = c.meta().unwrap();
+            assert_eq!((e.min_time, e.max_time), (meta.min_time, meta.max_time));
+        }
+    }
+
+    // a broken index is detected
+    let index = r.dir().join(INDEX_FILENAME);
+    let mut b = fs::read(&index).unwrap();
+    b[10] ^= 1;
+    fs::write(&index, b).unwrap();
+    assert!(matches!(BlockReader::open(r.dir()), Err(Error::InvalidIndex)));
+    fs::remove_dir_all(&dir).unwrap();
+}
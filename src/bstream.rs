@@ -1,7 +1,12 @@
-use std::io;
-
+use alloc::vec::Vec;
+#[cfg(test)]
+use alloc::vec;
+#[cfg(test)]
+use std::println;
+use core::fmt;
 
 // Bstream is a stream of bits
+#[derive(Clone)]
 pub struct Bstream {
     stream : Vec<u8>, // data stream
     count: u8 // how many right-most bits are available for writing in the current byte
@@ -9,7 +14,9 @@ pub struct Bstream {
 
 type Bit = bool;
 
+#[cfg(test)]
 const BIT_ONE: Bit = true;
+#[cfg(test)]
 const BIT_ZERO: Bit = false;
 const MAX_VARINT_LEN64: usize = 10;
 
@@ -22,6 +29,26 @@ impl Bstream {
         }
     }
     
+    // from_bits continues a stream of which the first nbits bits are written.
+    // Anything after them is dropped.
+    pub fn from_bits(mut stream:Vec<u8>, nbits:usize) -> Bstream {
+        let len = nbits.div_ceil(8);
+        stream.resize(len, 0);
+        let count = (len*8 - nbits) as u8;
+        if count > 0 {
+            stream[len-1] &= !((1u8 << count) - 1);
+        }
+        Bstream {
+            stream,
+            count
+        }
+    }
+
+    // bit_len returns the number of bits written to the stream.
+    pub fn bit_len(&self) -> usize {
+        self.stream.len()*8 - self.count as usize
+    }
+
     // this is some particial method for xor chunk to update the chunk header
     // maybe this is not a good way to do this
     pub fn modify_first_two_bytes(&mut self,byt1:u8,byt2:u8) {
@@ -76,11 +103,11 @@ impl Bstream {
     // write_bits writes the nbits right-most bits of u to the stream in left-to-right order.
     pub fn write_bits(&mut self,mut u:u64, mut nbits:i32) {
         //let mut nbits = nbits as u8;
-        u = u << (64 - nbits);
+        u <<= 64 - nbits;
         while nbits >= 8 {
             let byt = (u >> 56) as u8;
             self.write_byte(byt);
-            u = u << 8;
+            u <<= 8;
             nbits -= 8;
             
         }
@@ -91,7 +118,7 @@ impl Bstream {
             //println!("{:08b}",u);
             let bit = u >> 63;
             self.write_bit(bit == 1);
-            u = u << 1;
+            u <<= 1;
             nbits -=1;
         }
     }
@@ -100,7 +127,7 @@ impl Bstream {
         while u >= 0x80 {
             let byte = u as u8 | 0x80;
             self.write_byte(byte);
-            u = u >> 7;
+            u >>= 7;
         }
         self.write_byte(u as u8)
     }
@@ -112,9 +139,25 @@ impl Bstream {
         }
         self.write_uvarint(ui)
     }
+
+    // pad_to_byte fills the rest of the current byte with zeros, so the next
+    // write starts on a byte boundary.
+    pub fn pad_to_byte(&mut self) {
+        // write_byte leaves a fully unused byte behind, drop it
+        if self.count == 8 {
+            self.stream.pop();
+        }
+        self.count = 0;
+    }
+
+    // write_aligned_bytes pads the stream to a byte boundary and appends bytes as they are.
+    pub fn write_aligned_bytes(&mut self,bytes:&[u8]) {
+        self.pad_to_byte();
+        self.stream.extend_from_slice(bytes);
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BstreamReader<'a> {
     stream : &'a [u8],
     stream_offset: usize,
@@ -123,9 +166,32 @@ pub struct BstreamReader<'a> {
     valid: u8,
 }
 
-// enum BstreamError {
+// BstreamError is returned by BstreamReader when the stream can't satisfy a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BstreamError {
+    UnexpectedEof,
+}
+
+impl fmt::Display for BstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BstreamError::UnexpectedEof => write!(f, "unexpected end of bit stream"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BstreamError {}
+
+#[cfg(feature = "std")]
+impl From<BstreamError> for std::io::Error {
+    fn from(err: BstreamError) -> std::io::Error {
+        match err {
+            BstreamError::UnexpectedEof => std::io::Error::new(std::io::ErrorKind::UnexpectedEof, err),
+        }
+    }
+}
 
-// }
 impl<'a> BstreamReader<'a>{
     pub fn new(stream: &'a [u8]) -> BstreamReader<'a> {
         BstreamReader {
@@ -136,18 +202,44 @@ impl<'a> BstreamReader<'a>{
         }
     }
 
-    pub fn read_bit(&mut self) -> Result<Bit,io::Error> {
-        if self.valid == 0 {
-            if !self.load_next_buffer(1){
-                return Err(io::Error::from(io::ErrorKind::UnexpectedEof))
-            }
+    // reset makes the reader start over on another stream.
+    pub fn reset(&mut self, stream: &'a [u8]) {
+        self.stream = stream;
+        self.stream_offset = 0;
+        self.buffer = 0;
+        self.valid = 0;
+    }
+
+    // position returns the number of bits consumed from the start of the stream.
+    pub fn position(&self) -> usize {
+        self.stream_offset*8 - self.valid as usize
+    }
+
+    // seek positions the reader pos bits from the start of the stream.
+    pub fn seek(&mut self,pos:usize) -> Result<(),BstreamError> {
+        if pos > self.stream.len()*8 {
+            return Err(BstreamError::UnexpectedEof)
+        }
+        self.stream_offset = pos/8;
+        self.buffer = 0;
+        self.valid = 0;
+        let skip = (pos%8) as u8;
+        if skip > 0 {
+            self.read_bits(skip)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_bit(&mut self) -> Result<Bit,BstreamError> {
+        if self.valid == 0 && !self.load_next_buffer(1) {
+            return Err(BstreamError::UnexpectedEof)
         }
-        return self.read_bit_fast()
+        self.read_bit_fast()
     }
 
-    pub fn read_bit_fast(&mut self) -> Result<Bit,io::Error> {
+    pub fn read_bit_fast(&mut self) -> Result<Bit,BstreamError> {
         if self.valid == 0 {
-            return Err(io::Error::from(io::ErrorKind::UnexpectedEof)) // todo: maybe use a custom error
+            return Err(BstreamError::UnexpectedEof)
         }
         self.valid -=1;
         let bitmask = 1 << self.valid;
@@ -155,11 +247,9 @@ impl<'a> BstreamReader<'a>{
         Ok(bit)
     }
     
-    pub fn read_bits(&mut self,mut nbits:u8) -> Result<u64,io::Error> {
-        if self.valid == 0 {
-            if !self.load_next_buffer(nbits) {
-                return Err(io::Error::from(io::ErrorKind::UnexpectedEof))
-            }
+    pub fn read_bits(&mut self,mut nbits:u8) -> Result<u64,BstreamError> {
+        if self.valid == 0 && !self.load_next_buffer(nbits) {
+            return Err(BstreamError::UnexpectedEof)
         }
         if nbits <= self.valid {
             return self.read_bits_fast(nbits)
@@ -171,25 +261,48 @@ impl<'a> BstreamReader<'a>{
         self.valid = 0;
 
         if !self.load_next_buffer(nbits) {
-            return Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+            return Err(BstreamError::UnexpectedEof)
         }
         bitmask = (1 << nbits) -1;
-        v = v | ((self.buffer >> (self.valid - nbits)) & bitmask);
+        v |= (self.buffer >> (self.valid - nbits)) & bitmask;
         self.valid -=nbits;
         Ok(v)
     }
 
-    pub fn read_bits_fast(&mut self,nbits:u8) -> Result<u64,io::Error> {
+    pub fn read_bits_fast(&mut self,nbits:u8) -> Result<u64,BstreamError> {
         if nbits > self.valid { 
-            return Err(io::Error::from(io::ErrorKind::UnexpectedEof)) 
+            return Err(BstreamError::UnexpectedEof) 
         }
-        let bitmask = (1 << nbits) -1;
+        let bitmask = bitmask(nbits);
         self.valid -=nbits;
         Ok((self.buffer >> self.valid) & bitmask)
 
     }
 
-    pub fn read_byte(&mut self) -> Result<u8,io::Error> {
+    // peek_bits returns the next nbits (at most 56) without consuming them.
+    // It fails with UnexpectedEof if fewer than nbits bits are left in the stream.
+    pub fn peek_bits(&self,nbits:u8) -> Result<u64,BstreamError> {
+        debug_assert!(nbits <= 56);
+        if nbits <= self.valid {
+            return Ok((self.buffer >> (self.valid - nbits)) & bitmask(nbits))
+        }
+        // Take what is left in the buffer and top it up with the next bytes of
+        // the stream, without touching the reader state.
+        let mut v = self.buffer & bitmask(self.valid);
+        let mut have = self.valid;
+        let mut offset = self.stream_offset;
+        while have < nbits {
+            if offset >= self.stream.len() {
+                return Err(BstreamError::UnexpectedEof)
+            }
+            v = (v << 8) | u64::from(self.stream[offset]);
+            offset += 1;
+            have += 8;
+        }
+        Ok((v >> (have - nbits)) & bitmask(nbits))
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8,BstreamError> {
         match self.read_bits(8){
             Ok(bits) => Ok(bits as u8),
             Err(e) => Err(e)
@@ -218,17 +331,17 @@ impl<'a> BstreamReader<'a>{
         }
         let mut buffer:u64 = 0;
         for i in 0..n_bytes {
-            buffer = buffer | (u64::from(self.stream[self.stream_offset+i]) << (8*(n_bytes-i-1)));
+            buffer |= u64::from(self.stream[self.stream_offset+i]) << (8*(n_bytes-i-1));
         }
         self.buffer = buffer;
         self.stream_offset += n_bytes;
         self.valid = 8*n_bytes as u8;
         
-        return true
+        true
     }
 
 
-    pub fn read_uvarint(&mut self) -> Result<u64,io::Error> {
+    pub fn read_uvarint(&mut self) -> Result<u64,BstreamError> {
         let mut x:u64 = 0;
         let mut s:usize = 0;
         let mut i:usize = 0;
@@ -248,7 +361,7 @@ impl<'a> BstreamReader<'a>{
                         return Ok(x | (u64::from(byte) << s));
                     }
                     i+=1;
-                    x = x | (u64::from(byte & 0x7f) << s);
+                    x |= u64::from(byte & 0x7f) << s;
                     s += 7;
                 }
                 Err(e) => {
@@ -258,13 +371,13 @@ impl<'a> BstreamReader<'a>{
         }
     }
 
-    pub fn read_varint(&mut self) -> Result<i64,io::Error> {
+    pub fn read_varint(&mut self) -> Result<i64,BstreamError> {
         let ux = self.read_uvarint()?;
         let mut x = (ux >> 1) as i64;
         if ux & 1 != 0 {
             x = !x
         }
-        return Ok(x);
+        Ok(x)
     }
 }
 
@@ -272,6 +385,7 @@ impl<'a> BstreamReader<'a>{
 
 
 #[test]
+#[allow(clippy::useless_vec)]
 fn test_bstream() {
     let mut bstream = Bstream {
         stream: vec![],
@@ -353,6 +467,16 @@ fn test_bstream() {
     }
 }
 
+// bitmask returns a mask of the nbits right-most bits.
+fn bitmask(nbits:u8) -> u64 {
+    if nbits >= 64 {
+        return u64::MAX
+    }
+    (1 << nbits) - 1
+}
+
+#[cfg(test)]
+#[allow(non_upper_case_globals, clippy::precedence)]
 static cases : [i64;17] = [
     -1 << 63,
     // -1 << 63 +1,
@@ -411,4 +535,53 @@ fn test_varint() {
         let actual = r.read_varint().unwrap();
         assert_eq!(*v,actual);
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_peek_bits() {
+    let mut bstream = Bstream::new(vec![]);
+    bstream.write_bits(0b1011, 4);
+    bstream.write_bits(0x3ff, 10);
+    bstream.write_bits(0b1, 1);
+
+    let mut r = BstreamReader::new(bstream.bytes());
+    // peeking does not consume anything
+    assert_eq!(0b1011, r.peek_bits(4).unwrap());
+    assert_eq!(0b1011, r.peek_bits(4).unwrap());
+    assert_eq!(0b1011, r.read_bits(4).unwrap());
+
+    // peek across the buffer refill
+    assert_eq!(0x3ff, r.peek_bits(10).unwrap());
+    assert_eq!(0x3ff, r.read_bits(10).unwrap());
+    assert_eq!(0b1, r.peek_bits(1).unwrap());
+    assert!(r.read_bit().unwrap());
+
+    // only the padding of the last byte is left
+    assert_eq!(0, r.peek_bits(1).unwrap());
+    assert_eq!(Err(BstreamError::UnexpectedEof), r.peek_bits(9));
+}
+
+#[test]
+fn test_seek() {
+    let mut bstream = Bstream::new(vec![]);
+    for v in 0..100 {
+        bstream.write_bits(v, 13);
+    }
+    bstream.write_byte(0xab);
+    bstream.pad_to_byte();
+    bstream.write_aligned_bytes(&[0xcd]);
+    let bytes = bstream.bytes();
+    assert_eq!(0xcd, bytes[bytes.len()-1]);
+
+    let mut r = BstreamReader::new(&[0xff]);
+    assert_eq!(0xff, r.read_byte().unwrap());
+    r.reset(bytes);
+    for v in [57, 3, 99, 0, 64] {
+        r.seek(v as usize * 13).unwrap();
+        assert_eq!(v as usize * 13, r.position());
+        assert_eq!(v, r.read_bits(13).unwrap());
+        assert_eq!((v as usize + 1) * 13, r.position());
+    }
+    r.seek(1300).unwrap();
+    assert_eq!(0xab, r.read_byte().unwrap());
+    assert_eq!(Err(BstreamError::UnexpectedEof), r.seek(bytes.len()*8 + 1));
+}
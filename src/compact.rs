@@ -0,0 +1,466 @@
+// The compactor merges blocks into larger ones. The samples of a series in
+// all source blocks are merged with a MergeIterator and appended into new
+// chunks of samples_per_chunk samples, which also repacks the small chunks of
+// blocks written from a sparse head.
+//
+// Blocks are compacted when their time ranges overlap, or when they lie in
+// the same window of one of the compaction ranges, starting with the smallest.
+// The new block is written under a temporary name and moved in place before
+// the sources are deleted. A source is first renamed to <ulid>.tmp-for-deletion,
+// so a crash never leaves it half deleted, and cleanup removes the sources of
+// a block that survived a crash together with the block.
+//
+// Compacting also drops the samples deleted by tombstones, and a block with
+// tombstones that isn't compacted with others is rewritten on its own.
+// Retention deletes whole blocks, the oldest first, once they are older than
+// the retention duration or the blocks take more than the retention size.
+use crate::block::{list_blocks, BlockBuilder, BlockCompaction, BlockId, BlockMeta, BlockReader, Error};
+use crate::head::Head;
+use crate::index::Matcher;
+use crate::labels::Labels;
+use crate::merge::{DuplicatePolicy, MergeIterator};
+use crate::series::DEFAULT_SAMPLES_PER_CHUNK;
+use crate::tombstones::TOMBSTONES_FILENAME;
+use crate::xor::{SampleIterator, SealOptions};
+#[cfg(test)]
+use crate::test_dir;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const HOUR: i64 = 60 * 60 * 1000;
+
+// DEFAULT_RANGES are the windows blocks are compacted into, in milliseconds,
+// like Prometheus does with 2h blocks from the head.
+pub const DEFAULT_RANGES: [i64; 3] = [6 * HOUR, 18 * HOUR, 54 * HOUR];
+
+const TMP_SUFFIX: &str = ".tmp";
+const DELETION_SUFFIX: &str = ".tmp-for-deletion";
+
+pub struct Compactor {
+    dir: PathBuf,
+    ranges: Vec<i64>,
+    samples_per_chunk: u16,
+    seal: Option<SealOptions>,
+    retention_duration: Option<i64>,
+    retention_size: Option<u64>,
+}
+
+impl Compactor {
+    pub fn new(dir: impl AsRef<Path>) -> Compactor {
+        Compactor {
+            dir: dir.as_ref().to_path_buf(),
+            ranges: DEFAULT_RANGES.to_vec(),
+            samples_per_chunk: DEFAULT_SAMPLES_PER_CHUNK,
+            seal: None,
+            retention_duration: None,
+            retention_size: None,
+        }
+    }
+
+    // with_ranges sets the compaction windows in milliseconds.
+    pub fn with_ranges(mut self, mut ranges: Vec<i64>) -> Compactor {
+        ranges.retain(|r| *r > 0);
+        ranges.sort();
+        self.ranges = ranges;
+        self
+    }
+
+    pub fn with_samples_per_chunk(mut self, samples_per_chunk: u16) -> Compactor {
+        self.samples_per_chunk = samples_per_chunk;
+        self
+    }
+
+    // with_seal_options seals the chunks of compacted blocks, see
+    // BlockWriter::with_seal_options.
+    pub fn with_seal_options(mut self, opts: SealOptions) -> Compactor {
+        self.seal = Some(opts);
+        self
+    }
+
+    // with_retention_duration deletes blocks that end more than duration
+    // milliseconds before the end of the newest block.
+    pub fn with_retention_duration(mut self, duration: i64) -> Compactor {
+        self.retention_duration = Some(duration);
+        self
+    }
+
+    // with_retention_size deletes the oldest blocks while all blocks take
+    // more than size bytes on disk.
+    pub fn with_retention_size(mut self, size: u64) -> Compactor {
+        self.retention_size = Some(size);
+        self
+    }
+
+    // plan returns the next group of blocks to compact, oldest first, or
+    // nothing if there is none.
+    pub fn plan(&self) -> Result<Vec<(PathBuf, BlockMeta)>, Error> {
+        let blocks = list_blocks(&self.dir)?;
+        // overlapping blocks first, they make queries merge on every read
+        let mut group: Vec<(PathBuf, BlockMeta)> = Vec::new();
+        let mut group_maxt = i64::MIN;
+        for b in &blocks {
+            if b.1.min_time < group_maxt {
+                group_maxt = group_maxt.max(b.1.max_time);
+                group.push(b.clone());
+                continue;
+            }
+            if group.len() > 1 {
+                return Ok(group);
+            }
+            group = vec![b.clone()];
+            group_maxt = b.1.max_time;
+        }
+        if group.len() > 1 {
+            return Ok(group);
+        }
+
+        for range in &self.ranges {
+            let mut windows: BTreeMap<i64, Vec<(PathBuf, BlockMeta)>> = BTreeMap::new();
+            for b in &blocks {
+                let w = b.1.min_time.div_euclid(*range);
+                // blocks that don't fit into a window are left alone
+                if b.1.max_time - 1 < (w + 1) * range {
+                    windows.entry(w).or_default().push(b.clone());
+                }
+            }
+            if let Some(group) = windows.into_values().find(|g| g.len() > 1) {
+                return Ok(group);
+            }
+        }
+
+        Ok(blocks
+            .into_iter()
+            .filter(|(dir, _)| dir.join(TOMBSTONES_FILENAME).exists())
+            .take(1)
+            .collect())
+    }
+
+    // run applies the retention and compacts until there is nothing left to
+    // do. It returns the new blocks.
+    pub fn run(&self) -> Result<Vec<BlockMeta>, Error> {
+        self.cleanup()?;
+        self.apply_retention()?;
+        let mut out = Vec::new();
+        loop {
+            let group = self.plan()?;
+            if group.is_empty() {
+                return Ok(out);
+            }
+            let dirs: Vec<_> = group.into_iter().map(|(dir, _)| dir).collect();
+            out.extend(self.compact(&dirs)?);
+        }
+    }
+
+    // compact merges the given blocks into a new one and deletes them. It
+    // returns None if the blocks had no samples at all.
+    pub fn compact(&self, dirs: &[PathBuf]) -> Result<Option<BlockMeta>, Error> {
+        let mut readers = dirs.iter().map(BlockReader::open).collect::<Result<Vec<_>, _>>()?;
+        // of samples with the same timestamp the one of the newest block wins
+        readers.sort_by_key(|r| r.meta().id);
+
+        let mint = readers.iter().map(|r| r.meta().min_time).min().unwrap_or(0);
+        let maxt = readers.iter().map(|r| r.meta().max_time).max().unwrap_or(0);
+        let mut sources = BTreeSet::new();
+        let mut level = 0;
+        for r in &readers {
+            sources.extend(r.meta().compaction.sources.iter().copied());
+            level = level.max(r.meta().compaction.level);
+        }
+
+        // all series of all blocks with the blocks they are in
+        let mut series: BTreeMap<&Labels, Vec<usize>> = BTreeMap::new();
+        for (i, r) in readers.iter().enumerate() {
+            for sref in r.series_refs() {
+                series.entry(r.series(sref).unwrap().0).or_default().push(i);
+            }
+        }
+
+        let mut b = BlockBuilder::new(&self.dir, mint, maxt, self.samples_per_chunk, self.seal)?;
+        let mut samples = Vec::new();
+        for (labels, in_blocks) in &series {
+            let chunks = in_blocks
+                .iter()
+                .map(|i| {
+                    let r = &readers[*i];
+                    r.series_chunks(r.series_by_labels(labels).unwrap(), i64::MIN, i64::MAX)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let iters: Vec<_> = chunks.iter().map(|c| c.iter()).collect();
+            let mut merged = MergeIterator::new(iters, DuplicatePolicy::LastWins);
+            samples.clear();
+            samples.extend(&mut merged);
+            if let Some(err) = merged.err() {
+                return Err(err.clone().into());
+            }
+            b.add_series(labels, samples.iter().copied())?;
+        }
+
+        let meta = if b.num_series() > 0 {
+            Some(b.finish(Some(BlockCompaction {
+                level: level + 1,
+                sources: sources.into_iter().collect(),
+            }))?)
+        } else {
+            None
+        };
+        drop(readers);
+        for dir in dirs {
+            delete_block(dir)?;
+        }
+        Ok(meta)
+    }
+
+    // delete marks the samples in [mint, maxt] of the series the matchers
+    // select as deleted in all blocks, and deletes them from the head whose
+    // samples go into the blocks, see Head::delete. Without it samples not
+    // yet written to a block survive. It returns the number of series that
+    // had samples in the range, counted once per block and for the head.
+    pub fn delete(&self, head: Option<&Head>, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<usize, Error> {
+        let mut n = match head {
+            Some(head) => head.delete(matchers, mint, maxt)?,
+            None => 0,
+        };
+        for (dir, meta) in list_blocks(&self.dir)? {
+            if meta.max_time > mint && meta.min_time <= maxt {
+                n += BlockReader::open(dir)?.delete(matchers, mint, maxt)?;
+            }
+        }
+        Ok(n)
+    }
+
+    // apply_retention deletes the blocks beyond the retention duration or
+    // size and returns them.
+    pub fn apply_retention(&self) -> Result<Vec<BlockMeta>, Error> {
+        let mut blocks = list_blocks(&self.dir)?;
+        // newest first
+        blocks.sort_by_key(|(_, m)| std::cmp::Reverse((m.max_time, m.id)));
+        let newest = blocks.first().map_or(0, |(_, m)| m.max_time);
+        let mut size = 0;
+        let mut deleted = Vec::new();
+        for (dir, meta) in blocks {
+            size += dir_size(&dir)?;
+            let too_old = self.retention_duration.is_some_and(|d| newest - meta.max_time > d);
+            let too_large = self.retention_size.is_some_and(|s| size > s);
+            if too_old || too_large {
+                delete_block(&dir)?;
+                deleted.push(meta);
+            }
+        }
+        Ok(deleted)
+    }
+
+    // cleanup removes what a crash during writing or compaction leaves
+    // behind: unfinished blocks, half deleted blocks and blocks whose samples
+    // are all in a compacted block.
+    pub fn cleanup(&self) -> Result<(), Error> {
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let stale = [TMP_SUFFIX, DELETION_SUFFIX]
+                .iter()
+                .any(|s| name.strip_suffix(s).and_then(BlockId::parse).is_some());
+            if stale && entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+        let blocks = list_blocks(&self.dir)?;
+        for (dir, meta) in &blocks {
+            let replaced = blocks.iter().any(|(_, other)| {
+                other.id != meta.id
+                    && other.compaction.level > meta.compaction.level
+                    && meta.compaction.sources.iter().all(|s| other.compaction.sources.contains(s))
+            });
+            if replaced {
+                delete_block(dir)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// delete_block renames a block out of the way before deleting it, so a crash
+// can't leave a partial block behind under its name.
+fn delete_block(dir: &Path) -> Result<(), Error> {
+    let mut tmp = dir.as_os_str().to_os_string();
+    tmp.push(DELETION_SUFFIX);
+    fs::rename(dir, &tmp)?;
+    fs::remove_dir_all(&tmp)?;
+    Ok(())
+}
+
+// dir_size returns the size of the files in dir and its subdirectories.
+fn dir_size(dir: &Path) -> Result<u64, Error> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        size += if meta.is_dir() { dir_size(&entry.path())? } else { meta.len() };
+    }
+    Ok(size)
+}
+
+#[test]
+fn test_compact() {
+    use crate::block::BlockWriter;
+    use crate::head::Head;
+
+    let dir = test_dir("compact");
+    let a = Labels::from_pairs(&[("__name__", "a")]);
+    let b = Labels::from_pairs(&[("__name__", "b")]);
+    let w = BlockWriter::new(&dir).with_samples_per_chunk(7);
+
+    // a sparse series in three adjacent blocks, another one only in the last
+    let head = Head::new();
+    for t in 0..300 {
+        head.append(&a, t * 10, t as f64).unwrap();
+        if t >= 200 {
+            head.append(&b, t * 10, -t as f64).unwrap();
+        }
+    }
+    let metas: Vec<_> = [(0, 1000), (1000, 2000), (2000, 3000)]
+        .iter()
+        .map(|(mint, maxt)| w.write_head(&head, *mint, *maxt).unwrap().unwrap())
+        .collect();
+
+    let c = Compactor::new(&dir).with_ranges(vec![4000]).with_samples_per_chunk(120);
+    let plan = c.plan().unwrap();
+    assert_eq!(metas.iter().map(|m| m.id).collect::<Vec<_>>(), plan.iter().map(|(_, m)| m.id).collect::<Vec<_>>());
+    let out = c.run().unwrap();
+    assert_eq!(1, out.len());
+    let meta = &out[0];
+    assert_eq!((0, 3000), (meta.min_time, meta.max_time));
+    assert_eq!(2, meta.compaction.level);
+    let mut sources: Vec<_> = metas.iter().map(|m| m.id).collect();
+    sources.sort();
+    assert_eq!(sources, meta.compaction.sources);
+    assert_eq!(400, meta.stats.num_samples);
+    // 300 and 100 samples in chunks of 120
+    assert_eq!(3 + 1, meta.stats.num_chunks);
+
+    let blocks = list_blocks(&dir).unwrap();
+    assert_eq!(1, blocks.len());
+    let r = BlockReader::open(&blocks[0].0).unwrap();
+    let got: Vec<_> = r.series_chunks(r.series_by_labels(&a).unwrap(), i64::MIN, i64::MAX).unwrap().iter().collect();
+    assert_eq!((0..300).map(|t| (t * 10, t as f64)).collect::<Vec<_>>(), got);
+    assert!(c.plan().unwrap().is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_compact_overlapping() {
+    use crate::block::BlockWriter;
+    use crate::head::Head;
+
+    let dir = test_dir("compact-overlap");
+    let a = Labels::from_pairs(&[("__name__", "a")]);
+    let w = BlockWriter::new(&dir);
+    let old = Head::new();
+    for t in 0..10 {
+        old.append(&a, t * 10, 1.0).unwrap();
+    }
+    let new = Head::new();
+    for t in 5..15 {
+        new.append(&a, t * 10, 2.0).unwrap();
+    }
+    let m1 = w.write_head(&old, 0, 100).unwrap().unwrap();
+    let m2 = w.write_head(&new, 50, 150).unwrap().unwrap();
+
+    // overlapping blocks are compacted whatever the ranges
+    let c = Compactor::new(&dir).with_ranges(Vec::new());
+    assert_eq!(2, c.plan().unwrap().len());
+    let meta = c.run().unwrap().pop().unwrap();
+    assert_eq!((0, 150), (meta.min_time, meta.max_time));
+    let r = BlockReader::open(dir.join(meta.id.to_string())).unwrap();
+    let sref = r.series_by_labels(&a).unwrap();
+    let got: Vec<_> = r.series_chunks(sref, i64::MIN, i64::MAX).unwrap().iter().collect();
+    // the newer block wins on duplicate timestamps
+    let want: Vec<_> = (0..15).map(|t| (t * 10, if t < 5 { 1.0 } else { 2.0 })).collect();
+    assert_eq!(want, got);
+
+    // a crash after the compacted block was written leaves the sources and
+    // maybe a half deleted one behind, cleanup removes them
+    let src = dir.join(m1.id.to_string());
+    fs::create_dir_all(src.join("chunks")).unwrap();
+    let m = BlockMeta { compaction: BlockCompaction { level: 1, sources: vec![m1.id] }, ..m1 };
+    fs::write(src.join(crate::block::META_FILENAME), m.to_json()).unwrap();
+    let deleting = dir.join(format!("{}{}", m2.id, DELETION_SUFFIX));
+    fs::create_dir_all(&deleting).unwrap();
+    assert_eq!(3, fs::read_dir(&dir).unwrap().count());
+    c.cleanup().unwrap();
+    assert!(!src.exists() && !deleting.exists());
+    assert_eq!(1, list_blocks(&dir).unwrap().len());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_delete_and_retention() {
+    use crate::block::BlockWriter;
+    use crate::head::Head;
+    use crate::index::MatchType;
+
+    let dir = test_dir("compact-delete");
+    let a = Labels::from_pairs(&[("__name__", "a")]);
+    let b = Labels::from_pairs(&[("__name__", "b")]);
+    let head = Head::new();
+    for t in 0..300 {
+        head.append(&a, t * 10, t as f64).unwrap();
+        head.append(&b, t * 10, t as f64).unwrap();
+    }
+    let w = BlockWriter::new(&dir).with_samples_per_chunk(20);
+    let metas: Vec<_> = (0..3).map(|i| w.write_head(&head, i * 1000, (i + 1) * 1000).unwrap().unwrap()).collect();
+
+    // ranges that never group the blocks, so only tombstones make them compacted
+    let c = Compactor::new(&dir).with_ranges(vec![1000]);
+    assert!(c.plan().unwrap().is_empty());
+    let m = [Matcher::new(MatchType::Equal, "__name__", "a").unwrap()];
+    assert_eq!(1 + 1, c.delete(Some(&head), &m, 500, 999).unwrap());
+    assert_eq!(0, c.delete(Some(&head), &m, 5000, 6000).unwrap());
+    // the head doesn't have them anymore either, so no later block brings them back
+    let got: Vec<_> = head.series_by_labels(&a).unwrap().snapshot().unwrap().iter().map(|(t, _)| t).collect();
+    assert_eq!((0..300).map(|t| t * 10).filter(|t| !(500..1000).contains(t)).collect::<Vec<_>>(), got);
+
+    // deleted samples are skipped when reading
+    let r = BlockReader::open(dir.join(metas[0].id.to_string())).unwrap();
+    let sref = r.series_by_labels(&a).unwrap();
+    assert_eq!(1, r.tombstones().len());
+    let chunks = r.series_chunks(sref, i64::MIN, i64::MAX).unwrap();
+    // the chunks of [600, 790] and [800, 990] are skipped entirely
+    assert_eq!(3, chunks.len());
+    let got: Vec<_> = chunks.iter().map(|(t, _)| t).collect();
+    assert_eq!((0..50).map(|t| t * 10).collect::<Vec<_>>(), got);
+    let sref = r.series_by_labels(&b).unwrap();
+    assert_eq!(100, r.series_chunks(sref, i64::MIN, i64::MAX).unwrap().iter().count());
+    drop(r);
+
+    // compaction rewrites the block with tombstones and drops the samples
+    let out = c.run().unwrap();
+    assert_eq!(1, out.len());
+    assert_eq!(vec![metas[0].id], out[0].compaction.sources);
+    assert_eq!(50 + 100, out[0].stats.num_samples);
+    let r = BlockReader::open(dir.join(out[0].id.to_string())).unwrap();
+    assert!(r.tombstones().is_empty());
+    assert!(!r.dir().join(TOMBSTONES_FILENAME).exists());
+    assert!(c.plan().unwrap().is_empty());
+    drop(r);
+
+    // deleting all samples of a block and compacting leaves no block
+    assert_eq!(2, c.delete(None, &[], 2000, 2999).unwrap());
+    assert!(c.run().unwrap().is_empty());
+    let blocks = list_blocks(&dir).unwrap();
+    assert_eq!(vec![out[0].id, metas[1].id], blocks.iter().map(|(_, m)| m.id).collect::<Vec<_>>());
+
+    // the newest block ends at 2000, the first one 1000 before
+    assert!(Compactor::new(&dir).with_retention_duration(1000).apply_retention().unwrap().is_empty());
+    let deleted = Compactor::new(&dir).with_retention_duration(999).apply_retention().unwrap();
+    assert_eq!(vec![out[0].id], deleted.iter().map(|m| m.id).collect::<Vec<_>>());
+
+    let w = BlockWriter::new(&dir);
+    let m3 = w.write_head(&head, 2000, 3000).unwrap().unwrap();
+    let size = dir_size(&dir.join(m3.id.to_string())).unwrap();
+    let deleted = Compactor::new(&dir).with_retention_size(size).apply_retention().unwrap();
+    assert_eq!(vec![metas[1].id], deleted.iter().map(|m| m.id).collect::<Vec<_>>());
+    assert_eq!(vec![m3.id], list_blocks(&dir).unwrap().iter().map(|(_, m)| m.id).collect::<Vec<_>>());
+    fs::remove_dir_all(&dir).unwrap();
+}
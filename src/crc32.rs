@@ -0,0 +1,44 @@
+// CRC32 with the Castagnoli polynomial, which is what Prometheus uses to
+// checksum chunks and WAL records.
+
+const CASTAGNOLI: u32 = 0x82f63b78; // reversed
+
+static TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CASTAGNOLI } else { crc >> 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// update returns the checksum of the bytes behind those crc is the checksum of.
+pub fn update(crc: u32, b: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in b {
+        crc = TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+pub fn checksum(b: &[u8]) -> u32 {
+    update(0, b)
+}
+
+#[test]
+fn test_checksum() {
+    assert_eq!(0, checksum(b""));
+    assert_eq!(0xe3069283, checksum(b"123456789"));
+    assert_eq!(0x22620404, checksum(b"The quick brown fox jumps over the lazy dog"));
+    let b = b"hello, world";
+    assert_eq!(checksum(b), update(checksum(&b[..5]), &b[5..]));
+}
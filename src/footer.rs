@@ -0,0 +1,204 @@
+// A sealed chunk carries a footer behind its sample data. The footer is a
+// list of optional sections followed by a flags byte saying which sections
+// are present, so it can be parsed from the end of the chunk:
+//
+//   [header][sample data, padded to a byte][index][meta][aggregates][flags u8]
+//
+// The index section is a sparse seek index. Every N samples it records the
+// decoder state right before that sample, followed by N and the number of
+// entries (both u16 big endian).
+//
+// The meta section holds the ChunkMeta of the chunk: min and max time (i64)
+// and the number of bits used by the header and samples (u32), big endian.
+//
+// The aggregates section holds the Aggregates of all samples: min, max, sum
+// (f64 bits), count (u32), first and last (f64 bits), big endian.
+use crate::xor::{Aggregates, ChunkMeta, Error};
+use alloc::vec::Vec;
+
+pub(crate) const FOOTER_INDEX: u8 = 0x01;
+pub(crate) const FOOTER_META: u8 = 0x02;
+pub(crate) const FOOTER_AGGREGATES: u8 = 0x04;
+
+const INDEX_ENTRY_LEN: usize = 32;
+const META_LEN: usize = 20;
+const AGGREGATES_LEN: usize = 44;
+
+// IndexEntry is the state of XORIterator right before it reads sample
+// num_read: the bit offset of that sample in the chunk and everything the
+// decoder carries over from the previous sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexEntry {
+    pub bit_offset: u32,
+    pub num_read: u16,
+    pub t: i64,
+    pub val: f64,
+    pub t_delta: u64,
+    pub leading: u8,
+    pub trailing: u8,
+}
+
+impl IndexEntry {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.bit_offset.to_be_bytes());
+        buf.extend_from_slice(&self.num_read.to_be_bytes());
+        buf.extend_from_slice(&self.t.to_be_bytes());
+        buf.extend_from_slice(&self.val.to_bits().to_be_bytes());
+        buf.extend_from_slice(&self.t_delta.to_be_bytes());
+        buf.push(self.leading);
+        buf.push(self.trailing);
+    }
+
+    fn decode(b: &[u8]) -> IndexEntry {
+        IndexEntry {
+            bit_offset: u32::from_be_bytes(b[0..4].try_into().unwrap()),
+            num_read: u16::from_be_bytes(b[4..6].try_into().unwrap()),
+            t: i64::from_be_bytes(b[6..14].try_into().unwrap()),
+            val: f64::from_bits(u64::from_be_bytes(b[14..22].try_into().unwrap())),
+            t_delta: u64::from_be_bytes(b[22..30].try_into().unwrap()),
+            leading: b[30],
+            trailing: b[31],
+        }
+    }
+}
+
+// SeekIndex is a view on the index section of a sealed chunk.
+#[derive(Debug, Clone, Copy)]
+pub struct SeekIndex<'a> {
+    interval: u16,
+    entries: &'a [u8],
+}
+
+impl<'a> SeekIndex<'a> {
+    // interval returns the number of samples between two entries.
+    pub fn interval(&self) -> u16 {
+        self.interval
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() / INDEX_ENTRY_LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entry(&self, i: usize) -> IndexEntry {
+        IndexEntry::decode(&self.entries[i * INDEX_ENTRY_LEN..(i + 1) * INDEX_ENTRY_LEN])
+    }
+
+    // search returns the last entry that only has samples before t behind it,
+    // which is where decoding has to resume to find the first sample >= t.
+    pub fn search(&self, t: i64) -> Option<IndexEntry> {
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.entry(mid).t < t {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        if lo == 0 {
+            return None;
+        }
+        Some(self.entry(lo - 1))
+    }
+}
+
+// Footer is a parsed view on the footer of a sealed chunk.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Footer<'a> {
+    pub index: Option<SeekIndex<'a>>,
+    pub meta: Option<ChunkMeta>,
+    pub aggregates: Option<Aggregates>,
+}
+
+impl<'a> Footer<'a> {
+    // parse reads the footer from the end of the bytes of a sealed chunk.
+    pub fn parse(bytes: &'a [u8]) -> Result<Footer<'a>, Error> {
+        let mut footer = Footer::default();
+        // never read into the 2 byte chunk header
+        if bytes.len() < 3 {
+            return Err(Error::InvalidFooter);
+        }
+        let flags = bytes[bytes.len() - 1];
+        let mut end = bytes.len() - 1;
+        if flags & !(FOOTER_INDEX | FOOTER_META | FOOTER_AGGREGATES) != 0 {
+            return Err(Error::InvalidFooter);
+        }
+        // sections are read back to front
+        if flags & FOOTER_AGGREGATES != 0 {
+            let b = take(bytes, &mut end, AGGREGATES_LEN)?;
+            let f = |i: usize| f64::from_bits(u64::from_be_bytes(b[i..i + 8].try_into().unwrap()));
+            footer.aggregates = Some(Aggregates {
+                min: f(0),
+                max: f(8),
+                sum: f(16),
+                count: u32::from_be_bytes(b[24..28].try_into().unwrap()),
+                first: f(28),
+                last: f(36),
+            });
+        }
+        if flags & FOOTER_META != 0 {
+            let b = take(bytes, &mut end, META_LEN)?;
+            footer.meta = Some(ChunkMeta {
+                min_time: i64::from_be_bytes(b[0..8].try_into().unwrap()),
+                max_time: i64::from_be_bytes(b[8..16].try_into().unwrap()),
+                bit_len: u32::from_be_bytes(b[16..20].try_into().unwrap()) as usize,
+            });
+        }
+        if flags & FOOTER_INDEX != 0 {
+            let b = take(bytes, &mut end, 4)?;
+            let interval = u16::from_be_bytes([b[0], b[1]]);
+            let n = u16::from_be_bytes([b[2], b[3]]) as usize;
+            let entries = take(bytes, &mut end, n * INDEX_ENTRY_LEN)?;
+            footer.index = Some(SeekIndex { interval, entries });
+        }
+        Ok(footer)
+    }
+}
+
+// take returns the n bytes in front of end and moves end to their start.
+fn take<'a>(bytes: &'a [u8], end: &mut usize, n: usize) -> Result<&'a [u8], Error> {
+    if *end < 2 + n {
+        return Err(Error::InvalidFooter);
+    }
+    *end -= n;
+    Ok(&bytes[*end..*end + n])
+}
+
+// encode_footer returns the footer to append to the sample data of a chunk.
+pub(crate) fn encode_footer(
+    index: Option<(u16, &[IndexEntry])>,
+    meta: Option<&ChunkMeta>,
+    aggregates: Option<&Aggregates>,
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut flags = 0;
+    if let Some((interval, entries)) = index {
+        for e in entries {
+            e.encode(&mut buf);
+        }
+        buf.extend_from_slice(&interval.to_be_bytes());
+        buf.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+        flags |= FOOTER_INDEX;
+    }
+    if let Some(meta) = meta {
+        buf.extend_from_slice(&meta.min_time.to_be_bytes());
+        buf.extend_from_slice(&meta.max_time.to_be_bytes());
+        buf.extend_from_slice(&(meta.bit_len as u32).to_be_bytes());
+        flags |= FOOTER_META;
+    }
+    if let Some(a) = aggregates {
+        buf.extend_from_slice(&a.min.to_bits().to_be_bytes());
+        buf.extend_from_slice(&a.max.to_bits().to_be_bytes());
+        buf.extend_from_slice(&a.sum.to_bits().to_be_bytes());
+        buf.extend_from_slice(&a.count.to_be_bytes());
+        buf.extend_from_slice(&a.first.to_bits().to_be_bytes());
+        buf.extend_from_slice(&a.last.to_bits().to_be_bytes());
+        flags |= FOOTER_AGGREGATES;
+    }
+    buf.push(flags);
+    buf
+}
@@ -0,0 +1,548 @@
+// The head is the in-memory part of the database that all samples are
+// appended to. Every series is created on its first append and gets a
+// SeriesRef, which later appends can use instead of the labels. The samples of
+// a series live in a ConcurrentSeries: the open chunk and the sealed chunks
+// cut from it, which queries read without blocking appends.
+//
+// With a WAL, series creation and appends are logged while holding the WAL
+// lock, so the WAL has them in the order they were applied. append_batch
+// logs a whole batch of samples in one record under a single lock.
+use crate::index::{IndexReader, Matcher, MemPostings};
+use crate::labels::Labels;
+use crate::series::{ConcurrentSeries, SeriesSnapshot, DEFAULT_SAMPLES_PER_CHUNK};
+use crate::tombstones::Interval;
+use crate::wal::{self, RefSample, Wal};
+use crate::xor::{self, XORChunk};
+use alloc::sync::Arc;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SeriesRef(pub u64);
+
+impl fmt::Display for SeriesRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Chunk(xor::Error),
+    UnknownSeries(SeriesRef),
+    Wal(wal::Error),
+}
+
+impl From<xor::Error> for Error {
+    fn from(err: xor::Error) -> Error {
+        Error::Chunk(err)
+    }
+}
+
+impl From<wal::Error> for Error {
+    fn from(err: wal::Error) -> Error {
+        Error::Wal(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Chunk(err) => write!(f, "head: {}", err),
+            Error::UnknownSeries(r) => write!(f, "head: unknown series {}", r),
+            Error::Wal(err) => write!(f, "head: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// MemSeries is a series in the head.
+pub struct MemSeries {
+    sref: SeriesRef,
+    labels: Labels,
+    samples: ConcurrentSeries,
+}
+
+impl MemSeries {
+    pub fn series_ref(&self) -> SeriesRef {
+        self.sref
+    }
+
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
+
+    // min_time returns the timestamp of the first sample, i64::MAX if there is none.
+    pub fn min_time(&self) -> i64 {
+        self.samples.min_time()
+    }
+
+    // max_time returns the timestamp of the last sample, i64::MIN if there is none.
+    pub fn max_time(&self) -> i64 {
+        self.samples.max_time()
+    }
+
+    // append doesn't log to the WAL, Head::append_ref does.
+    pub(crate) fn append(&self, t: i64, v: f64) -> Result<(), Error> {
+        Ok(self.samples.append(t, v)?)
+    }
+
+    // snapshot returns the chunks of the series as of the last append.
+    pub fn snapshot(&self) -> Result<SeriesSnapshot, Error> {
+        Ok(self.samples.snapshot()?)
+    }
+
+    // compact_ooo merges the out of order samples into the chunks, see
+    // ConcurrentSeries::compact_ooo.
+    pub fn compact_ooo(&self) -> Result<usize, Error> {
+        Ok(self.samples.compact_ooo()?)
+    }
+
+    // contains says whether the series has a sample at t with the value v.
+    pub(crate) fn contains(&self, t: i64, v: f64) -> Result<bool, xor::Error> {
+        self.samples.contains(t, v)
+    }
+
+    // delete doesn't log to the WAL, Head::delete does.
+    pub(crate) fn delete(&self, mint: i64, maxt: i64) -> Result<usize, xor::Error> {
+        self.samples.delete(mint, maxt)
+    }
+}
+
+#[derive(Default)]
+struct Index {
+    series: HashMap<SeriesRef, Arc<MemSeries>>,
+    refs: HashMap<Labels, SeriesRef>,
+    postings: MemPostings,
+    last_ref: u64,
+}
+
+pub struct Head {
+    index: RwLock<Index>,
+    samples_per_chunk: u16,
+    ooo_window: i64,
+    // time range of all samples
+    min_time: AtomicI64,
+    max_time: AtomicI64,
+    wal: Option<Mutex<Wal>>,
+}
+
+impl Default for Head {
+    fn default() -> Head {
+        Head::new()
+    }
+}
+
+impl Head {
+    pub fn new() -> Head {
+        Head::with_samples_per_chunk(DEFAULT_SAMPLES_PER_CHUNK)
+    }
+
+    pub fn with_samples_per_chunk(samples_per_chunk: u16) -> Head {
+        Head {
+            index: RwLock::new(Index::default()),
+            samples_per_chunk,
+            ooo_window: 0,
+            min_time: AtomicI64::new(i64::MAX),
+            max_time: AtomicI64::new(i64::MIN),
+            wal: None,
+        }
+    }
+
+    // with_ooo_window makes series accept samples up to window milliseconds
+    // older than their newest one, see ConcurrentSeries::with_ooo_window.
+    pub fn with_ooo_window(mut self, window: i64) -> Head {
+        self.ooo_window = window;
+        self
+    }
+
+    // with_wal makes the head log everything appended from now on to wal.
+    // Replay the WAL with wal::replay before.
+    pub fn with_wal(mut self, wal: Wal) -> Head {
+        self.wal = Some(Mutex::new(wal));
+        self
+    }
+
+    pub(crate) fn lock_wal(&self) -> Option<MutexGuard<'_, Wal>> {
+        self.wal.as_ref().map(|w| w.lock().unwrap())
+    }
+
+    // sync_wal writes out and fsyncs everything logged so far.
+    pub fn sync_wal(&self) -> Result<(), Error> {
+        if let Some(mut wal) = self.lock_wal() {
+            wal.sync()?;
+        }
+        Ok(())
+    }
+
+    // close_wal syncs the WAL and detaches it from the head.
+    pub fn close_wal(self) -> Result<(), Error> {
+        if let Some(wal) = self.wal {
+            wal.into_inner().unwrap().close()?;
+        }
+        Ok(())
+    }
+
+    // truncate_wal drops the samples before mint from the WAL, together with
+    // the series that aren't in the head anymore. Call it once everything
+    // before mint is persisted.
+    pub fn truncate_wal(&self, mint: i64) -> Result<(), Error> {
+        if let Some(mut wal) = self.lock_wal() {
+            wal.truncate(mint, |sref| self.series(sref).is_some())?;
+        }
+        Ok(())
+    }
+
+    // append adds a sample to the series with the given labels, which is
+    // created if it doesn't exist yet, and returns the ref of the series.
+    pub fn append(&self, labels: &Labels, t: i64, v: f64) -> Result<SeriesRef, Error> {
+        let mut wal = self.lock_wal();
+        let s = self.get_or_create_logged(labels, &mut wal)?.0;
+        self.append_logged(&s, t, v, &mut wal)?;
+        Ok(s.sref)
+    }
+
+    // append_ref adds a sample to an existing series without looking up its labels.
+    pub fn append_ref(&self, sref: SeriesRef, t: i64, v: f64) -> Result<(), Error> {
+        let mut wal = self.lock_wal();
+        let s = self.series(sref).ok_or(Error::UnknownSeries(sref))?;
+        self.append_logged(&s, t, v, &mut wal)
+    }
+
+    // append_logged logs the sample before appending it, so a sample that is
+    // in memory is never lost to a crash. Holding the WAL lock keeps other
+    // appends from rejecting it in between.
+    fn append_logged(&self, s: &MemSeries, t: i64, v: f64, wal: &mut Option<MutexGuard<'_, Wal>>) -> Result<(), Error> {
+        if let Some(wal) = wal {
+            s.samples.check(t)?;
+            wal.log_samples(&[RefSample { sref: s.sref, t, v }])?;
+        }
+        s.append(t, v)?;
+        self.update_time_range(t);
+        Ok(())
+    }
+
+    // append_batch adds samples to existing series and returns how many of
+    // them were appended. With a WAL they are logged as a single record, so
+    // the WAL lock is taken once per batch rather than once per sample.
+    // Samples append_ref would reject as out of order are left out, an
+    // unknown series fails the batch before anything is appended.
+    pub fn append_batch(&self, samples: &[RefSample]) -> Result<usize, Error> {
+        let mut wal = self.lock_wal();
+        let series = {
+            let index = self.index.read().unwrap();
+            samples
+                .iter()
+                .map(|s| index.series.get(&s.sref).cloned().ok_or(Error::UnknownSeries(s.sref)))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        // samples of the batch count as appended for the ones behind them
+        let mut max_time: HashMap<SeriesRef, i64> = HashMap::new();
+        let mut accepted = Vec::with_capacity(samples.len());
+        for (smp, s) in samples.iter().zip(series) {
+            let max = max_time.get(&smp.sref).map_or(s.max_time(), |t| s.max_time().max(*t));
+            if s.samples.check_after(smp.t, max).is_ok() {
+                max_time.insert(smp.sref, max.max(smp.t));
+                accepted.push((s, *smp));
+            }
+        }
+        if let Some(wal) = &mut wal {
+            if !accepted.is_empty() {
+                wal.log_samples(&accepted.iter().map(|(_, smp)| *smp).collect::<Vec<_>>())?;
+            }
+        }
+        for (s, smp) in &accepted {
+            s.append(smp.t, smp.v)?;
+            self.update_time_range(smp.t);
+        }
+        Ok(accepted.len())
+    }
+
+    fn update_time_range(&self, t: i64) {
+        self.min_time.fetch_min(t, Ordering::AcqRel);
+        self.max_time.fetch_max(t, Ordering::AcqRel);
+    }
+
+    // get_or_create returns the series with the given labels and whether it
+    // was created by this call.
+    pub fn get_or_create(&self, labels: &Labels) -> Result<(Arc<MemSeries>, bool), Error> {
+        if let Some(s) = self.series_by_labels(labels) {
+            return Ok((s, false));
+        }
+        self.get_or_create_logged(labels, &mut self.lock_wal())
+    }
+
+    fn get_or_create_logged(
+        &self,
+        labels: &Labels,
+        wal: &mut Option<MutexGuard<'_, Wal>>,
+    ) -> Result<(Arc<MemSeries>, bool), Error> {
+        if let Some(s) = self.series_by_labels(labels) {
+            return Ok((s, false));
+        }
+        let mut index = self.index.write().unwrap();
+        // somebody else may have created it in the meantime
+        if let Some(sref) = index.refs.get(labels) {
+            return Ok((index.series[sref].clone(), false));
+        }
+        let sref = SeriesRef(index.last_ref + 1);
+        // like samples, the series is logged before it exists in memory
+        if let Some(wal) = wal {
+            wal.log_series(&[(sref, labels.clone())])?;
+        }
+        Ok((self.insert(&mut index, sref, labels), true))
+    }
+
+    // create_with_ref creates a series with a given ref, which replay needs to
+    // restore the refs the WAL refers to. It returns the existing series if
+    // there is one with the labels.
+    pub(crate) fn create_with_ref(&self, sref: SeriesRef, labels: &Labels) -> Arc<MemSeries> {
+        let mut index = self.index.write().unwrap();
+        if let Some(sref) = index.refs.get(labels) {
+            return index.series[sref].clone();
+        }
+        self.insert(&mut index, sref, labels)
+    }
+
+    // restore_series adds a series with the chunks and out of order samples
+    // of a snapshot, see ConcurrentSeries::with_chunks.
+    pub(crate) fn restore_series(
+        &self,
+        sref: SeriesRef,
+        labels: &Labels,
+        chunks: Vec<XORChunk>,
+        ooo: Vec<(i64, f64)>,
+    ) -> Result<Arc<MemSeries>, Error> {
+        let samples = self.new_samples().with_chunks(chunks, ooo)?;
+        if samples.max_time() >= samples.min_time() {
+            self.update_time_range(samples.min_time());
+            self.update_time_range(samples.max_time());
+        }
+        let mut index = self.index.write().unwrap();
+        Ok(self.insert_samples(&mut index, sref, labels, samples))
+    }
+
+    fn new_samples(&self) -> ConcurrentSeries {
+        ConcurrentSeries::with_samples_per_chunk(self.samples_per_chunk).with_ooo_window(self.ooo_window)
+    }
+
+    fn insert(&self, index: &mut Index, sref: SeriesRef, labels: &Labels) -> Arc<MemSeries> {
+        self.insert_samples(index, sref, labels, self.new_samples())
+    }
+
+    fn insert_samples(&self, index: &mut Index, sref: SeriesRef, labels: &Labels, samples: ConcurrentSeries) -> Arc<MemSeries> {
+        let s = Arc::new(MemSeries {
+            sref,
+            labels: labels.clone(),
+            samples,
+        });
+        index.last_ref = index.last_ref.max(sref.0);
+        index.series.insert(sref, s.clone());
+        index.refs.insert(labels.clone(), sref);
+        index.postings.add(sref, labels);
+        s
+    }
+
+    // delete drops the samples in [mint, maxt] of the series the matchers
+    // select and returns how many series had some. The tombstones are logged
+    // to the WAL first, so replay deletes them again, and a snapshot written
+    // afterwards doesn't have them anymore.
+    pub fn delete(&self, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<usize, Error> {
+        let mut wal = self.lock_wal();
+        let series: Vec<_> = IndexReader::select(self, matchers)
+            .into_iter()
+            .filter_map(|sref| self.series(sref))
+            .filter(|s| s.min_time() <= maxt && s.max_time() >= mint)
+            .collect();
+        if series.is_empty() || mint > maxt {
+            return Ok(0);
+        }
+        if let Some(wal) = &mut wal {
+            let stones: Vec<_> = series.iter().map(|s| (s.sref, Interval::new(mint, maxt))).collect();
+            wal.log_tombstones(&stones)?;
+        }
+        let mut n = 0;
+        for s in series {
+            if s.delete(mint, maxt)? > 0 {
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    // compact_ooo merges the out of order samples of all series into their
+    // chunks and returns how many there were.
+    pub fn compact_ooo(&self) -> Result<usize, Error> {
+        let mut n = 0;
+        for s in self.all_series() {
+            n += s.compact_ooo()?;
+        }
+        Ok(n)
+    }
+
+    pub fn series(&self, sref: SeriesRef) -> Option<Arc<MemSeries>> {
+        self.index.read().unwrap().series.get(&sref).cloned()
+    }
+
+    pub fn series_by_labels(&self, labels: &Labels) -> Option<Arc<MemSeries>> {
+        let index = self.index.read().unwrap();
+        index.refs.get(labels).map(|sref| index.series[sref].clone())
+    }
+
+    // all_series returns all series, sorted by ref.
+    pub fn all_series(&self) -> Vec<Arc<MemSeries>> {
+        let mut all: Vec<_> = self.index.read().unwrap().series.values().cloned().collect();
+        all.sort_by_key(|s| s.sref);
+        all
+    }
+
+    pub fn num_series(&self) -> usize {
+        self.index.read().unwrap().series.len()
+    }
+
+    // min_time returns the timestamp of the oldest sample, i64::MAX if there is none.
+    pub fn min_time(&self) -> i64 {
+        self.min_time.load(Ordering::Acquire)
+    }
+
+    // max_time returns the timestamp of the newest sample, i64::MIN if there is none.
+    pub fn max_time(&self) -> i64 {
+        self.max_time.load(Ordering::Acquire)
+    }
+}
+
+impl IndexReader for Head {
+    fn postings(&self, name: &str, value: &str) -> Vec<SeriesRef> {
+        self.index.read().unwrap().postings.postings(name, value)
+    }
+
+    fn all_postings(&self) -> Vec<SeriesRef> {
+        self.index.read().unwrap().postings.all_postings()
+    }
+
+    fn label_names(&self) -> Vec<String> {
+        self.index.read().unwrap().postings.label_names()
+    }
+
+    fn label_values(&self, name: &str) -> Vec<String> {
+        self.index.read().unwrap().postings.label_values(name)
+    }
+}
+
+#[test]
+fn test_head() {
+    let head = Head::with_samples_per_chunk(10);
+    let up_a = Labels::from_pairs(&[("__name__", "up"), ("instance", "a")]);
+    let up_b = Labels::from_pairs(&[("__name__", "up"), ("instance", "b")]);
+    assert_eq!((i64::MAX, i64::MIN), (head.min_time(), head.max_time()));
+
+    let ref_a = head.append(&up_a, 1000, 1.0).unwrap();
+    let ref_b = head.append(&up_b, 500, 0.0).unwrap();
+    assert_ne!(ref_a, ref_b);
+    assert_eq!(ref_a, head.append(&up_a, 2000, 1.0).unwrap());
+    for i in 3..=25 {
+        head.append_ref(ref_a, i * 1000, i as f64).unwrap();
+    }
+    assert!(matches!(
+        head.append_ref(ref_a, 25000, 0.0),
+        Err(Error::Chunk(xor::Error::OutOfOrderSample(25000)))
+    ));
+    assert!(matches!(head.append_ref(SeriesRef(42), 0, 0.0), Err(Error::UnknownSeries(SeriesRef(42)))));
+
+    assert_eq!(2, head.num_series());
+    assert_eq!((500, 25000), (head.min_time(), head.max_time()));
+    let a = head.series_by_labels(&up_a).unwrap();
+    assert_eq!(ref_a, a.series_ref());
+    assert_eq!(&up_a, a.labels());
+    assert_eq!((1000, 25000), (a.min_time(), a.max_time()));
+    let snap = a.snapshot().unwrap();
+    assert_eq!(3, snap.chunks().count());
+    assert_eq!(25, snap.iter().count());
+    assert_eq!(
+        vec![ref_a, ref_b],
+        head.all_series().iter().map(|s| s.series_ref()).collect::<Vec<_>>()
+    );
+    assert!(head.series(ref_b).unwrap().snapshot().unwrap().iter().eq([(500, 0.0)]));
+
+    use crate::index::{MatchType, Matcher};
+    assert_eq!(vec![ref_a, ref_b], head.select(&[Matcher::new(MatchType::Equal, "__name__", "up").unwrap()]));
+    assert_eq!(vec![ref_b], head.select(&[Matcher::new(MatchType::Regex, "instance", "b|c").unwrap()]));
+}
+
+#[test]
+fn test_head_append_batch() {
+    let head = Head::with_samples_per_chunk(10).with_ooo_window(5000);
+    let a = head.get_or_create(&Labels::from_pairs(&[("__name__", "a")])).unwrap().0.series_ref();
+    let b = head.get_or_create(&Labels::from_pairs(&[("__name__", "b")])).unwrap().0.series_ref();
+    head.append_ref(a, 10000, 0.0).unwrap();
+    let batch: Vec<_> = [(a, 11000), (b, 1000), (a, 12000), (a, 12000), (b, 20000), (b, 16000), (a, 8000), (b, 14000)]
+        .into_iter()
+        .map(|(sref, t)| RefSample { sref, t, v: t as f64 })
+        .collect();
+    // the batch is checked as if its samples were appended one by one
+    assert_eq!(6, head.append_batch(&batch).unwrap());
+    let ts = |sref| head.series(sref).unwrap().snapshot().unwrap().iter().map(|s| s.0).collect::<Vec<_>>();
+    assert_eq!(vec![8000, 10000, 11000, 12000], ts(a));
+    assert_eq!(vec![1000, 16000, 20000], ts(b));
+    assert_eq!((1000, 20000), (head.min_time(), head.max_time()));
+
+    assert!(matches!(
+        head.append_batch(&[RefSample { sref: a, t: 13000, v: 0.0 }, RefSample { sref: SeriesRef(42), t: 0, v: 0.0 }]),
+        Err(Error::UnknownSeries(SeriesRef(42)))
+    ));
+    assert_eq!(12000, head.series(a).unwrap().max_time());
+}
+
+#[test]
+fn test_head_ooo() {
+    let head = Head::with_samples_per_chunk(10).with_ooo_window(5000);
+    let ls = Labels::from_pairs(&[("__name__", "up")]);
+    for t in (0..30).filter(|t| t % 3 != 0) {
+        head.append(&ls, t * 1000, t as f64).unwrap();
+    }
+    assert!(matches!(
+        head.append(&ls, 21000, 0.0),
+        Err(Error::Chunk(xor::Error::OutOfOrderSample(21000)))
+    ));
+    head.append(&ls, 0, 0.0).unwrap_err();
+    head.append(&ls, 24000, 24.0).unwrap();
+    head.append(&ls, 27000, 27.0).unwrap();
+
+    let s = head.series_by_labels(&ls).unwrap();
+    let got: Vec<_> = s.snapshot().unwrap().iter().map(|(t, _)| t / 1000).collect();
+    let want: Vec<_> = (1..30).filter(|t| t % 3 != 0 || *t >= 24).collect();
+    assert_eq!(want, got);
+    assert_eq!(2, head.compact_ooo().unwrap());
+    assert!(s.snapshot().unwrap().ooo_samples().is_empty());
+    assert_eq!(want.len(), s.snapshot().unwrap().iter().count());
+}
+
+#[test]
+fn test_head_concurrent_create() {
+    use std::thread;
+
+    let head = Arc::new(Head::new());
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let head = head.clone();
+            thread::spawn(move || {
+                for j in 0..100 {
+                    let ls = Labels::from_pairs(&[("__name__", "m"), ("j", &j.to_string())]);
+                    let (s, _) = head.get_or_create(&ls).unwrap();
+                    // every thread writes its own timestamps, some of them lose the race
+                    let _ = s.append(i, i as f64);
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(100, head.num_series());
+    for s in head.all_series() {
+        assert!(s.snapshot().unwrap().num_samples() >= 1);
+    }
+}
@@ -0,0 +1,340 @@
+// The label index maps every label name and value to the postings list of the
+// series that have it: their refs, sorted. Series are selected with matchers
+// by combining postings lists, the same way Prometheus does it.
+use crate::head::SeriesRef;
+use crate::labels::Labels;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// intersect returns the refs that are in both a and b.
+pub fn intersect(a: &[SeriesRef], b: &[SeriesRef]) -> Vec<SeriesRef> {
+    let mut out = Vec::with_capacity(a.len().min(b.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    out
+}
+
+// union returns the refs that are in a or b.
+pub fn union(a: &[SeriesRef], b: &[SeriesRef]) -> Vec<SeriesRef> {
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => {
+                out.push(a[i]);
+                i += 1;
+            }
+            std::cmp::Ordering::Greater => {
+                out.push(b[j]);
+                j += 1;
+            }
+            std::cmp::Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    out.extend_from_slice(&a[i..]);
+    out.extend_from_slice(&b[j..]);
+    out
+}
+
+// union_all returns the refs that are in any of lists. It sorts them all at
+// once, which is cheaper than a union per list when there are many.
+pub fn union_all(lists: &[Vec<SeriesRef>]) -> Vec<SeriesRef> {
+    let mut out = lists.concat();
+    out.sort_unstable();
+    out.dedup();
+    out
+}
+
+// difference returns the refs that are in a but not in b.
+pub fn difference(a: &[SeriesRef], b: &[SeriesRef]) -> Vec<SeriesRef> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut j = 0;
+    for r in a {
+        while j < b.len() && b[j] < *r {
+            j += 1;
+        }
+        if j == b.len() || b[j] != *r {
+            out.push(*r);
+        }
+    }
+    out
+}
+
+// MemPostings is a label index held in memory.
+#[derive(Debug, Default)]
+pub struct MemPostings {
+    labels: BTreeMap<String, BTreeMap<String, Vec<SeriesRef>>>,
+    all: Vec<SeriesRef>,
+}
+
+fn insert_sorted(list: &mut Vec<SeriesRef>, sref: SeriesRef) {
+    // refs are mostly added in order
+    if list.last().is_none_or(|last| *last < sref) {
+        list.push(sref);
+    } else if let Err(i) = list.binary_search(&sref) {
+        list.insert(i, sref);
+    }
+}
+
+impl MemPostings {
+    pub fn new() -> MemPostings {
+        MemPostings::default()
+    }
+
+    pub fn add(&mut self, sref: SeriesRef, labels: &Labels) {
+        for l in labels.iter() {
+            let values = self.labels.entry(l.name.clone()).or_default();
+            insert_sorted(values.entry(l.value.clone()).or_default(), sref);
+        }
+        insert_sorted(&mut self.all, sref);
+    }
+
+    pub fn delete(&mut self, sref: SeriesRef, labels: &Labels) {
+        for l in labels.iter() {
+            let Some(values) = self.labels.get_mut(&l.name) else { continue };
+            if let Some(list) = values.get_mut(&l.value) {
+                list.retain(|r| *r != sref);
+                if list.is_empty() {
+                    values.remove(&l.value);
+                }
+            }
+            if values.is_empty() {
+                self.labels.remove(&l.name);
+            }
+        }
+        self.all.retain(|r| *r != sref);
+    }
+}
+
+impl IndexReader for MemPostings {
+    fn postings(&self, name: &str, value: &str) -> Vec<SeriesRef> {
+        self.labels
+            .get(name)
+            .and_then(|values| values.get(value))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn all_postings(&self) -> Vec<SeriesRef> {
+        self.all.clone()
+    }
+
+    fn label_names(&self) -> Vec<String> {
+        self.labels.keys().cloned().collect()
+    }
+
+    fn label_values(&self, name: &str) -> Vec<String> {
+        self.labels.get(name).map_or_else(Vec::new, |values| values.keys().cloned().collect())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchType {
+    Equal,
+    NotEqual,
+    Regex,
+    NotRegex,
+}
+
+impl fmt::Display for MatchType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MatchType::Equal => "=",
+            MatchType::NotEqual => "!=",
+            MatchType::Regex => "=~",
+            MatchType::NotRegex => "!~",
+        })
+    }
+}
+
+// Matcher matches the value of a label. A series without the label matches
+// like one with an empty value. Regular expressions have to match the whole value.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pub kind: MatchType,
+    pub name: String,
+    pub value: String,
+    re: Option<Regex>,
+}
+
+impl Matcher {
+    pub fn new(kind: MatchType, name: &str, value: &str) -> Result<Matcher, regex::Error> {
+        let re = match kind {
+            MatchType::Regex | MatchType::NotRegex => Some(Regex::new(&format!("^(?s:{})$", value))?),
+            _ => None,
+        };
+        Ok(Matcher {
+            kind,
+            name: name.to_string(),
+            value: value.to_string(),
+            re,
+        })
+    }
+
+    pub fn matches(&self, v: &str) -> bool {
+        match self.kind {
+            MatchType::Equal => self.value == v,
+            MatchType::NotEqual => self.value != v,
+            MatchType::Regex => self.re.as_ref().unwrap().is_match(v),
+            MatchType::NotRegex => !self.re.as_ref().unwrap().is_match(v),
+        }
+    }
+
+    // matches_labels returns whether the matcher matches the value of its label in labels.
+    pub fn matches_labels(&self, labels: &Labels) -> bool {
+        self.matches(labels.get(&self.name).unwrap_or(""))
+    }
+
+    // inverse returns the matcher that matches exactly the values this one doesn't.
+    fn inverse(&self) -> Matcher {
+        let kind = match self.kind {
+            MatchType::Equal => MatchType::NotEqual,
+            MatchType::NotEqual => MatchType::Equal,
+            MatchType::Regex => MatchType::NotRegex,
+            MatchType::NotRegex => MatchType::Regex,
+        };
+        Matcher { kind, ..self.clone() }
+    }
+}
+
+impl PartialEq for Matcher {
+    fn eq(&self, other: &Matcher) -> bool {
+        self.kind == other.kind && self.name == other.name && self.value == other.value
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{:?}", self.name, self.kind, self.value)
+    }
+}
+
+// IndexReader is a label index of the head or of a block. All lists it
+// returns are sorted.
+pub trait IndexReader {
+    // postings returns the series with label name=value.
+    fn postings(&self, name: &str, value: &str) -> Vec<SeriesRef>;
+
+    fn all_postings(&self) -> Vec<SeriesRef>;
+
+    fn label_names(&self) -> Vec<String>;
+
+    fn label_values(&self, name: &str) -> Vec<String>;
+
+    // postings_for_matcher returns the series that have the label of m with
+    // a value m matches. Series without the label are left out.
+    fn postings_for_matcher(&self, m: &Matcher) -> Vec<SeriesRef> {
+        if m.kind == MatchType::Equal {
+            return self.postings(&m.name, &m.value);
+        }
+        let lists: Vec<_> = self.label_values(&m.name).into_iter().filter(|v| m.matches(v)).map(|v| self.postings(&m.name, &v)).collect();
+        union_all(&lists)
+    }
+
+    // select returns the series all matchers match.
+    fn select(&self, matchers: &[Matcher]) -> Vec<SeriesRef> {
+        let mut its: Vec<Vec<SeriesRef>> = Vec::new();
+        let mut nots: Vec<SeriesRef> = Vec::new();
+        for m in matchers {
+            if m.matches("") {
+                // also matches series without the label, so subtract the ones
+                // with a value it doesn't match instead
+                nots = union(&nots, &self.postings_for_matcher(&m.inverse()));
+            } else {
+                its.push(self.postings_for_matcher(m));
+            }
+        }
+        // shortest first keeps the intermediate lists small
+        its.sort_by_key(|p| p.len());
+        let mut out = match its.split_first() {
+            Some((first, rest)) => rest.iter().fold(first.clone(), |acc, p| intersect(&acc, p)),
+            None => self.all_postings(),
+        };
+        if !nots.is_empty() {
+            out = difference(&out, &nots);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+fn refs(r: &[u64]) -> Vec<SeriesRef> {
+    r.iter().map(|r| SeriesRef(*r)).collect()
+}
+
+#[test]
+fn test_postings_ops() {
+    let a = refs(&[1, 3, 5, 7, 9]);
+    let b = refs(&[2, 3, 4, 9, 10]);
+    assert_eq!(refs(&[3, 9]), intersect(&a, &b));
+    assert_eq!(refs(&[1, 2, 3, 4, 5, 7, 9, 10]), union(&a, &b));
+    assert_eq!(refs(&[1, 5, 7]), difference(&a, &b));
+    assert_eq!(refs(&[2, 4, 10]), difference(&b, &a));
+    assert_eq!(refs(&[]), intersect(&a, &[]));
+    assert_eq!(a, union(&[], &a));
+    assert_eq!(refs(&[1, 2, 3, 4, 5, 7, 8, 9, 10]), union_all(&[a.clone(), refs(&[8]), b.clone(), a.clone()]));
+    assert_eq!(refs(&[]), union_all(&[]));
+    assert_eq!(a, difference(&a, &[]));
+}
+
+#[test]
+fn test_select() {
+    let mut p = MemPostings::new();
+    let series = [
+        (1, &[("__name__", "up"), ("job", "api"), ("env", "prod")][..]),
+        (2, &[("__name__", "up"), ("job", "db"), ("env", "dev")][..]),
+        (3, &[("__name__", "up"), ("job", "api")][..]),
+        (4, &[("__name__", "requests"), ("job", "api"), ("env", "prod")][..]),
+    ];
+    // out of order, as after a WAL replay
+    for (r, ls) in series.iter().rev() {
+        p.add(SeriesRef(*r), &Labels::from_pairs(ls));
+    }
+    assert_eq!(refs(&[1, 2, 3, 4]), p.all_postings());
+    assert_eq!(vec!["__name__", "env", "job"], p.label_names());
+    assert_eq!(vec!["api", "db"], p.label_values("job"));
+
+    let m = |kind, name: &str, value: &str| Matcher::new(kind, name, value).unwrap();
+    use MatchType::*;
+    let cases = [
+        (vec![m(Equal, "__name__", "up")], vec![1, 2, 3]),
+        (vec![m(Equal, "__name__", "up"), m(Equal, "job", "api")], vec![1, 3]),
+        (vec![m(Equal, "__name__", "up"), m(NotEqual, "job", "api")], vec![2]),
+        // series without env have an empty one
+        (vec![m(Equal, "__name__", "up"), m(NotEqual, "env", "prod")], vec![2, 3]),
+        (vec![m(Equal, "env", "")], vec![3]),
+        (vec![m(NotEqual, "env", "")], vec![1, 2, 4]),
+        (vec![m(Regex, "job", "a.*")], vec![1, 3, 4]),
+        // regular expressions are anchored
+        (vec![m(Regex, "job", "p")], vec![]),
+        (vec![m(Regex, "env", "prod|")], vec![1, 3, 4]),
+        (vec![m(NotRegex, "job", "api|web")], vec![2]),
+        (vec![m(Regex, "__name__", ".*"), m(NotRegex, "env", "d.*")], vec![1, 3, 4]),
+        (vec![m(Equal, "missing", "x")], vec![]),
+        (vec![], vec![1, 2, 3, 4]),
+    ];
+    for (matchers, want) in cases {
+        assert_eq!(refs(&want), p.select(&matchers), "{:?}", matchers.iter().map(|m| m.to_string()).collect::<Vec<_>>());
+    }
+
+    p.delete(SeriesRef(2), &Labels::from_pairs(series[1].1));
+    assert_eq!(vec!["api"], p.label_values("job"));
+    assert_eq!(refs(&[1, 3]), p.select(&[m(Equal, "__name__", "up")]));
+    assert!(Matcher::new(Regex, "a", "(").is_err());
+    assert_eq!(r#"job!~"a\"b""#, m(NotRegex, "job", "a\"b").to_string());
+}
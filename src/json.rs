@@ -0,0 +1,232 @@
+// Just enough JSON to read and write the meta.json of blocks. It parses any
+// document so fields other tools add are skipped, but numbers have to be
+// integers, the only ones meta.json has. They are kept as their text, so
+// both i64 and u64 values survive the round trip.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+// write_string appends s to out as a JSON string.
+pub(crate) fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// parse parses a JSON document, None if it isn't valid or has a number that
+// isn't an integer.
+pub(crate) fn parse(s: &str) -> Option<Value> {
+    let mut p = Parser { b: s.as_bytes(), pos: 0 };
+    let v = p.value()?;
+    p.skip_ws();
+    if p.pos != p.b.len() {
+        return None;
+    }
+    Some(v)
+}
+
+struct Parser<'a> {
+    b: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_ws(&mut self) {
+        while self.pos < self.b.len() && matches!(self.b[self.pos], b' ' | b'\t' | b'\n' | b'\r') {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_ws();
+        self.b.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, lit: &str) -> Option<()> {
+        if self.b[self.pos..].starts_with(lit.as_bytes()) {
+            self.pos += lit.len();
+            return Some(());
+        }
+        None
+    }
+
+    // list reads the elements of an array or the fields of an object up to
+    // the closing bracket, with item reading one.
+    fn list(&mut self, close: u8, mut item: impl FnMut(&mut Self) -> Option<()>) -> Option<()> {
+        self.pos += 1;
+        if self.peek()? == close {
+            self.pos += 1;
+            return Some(());
+        }
+        loop {
+            item(self)?;
+            match self.peek()? {
+                b',' => self.pos += 1,
+                c if c == close => {
+                    self.pos += 1;
+                    return Some(());
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        match self.peek()? {
+            b'n' => self.expect("null").map(|_| Value::Null),
+            b't' => self.expect("true").map(|_| Value::Bool(true)),
+            b'f' => self.expect("false").map(|_| Value::Bool(false)),
+            b'"' => self.string().map(Value::String),
+            b'[' => {
+                let mut a = Vec::new();
+                self.list(b']', |p| {
+                    a.push(p.value()?);
+                    Some(())
+                })?;
+                Some(Value::Array(a))
+            }
+            b'{' => {
+                let mut o = Vec::new();
+                self.list(b'}', |p| {
+                    if p.peek()? != b'"' {
+                        return None;
+                    }
+                    let k = p.string()?;
+                    if p.peek()? != b':' {
+                        return None;
+                    }
+                    p.pos += 1;
+                    o.push((k, p.value()?));
+                    Some(())
+                })?;
+                Some(Value::Object(o))
+            }
+            b'-' | b'0'..=b'9' => {
+                let start = self.pos;
+                self.pos += 1;
+                while self.pos < self.b.len() && self.b[self.pos].is_ascii_digit() {
+                    self.pos += 1;
+                }
+                let n = core::str::from_utf8(&self.b[start..self.pos]).ok()?;
+                (n != "-").then(|| Value::Number(String::from(n)))
+            }
+            _ => None,
+        }
+    }
+
+    // string reads a string starting at its opening quote.
+    fn string(&mut self) -> Option<String> {
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let start = self.pos;
+            while self.pos < self.b.len() && self.b[self.pos] != b'"' && self.b[self.pos] != b'\\' {
+                self.pos += 1;
+            }
+            out.push_str(core::str::from_utf8(&self.b[start..self.pos]).ok()?);
+            if *self.b.get(self.pos)? == b'"' {
+                self.pos += 1;
+                return Some(out);
+            }
+            let c = *self.b.get(self.pos + 1)?;
+            self.pos += 2;
+            out.push(match c {
+                b'"' => '"',
+                b'\\' => '\\',
+                b'/' => '/',
+                b'b' => '\u{8}',
+                b'f' => '\u{c}',
+                b'n' => '\n',
+                b'r' => '\r',
+                b't' => '\t',
+                b'u' => {
+                    let hex = core::str::from_utf8(self.b.get(self.pos..self.pos + 4)?).ok()?;
+                    self.pos += 4;
+                    char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+                }
+                _ => return None,
+            });
+        }
+    }
+}
+
+#[test]
+fn test_json() {
+    let doc = r#" {"a": 1, "b": [true, false, null, "x\"\nA"], "c": {}, "d": -9223372036854775808, "e": 18446744073709551615} "#;
+    let v = parse(doc).unwrap();
+    assert_eq!(Some(1), v.get("a").and_then(Value::as_i64));
+    assert_eq!(Some(i64::MIN), v.get("d").and_then(Value::as_i64));
+    assert_eq!(Some(u64::MAX), v.get("e").and_then(Value::as_u64));
+    assert_eq!(None, v.get("e").and_then(Value::as_i64));
+    assert_eq!(Some("x\"\nA"), v.get("b").and_then(|b| b.as_array()?[3].as_str()));
+    assert_eq!(Some(&Value::Object(Vec::new())), v.get("c"));
+
+    // escaped strings round trip
+    for s in ["", "plain", "quote \" and \\ backslash", "new\nline\ttab\r\u{1}\u{1f}", "ünïcödé ✓ \u{10348}"] {
+        let mut out = String::new();
+        write_string(&mut out, s);
+        assert_eq!(Some(Value::String(String::from(s))), parse(&out), "{}", out);
+    }
+    assert_eq!(Some(Value::String(String::from("/\u{8}\u{c}é"))), parse(r#""\/\b\f\u00e9""#));
+
+    for bad in ["", "{", "[1,]", "{\"a\" 1}", "tru", "\"abc", "1 2", "-", "1.5", "1e3", "\"\\x\"", "\"\\ud800\""] {
+        assert_eq!(None, parse(bad), "{}", bad);
+    }
+}
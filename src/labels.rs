@@ -0,0 +1,133 @@
+use crate::bstream::{Bstream, BstreamReader};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+// METRIC_NAME is the name of the label that holds the metric name.
+pub const METRIC_NAME: &str = "__name__";
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Label {
+    pub name: String,
+    pub value: String,
+}
+
+// Labels is a set of labels sorted by name, which identifies a series.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Labels(Vec<Label>);
+
+impl Labels {
+    // new sorts the labels by name. Labels with an empty value are dropped and
+    // of several labels with the same name the last one is kept.
+    pub fn new(mut labels: Vec<Label>) -> Labels {
+        labels.retain(|l| !l.value.is_empty());
+        // stable, so duplicates keep their order
+        labels.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut out: Vec<Label> = Vec::with_capacity(labels.len());
+        for l in labels {
+            match out.last_mut() {
+                Some(last) if last.name == l.name => *last = l,
+                _ => out.push(l),
+            }
+        }
+        Labels(out)
+    }
+
+    pub fn from_pairs(pairs: &[(&str, &str)]) -> Labels {
+        Labels::new(
+            pairs
+                .iter()
+                .map(|(name, value)| Label { name: String::from(*name), value: String::from(*value) })
+                .collect(),
+        )
+    }
+
+    // get returns the value of the label with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .binary_search_by(|l| l.name.as_str().cmp(name))
+            .ok()
+            .map(|i| self.0[i].value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Label> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // encode writes the labels byte aligned as
+    // [number of labels uvarint]([len uvarint][name][len uvarint][value])...
+    pub fn encode(&self, b: &mut Bstream) {
+        b.write_uvarint(self.0.len() as u64);
+        for l in &self.0 {
+            b.write_uvarint(l.name.len() as u64);
+            b.write_aligned_bytes(l.name.as_bytes());
+            b.write_uvarint(l.value.len() as u64);
+            b.write_aligned_bytes(l.value.as_bytes());
+        }
+    }
+
+    // decode reads labels written by encode from br, which reads stream.
+    pub fn decode(br: &mut BstreamReader, stream: &[u8]) -> Option<Labels> {
+        let n = br.read_uvarint().ok()?;
+        let mut labels = Vec::new();
+        for _ in 0..n {
+            let name = read_string(br, stream)?;
+            let value = read_string(br, stream)?;
+            labels.push(Label { name, value });
+        }
+        Some(Labels::new(labels))
+    }
+}
+
+fn read_string(br: &mut BstreamReader, stream: &[u8]) -> Option<String> {
+    let n = br.read_uvarint().ok()? as usize;
+    let start = br.position() / 8;
+    let s = stream.get(start..start.checked_add(n)?)?;
+    br.seek((start + n) * 8).ok()?;
+    String::from_utf8(s.to_vec()).ok()
+}
+
+impl fmt::Display for Labels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{")?;
+        for (i, l) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={:?}", l.name, l.value)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+#[test]
+fn test_labels() {
+    let ls = Labels::from_pairs(&[("job", "api"), ("__name__", "up"), ("empty", ""), ("instance", "a"), ("job", "db")]);
+    assert_eq!(3, ls.len());
+    assert_eq!(Some("up"), ls.get(METRIC_NAME));
+    assert_eq!(Some("db"), ls.get("job"));
+    assert_eq!(None, ls.get("empty"));
+    assert_eq!(
+        ["__name__", "instance", "job"],
+        ls.iter().map(|l| l.name.as_str()).collect::<Vec<_>>()[..]
+    );
+    assert_eq!(r#"{__name__="up", instance="a", job="db"}"#, alloc::format!("{}", ls));
+    assert_eq!(ls, Labels::from_pairs(&[("instance", "a"), ("job", "db"), ("__name__", "up")]));
+
+    let mut b = Bstream::new(Vec::new());
+    ls.encode(&mut b);
+    Labels::default().encode(&mut b);
+    b.pad_to_byte();
+    let mut br = BstreamReader::new(b.read_bytes());
+    assert_eq!(Some(ls), Labels::decode(&mut br, b.read_bytes()));
+    assert_eq!(Some(Labels::default()), Labels::decode(&mut br, b.read_bytes()));
+    assert_eq!(None, Labels::decode(&mut br, b.read_bytes()));
+}
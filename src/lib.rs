@@ -0,0 +1,46 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+// the test harness needs std anyway, tests use it for threads and printing
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
+
+#[cfg(feature = "std")]
+pub mod block;
+pub mod bstream;
+#[cfg(feature = "std")]
+pub mod compact;
+pub mod crc32;
+pub mod footer;
+#[cfg(feature = "std")]
+pub mod head;
+#[cfg(feature = "std")]
+pub mod index;
+#[cfg(feature = "std")]
+mod json;
+pub mod labels;
+pub mod merge;
+#[cfg(feature = "std")]
+pub mod promql;
+#[cfg(feature = "std")]
+pub mod querier;
+#[cfg(feature = "std")]
+pub mod segment;
+#[cfg(feature = "std")]
+pub mod series;
+#[cfg(feature = "std")]
+pub mod snapshot;
+#[cfg(feature = "std")]
+pub mod tombstones;
+#[cfg(feature = "std")]
+pub mod wal;
+pub mod xor;
+
+// test_dir returns the path of an empty directory for a test, name keeps the
+// tests apart and the process id concurrent runs.
+#[cfg(all(test, feature = "std"))]
+pub(crate) fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust-tsz-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
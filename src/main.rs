@@ -1,7 +1,5 @@
-use xor::XORChunk;
+use rust_tsz::xor::XORChunk;
 
-mod bstream;
-mod xor;
 fn main() {
     let _ = XORChunk::new();
     println!("XORChunk instance created successfully.");
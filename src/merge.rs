@@ -0,0 +1,150 @@
+use crate::xor::{Error, SampleIterator};
+use alloc::collections::BinaryHeap;
+use alloc::vec::Vec;
+#[cfg(test)]
+use alloc::vec;
+use core::cmp::Reverse;
+
+// DuplicatePolicy decides what happens when more than one iterator has a
+// sample at the same timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    // FirstWins keeps the sample of the iterator that comes first in the list.
+    FirstWins,
+    // LastWins keeps the sample of the iterator that comes last in the list.
+    LastWins,
+    // Error stops the merge with Error::DuplicateSample.
+    Error,
+}
+
+// MergeIterator merges any number of iterators, each sorted by timestamp, into
+// a single stream sorted by timestamp. Equal timestamps are resolved by the
+// DuplicatePolicy. It stops at the first error of any of the iterators.
+#[derive(Debug)]
+pub struct MergeIterator<I> {
+    iters: Vec<I>,
+    // next timestamp of every iterator that isn't exhausted, smallest first
+    heap: BinaryHeap<Reverse<(i64, usize)>>,
+    vals: Vec<f64>, // next value of every iterator
+    policy: DuplicatePolicy,
+    err: Option<Error>,
+}
+
+impl<I: SampleIterator> MergeIterator<I> {
+    pub fn new(iters: Vec<I>, policy: DuplicatePolicy) -> MergeIterator<I> {
+        let mut m = MergeIterator {
+            heap: BinaryHeap::with_capacity(iters.len()),
+            vals: Vec::with_capacity(iters.len()),
+            iters,
+            policy,
+            err: None,
+        };
+        m.vals.resize(m.iters.len(), 0.0);
+        for i in 0..m.iters.len() {
+            m.advance(i);
+        }
+        m
+    }
+
+    // advance moves iterator i to its next sample.
+    fn advance(&mut self, i: usize) {
+        match self.iters[i].next() {
+            Some((t, v)) => {
+                self.vals[i] = v;
+                self.heap.push(Reverse((t, i)));
+            }
+            None => {
+                if let Some(err) = self.iters[i].err() {
+                    self.err = Some(err.clone());
+                }
+            }
+        }
+    }
+
+    // into_inner returns the merged iterators.
+    pub fn into_inner(self) -> Vec<I> {
+        self.iters
+    }
+}
+
+impl<I: SampleIterator> Iterator for MergeIterator<I> {
+    type Item = (i64, f64);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.err.is_some() {
+            return None;
+        }
+        // Equal timestamps pop in iterator order, so the first one popped is
+        // the first in the list.
+        let Reverse((t, i)) = self.heap.pop()?;
+        let mut v = self.vals[i];
+        self.advance(i);
+        while let Some(&Reverse((next, j))) = self.heap.peek() {
+            if next != t {
+                break;
+            }
+            self.heap.pop();
+            match self.policy {
+                DuplicatePolicy::FirstWins => {}
+                DuplicatePolicy::LastWins => v = self.vals[j],
+                DuplicatePolicy::Error => {
+                    self.err = Some(Error::DuplicateSample(t));
+                    return None;
+                }
+            }
+            self.advance(j);
+        }
+        Some((t, v))
+    }
+}
+
+impl<I: SampleIterator> SampleIterator for MergeIterator<I> {
+    fn err(&self) -> Option<&Error> {
+        self.err.as_ref()
+    }
+}
+
+#[test]
+fn test_merge_iterator() {
+    use crate::xor::XORChunk;
+
+    let chunk = |samples: &[(i64, f64)]| {
+        let mut c = XORChunk::new();
+        for (t, v) in samples {
+            c.append(*t, *v).unwrap();
+        }
+        c
+    };
+    let chunks = [
+        chunk(&[(1, 1.0), (3, 1.0), (5, 1.0), (7, 1.0)]),
+        chunk(&[(2, 2.0), (3, 2.0), (4, 2.0)]),
+        chunk(&[]),
+        chunk(&[(0, 3.0), (7, 3.0), (8, 3.0)]),
+    ];
+    let iters = || chunks.iter().map(|c| c.iterator()).collect::<Vec<_>>();
+
+    let merged: Vec<_> = MergeIterator::new(iters(), DuplicatePolicy::FirstWins).collect();
+    assert_eq!(
+        vec![(0, 3.0), (1, 1.0), (2, 2.0), (3, 1.0), (4, 2.0), (5, 1.0), (7, 1.0), (8, 3.0)],
+        merged
+    );
+    let merged: Vec<_> = MergeIterator::new(iters(), DuplicatePolicy::LastWins).collect();
+    assert_eq!(
+        vec![(0, 3.0), (1, 1.0), (2, 2.0), (3, 2.0), (4, 2.0), (5, 1.0), (7, 3.0), (8, 3.0)],
+        merged
+    );
+    let mut m = MergeIterator::new(iters(), DuplicatePolicy::Error);
+    assert_eq!(vec![(0, 3.0), (1, 1.0), (2, 2.0)], m.by_ref().collect::<Vec<_>>());
+    assert_eq!(Some(&Error::DuplicateSample(3)), m.err());
+
+    // merges nest, and errors of the merged iterators come through
+    let inner = MergeIterator::new(iters(), DuplicatePolicy::FirstWins);
+    let mut bytes = chunks[0].bytes().to_vec();
+    bytes.truncate(4);
+    let broken = crate::xor::XORIterator::new(&bytes);
+    let mut m = MergeIterator::new(vec![inner], DuplicatePolicy::Error);
+    assert_eq!(8, m.by_ref().count());
+    assert!(m.err().is_none());
+    let mut m = MergeIterator::new(vec![broken], DuplicatePolicy::Error);
+    assert_eq!(None, m.next());
+    assert!(m.err().is_some());
+}
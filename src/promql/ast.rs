@@ -0,0 +1,327 @@
+// The syntax tree of a query. Display prints an expression the way
+// Prometheus does, which parses back into the same tree.
+use crate::index::{MatchType, Matcher};
+use crate::labels::METRIC_NAME;
+use crate::promql::lexer::DURATION_UNITS;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    Scalar,
+    Vector,
+    Matrix,
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ValueType::Scalar => "scalar",
+            ValueType::Vector => "instant vector",
+            ValueType::Matrix => "range vector",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Vector(VectorSelector),
+    Matrix(MatrixSelector),
+    Paren(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinaryExpr),
+    Aggregate(AggregateExpr),
+    Call(Call),
+}
+
+impl Expr {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Expr::Number(_) => ValueType::Scalar,
+            Expr::Vector(_) | Expr::Aggregate(_) | Expr::Call(_) => ValueType::Vector,
+            Expr::Matrix(_) => ValueType::Matrix,
+            Expr::Paren(e) | Expr::Neg(e) => e.value_type(),
+            Expr::Binary(b) => match (b.lhs.value_type(), b.rhs.value_type()) {
+                (ValueType::Scalar, ValueType::Scalar) => ValueType::Scalar,
+                _ => ValueType::Vector,
+            },
+        }
+    }
+}
+
+// VectorSelector selects the last sample of every matching series within
+// the lookback delta. The metric name is one of the matchers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorSelector {
+    pub matchers: Vec<Matcher>,
+    // in milliseconds, positive looks into the past
+    pub offset: i64,
+}
+
+// MatrixSelector selects all samples of every matching series in range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixSelector {
+    pub selector: VectorSelector,
+    pub range: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Eql,
+    Neq,
+    Gtr,
+    Lss,
+    Gte,
+    Lte,
+}
+
+impl BinOp {
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, BinOp::Eql | BinOp::Neq | BinOp::Gtr | BinOp::Lss | BinOp::Gte | BinOp::Lte)
+    }
+
+    // precedence returns how tightly the operator binds, higher binds tighter.
+    pub(crate) fn precedence(&self) -> u8 {
+        match self {
+            BinOp::Eql | BinOp::Neq | BinOp::Gtr | BinOp::Lss | BinOp::Gte | BinOp::Lte => 1,
+            BinOp::Add | BinOp::Sub => 2,
+            BinOp::Mul | BinOp::Div | BinOp::Mod => 3,
+            BinOp::Pow => 4,
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+            BinOp::Eql => "==",
+            BinOp::Neq => "!=",
+            BinOp::Gtr => ">",
+            BinOp::Lss => "<",
+            BinOp::Gte => ">=",
+            BinOp::Lte => "<=",
+        })
+    }
+}
+
+// VectorMatching says which labels match the samples of two vectors: only
+// the listed ones with on, all but them with ignoring.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VectorMatching {
+    pub on: bool,
+    pub labels: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinaryExpr {
+    pub op: BinOp,
+    pub lhs: Box<Expr>,
+    pub rhs: Box<Expr>,
+    pub matching: Option<VectorMatching>,
+    // comparisons return 0 or 1 instead of filtering
+    pub return_bool: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+    Topk,
+}
+
+impl AggOp {
+    pub(crate) fn from_name(name: &str) -> Option<AggOp> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "sum" => AggOp::Sum,
+            "avg" => AggOp::Avg,
+            "min" => AggOp::Min,
+            "max" => AggOp::Max,
+            "count" => AggOp::Count,
+            "topk" => AggOp::Topk,
+            _ => return None,
+        })
+    }
+
+    // has_param returns whether the aggregation takes a parameter before the vector.
+    pub fn has_param(&self) -> bool {
+        *self == AggOp::Topk
+    }
+}
+
+impl fmt::Display for AggOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AggOp::Sum => "sum",
+            AggOp::Avg => "avg",
+            AggOp::Min => "min",
+            AggOp::Max => "max",
+            AggOp::Count => "count",
+            AggOp::Topk => "topk",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateExpr {
+    pub op: AggOp,
+    pub expr: Box<Expr>,
+    pub param: Option<Box<Expr>>,
+    // the labels to group by, or to drop with without
+    pub grouping: Vec<String>,
+    pub without: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Rate,
+    Irate,
+    Increase,
+    Delta,
+    Resets,
+    Changes,
+}
+
+impl Function {
+    pub(crate) fn from_name(name: &str) -> Option<Function> {
+        Some(match name {
+            "rate" => Function::Rate,
+            "irate" => Function::Irate,
+            "increase" => Function::Increase,
+            "delta" => Function::Delta,
+            "resets" => Function::Resets,
+            "changes" => Function::Changes,
+            _ => return None,
+        })
+    }
+
+    // arg_types returns the types of the arguments the function takes.
+    pub fn arg_types(&self) -> &'static [ValueType] {
+        &[ValueType::Matrix]
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Function::Rate => "rate",
+            Function::Irate => "irate",
+            Function::Increase => "increase",
+            Function::Delta => "delta",
+            Function::Resets => "resets",
+            Function::Changes => "changes",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub func: Function,
+    pub args: Vec<Expr>,
+}
+
+// format_duration prints milliseconds like 1h30m.
+pub(crate) fn format_duration(mut ms: i64) -> String {
+    if ms == 0 {
+        return "0s".to_string();
+    }
+    let mut out = String::new();
+    if ms < 0 {
+        out.push('-');
+        ms = -ms;
+    }
+    for (unit, n) in DURATION_UNITS {
+        if ms >= n {
+            out.push_str(&format!("{}{}", ms / n, unit));
+            ms %= n;
+        }
+    }
+    out
+}
+
+fn format_number(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_string()
+    } else if v.is_infinite() {
+        if v > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        format!("{}", v)
+    }
+}
+
+impl fmt::Display for VectorSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.matchers.iter().find(|m| m.name == METRIC_NAME && m.kind == MatchType::Equal);
+        let rest: Vec<_> = self.matchers.iter().filter(|m| Some(*m) != name).map(|m| m.to_string()).collect();
+        if let Some(name) = name {
+            f.write_str(&name.value)?;
+        }
+        if !rest.is_empty() || name.is_none() {
+            write!(f, "{{{}}}", rest.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn write_offset(f: &mut fmt::Formatter<'_>, offset: i64) -> fmt::Result {
+    if offset != 0 {
+        write!(f, " offset {}", format_duration(offset))?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(v) => f.write_str(&format_number(*v)),
+            Expr::Vector(vs) => {
+                write!(f, "{}", vs)?;
+                write_offset(f, vs.offset)
+            }
+            Expr::Matrix(ms) => {
+                write!(f, "{}[{}]", ms.selector, format_duration(ms.range))?;
+                write_offset(f, ms.selector.offset)
+            }
+            Expr::Paren(e) => write!(f, "({})", e),
+            Expr::Neg(e) => write!(f, "-{}", e),
+            Expr::Binary(b) => {
+                write!(f, "{} {}", b.lhs, b.op)?;
+                if b.return_bool {
+                    f.write_str(" bool")?;
+                }
+                if let Some(m) = &b.matching {
+                    write!(f, " {}({})", if m.on { "on" } else { "ignoring" }, m.labels.join(", "))?;
+                }
+                write!(f, " {}", b.rhs)
+            }
+            Expr::Aggregate(a) => {
+                write!(f, "{}", a.op)?;
+                if a.without || !a.grouping.is_empty() {
+                    write!(f, " {} ({}) ", if a.without { "without" } else { "by" }, a.grouping.join(", "))?;
+                }
+                match &a.param {
+                    Some(p) => write!(f, "({}, {})", p, a.expr),
+                    None => write!(f, "({})", a.expr),
+                }
+            }
+            Expr::Call(c) => {
+                let args: Vec<_> = c.args.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}({})", c.func, args.join(", "))
+            }
+        }
+    }
+}
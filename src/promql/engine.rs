@@ -0,0 +1,592 @@
+// The engine evaluates a parsed query at one timestamp, or at every step of
+// a range. The samples of all selectors are loaded once up front for the
+// whole range and every step evaluates against them.
+use super::ast::*;
+use super::functions;
+use super::parser::parse;
+use super::Error;
+use crate::labels::{Label, Labels, METRIC_NAME};
+use crate::querier::{Querier, Series};
+use std::collections::{BTreeMap, HashMap};
+
+// DEFAULT_LOOKBACK_DELTA is how far back an instant vector selector looks
+// for the last sample of a series, in milliseconds.
+pub const DEFAULT_LOOKBACK_DELTA: i64 = 5 * 60 * 1000;
+
+// Sample is an element of an instant vector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub labels: Labels,
+    pub t: i64,
+    pub v: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    Vector(Vec<Sample>),
+    Matrix(Vec<Series>),
+}
+
+pub struct Engine {
+    lookback_delta: i64,
+}
+
+impl Default for Engine {
+    fn default() -> Engine {
+        Engine::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Engine {
+        Engine { lookback_delta: DEFAULT_LOOKBACK_DELTA }
+    }
+
+    pub fn with_lookback_delta(mut self, delta: i64) -> Engine {
+        self.lookback_delta = delta;
+        self
+    }
+
+    // instant_query evaluates query at t.
+    pub fn instant_query(&self, q: &dyn Querier, query: &str, t: i64) -> Result<Value, Error> {
+        let expr = parse(query)?;
+        let ev = Evaluator::load(q, &expr, t, t, self.lookback_delta)?;
+        ev.eval(&expr, t)
+    }
+
+    // range_query evaluates query at every step from start to end and
+    // returns a series per labelset, sorted by labels. A scalar result is a
+    // series without labels.
+    pub fn range_query(&self, q: &dyn Querier, query: &str, start: i64, end: i64, step: i64) -> Result<Vec<Series>, Error> {
+        if step <= 0 {
+            return Err(Error::Eval("zero or negative query resolution step widths are not accepted".to_string()));
+        }
+        if end < start {
+            return Err(Error::Eval("end timestamp must not be before start time".to_string()));
+        }
+        let expr = parse(query)?;
+        if expr.value_type() == ValueType::Matrix {
+            return Err(Error::Eval(format!("invalid expression type {} for range query", expr.value_type())));
+        }
+        let ev = Evaluator::load(q, &expr, start, end, self.lookback_delta)?;
+        let mut out: BTreeMap<Labels, Vec<(i64, f64)>> = BTreeMap::new();
+        let mut ts = start;
+        while ts <= end {
+            match ev.eval(&expr, ts)? {
+                Value::Scalar(v) => out.entry(Labels::default()).or_default().push((ts, v)),
+                Value::Vector(vec) => {
+                    for s in vec {
+                        out.entry(s.labels).or_default().push((ts, s.v));
+                    }
+                }
+                Value::Matrix(_) => unreachable!(),
+            }
+            ts = match ts.checked_add(step) {
+                Some(ts) => ts,
+                None => break,
+            };
+        }
+        Ok(out.into_iter().map(|(labels, samples)| Series { labels, samples }).collect())
+    }
+}
+
+struct Evaluator {
+    lookback_delta: i64,
+    // the series of every selector, by the address of the selector in the tree
+    series: HashMap<usize, Vec<Series>>,
+}
+
+fn key(vs: &VectorSelector) -> usize {
+    vs as *const VectorSelector as usize
+}
+
+impl Evaluator {
+    // load selects the samples every selector in expr needs to evaluate it
+    // from start to end.
+    fn load(q: &dyn Querier, expr: &Expr, start: i64, end: i64, lookback_delta: i64) -> Result<Evaluator, Error> {
+        let mut ev = Evaluator { lookback_delta, series: HashMap::new() };
+        ev.preload(q, expr, start, end)?;
+        Ok(ev)
+    }
+
+    fn preload(&mut self, q: &dyn Querier, expr: &Expr, start: i64, end: i64) -> Result<(), Error> {
+        let mut select = |vs: &VectorSelector, range: i64| -> Result<(), Error> {
+            let mint = start.saturating_sub(vs.offset).saturating_sub(range).saturating_add(1);
+            let maxt = end.saturating_sub(vs.offset);
+            self.series.insert(key(vs), q.select(&vs.matchers, mint, maxt)?);
+            Ok(())
+        };
+        match expr {
+            Expr::Number(_) => Ok(()),
+            Expr::Vector(vs) => select(vs, self.lookback_delta),
+            Expr::Matrix(ms) => select(&ms.selector, ms.range),
+            Expr::Paren(e) | Expr::Neg(e) => self.preload(q, e, start, end),
+            Expr::Binary(b) => {
+                self.preload(q, &b.lhs, start, end)?;
+                self.preload(q, &b.rhs, start, end)
+            }
+            Expr::Aggregate(a) => {
+                if let Some(p) = &a.param {
+                    self.preload(q, p, start, end)?;
+                }
+                self.preload(q, &a.expr, start, end)
+            }
+            Expr::Call(c) => c.args.iter().try_for_each(|arg| self.preload(q, arg, start, end)),
+        }
+    }
+
+    fn eval(&self, expr: &Expr, ts: i64) -> Result<Value, Error> {
+        match expr {
+            Expr::Number(v) => Ok(Value::Scalar(*v)),
+            Expr::Vector(vs) => Ok(Value::Vector(self.vector(vs, ts))),
+            Expr::Matrix(ms) => Ok(Value::Matrix(self.matrix(ms, ts))),
+            Expr::Paren(e) => self.eval(e, ts),
+            Expr::Neg(e) => Ok(match self.eval(e, ts)? {
+                Value::Scalar(v) => Value::Scalar(-v),
+                Value::Vector(vec) => Value::Vector(
+                    vec.into_iter()
+                        .map(|s| Sample { labels: drop_metric_name(&s.labels), t: s.t, v: -s.v })
+                        .collect(),
+                ),
+                Value::Matrix(_) => unreachable!(),
+            }),
+            Expr::Binary(b) => self.binary(b, ts),
+            Expr::Aggregate(a) => self.aggregate(a, ts),
+            Expr::Call(c) => Ok(Value::Vector(self.call(c, ts))),
+        }
+    }
+
+    // call applies a function to the samples of every series in the range
+    // of its argument. The results drop the metric name.
+    fn call(&self, c: &Call, ts: i64) -> Vec<Sample> {
+        let mut arg = &c.args[0];
+        while let Expr::Paren(e) = arg {
+            arg = e;
+        }
+        let Expr::Matrix(ms) = arg else { unreachable!() };
+        let range_end = ts.saturating_sub(ms.selector.offset);
+        let range_start = range_end.saturating_sub(ms.range);
+        let mut out = Vec::new();
+        for s in self.matrix(ms, ts) {
+            let v = match c.func {
+                Function::Rate => functions::rate(&s.samples, range_start, range_end),
+                Function::Increase => functions::increase(&s.samples, range_start, range_end),
+                Function::Delta => functions::delta(&s.samples, range_start, range_end),
+                Function::Irate => functions::irate(&s.samples),
+                Function::Resets => functions::resets(&s.samples),
+                Function::Changes => functions::changes(&s.samples),
+            };
+            if let Some(v) = v {
+                out.push(Sample { labels: drop_metric_name(&s.labels), t: ts, v });
+            }
+        }
+        out
+    }
+
+    fn scalar(&self, expr: &Expr, ts: i64) -> Result<f64, Error> {
+        match self.eval(expr, ts)? {
+            Value::Scalar(v) => Ok(v),
+            _ => unreachable!(),
+        }
+    }
+
+    fn instant_vector(&self, expr: &Expr, ts: i64) -> Result<Vec<Sample>, Error> {
+        match self.eval(expr, ts)? {
+            Value::Vector(vec) => Ok(vec),
+            _ => unreachable!(),
+        }
+    }
+
+    // vector returns the last sample of every series in (ts-lookback, ts],
+    // stamped with ts.
+    fn vector(&self, vs: &VectorSelector, ts: i64) -> Vec<Sample> {
+        let refts = ts.saturating_sub(vs.offset);
+        let mut out = Vec::new();
+        for s in &self.series[&key(vs)] {
+            let i = s.samples.partition_point(|&(t, _)| t <= refts);
+            if i == 0 {
+                continue;
+            }
+            let (t, v) = s.samples[i - 1];
+            if t > refts.saturating_sub(self.lookback_delta) {
+                out.push(Sample { labels: s.labels.clone(), t: ts, v });
+            }
+        }
+        out
+    }
+
+    // matrix returns the samples of every series in (ts-range, ts].
+    fn matrix(&self, ms: &MatrixSelector, ts: i64) -> Vec<Series> {
+        let refts = ts.saturating_sub(ms.selector.offset);
+        let mint = refts.saturating_sub(ms.range);
+        let mut out = Vec::new();
+        for s in &self.series[&key(&ms.selector)] {
+            let from = s.samples.partition_point(|&(t, _)| t <= mint);
+            let to = s.samples.partition_point(|&(t, _)| t <= refts);
+            if from < to {
+                out.push(Series { labels: s.labels.clone(), samples: s.samples[from..to].to_vec() });
+            }
+        }
+        out
+    }
+
+    fn binary(&self, b: &BinaryExpr, ts: i64) -> Result<Value, Error> {
+        let lhs = self.eval(&b.lhs, ts)?;
+        let rhs = self.eval(&b.rhs, ts)?;
+        Ok(match (lhs, rhs) {
+            (Value::Scalar(l), Value::Scalar(r)) => {
+                let (v, keep) = apply(b.op, l, r);
+                Value::Scalar(if b.op.is_comparison() { bool_value(keep) } else { v })
+            }
+            (Value::Vector(vec), Value::Scalar(r)) => Value::Vector(vector_scalar(b, vec, r, false)),
+            (Value::Scalar(l), Value::Vector(vec)) => Value::Vector(vector_scalar(b, vec, l, true)),
+            (Value::Vector(l), Value::Vector(r)) => Value::Vector(vector_vector(b, l, r)?),
+            _ => unreachable!(),
+        })
+    }
+
+    fn aggregate(&self, a: &AggregateExpr, ts: i64) -> Result<Value, Error> {
+        let param = match &a.param {
+            Some(p) => Some(self.scalar(p, ts)?),
+            None => None,
+        };
+        let vec = self.instant_vector(&a.expr, ts)?;
+        let group_labels = |labels: &Labels| -> Labels {
+            let keep = |l: &&Label| match a.without {
+                true => l.name != METRIC_NAME && !a.grouping.contains(&l.name),
+                false => a.grouping.contains(&l.name),
+            };
+            Labels::new(labels.iter().filter(keep).cloned().collect())
+        };
+
+        if a.op == AggOp::Topk {
+            let k = param.unwrap();
+            if k.is_nan() || k < 1.0 {
+                return Ok(Value::Vector(Vec::new()));
+            }
+            let mut groups: BTreeMap<Labels, Vec<Sample>> = BTreeMap::new();
+            for s in vec {
+                groups.entry(group_labels(&s.labels)).or_default().push(s);
+            }
+            let mut out = Vec::new();
+            for (_, mut group) in groups {
+                // descending, NaN last
+                group.sort_by(|x, y| match (x.v.is_nan(), y.v.is_nan()) {
+                    (false, false) => y.v.partial_cmp(&x.v).unwrap(),
+                    (nx, ny) => nx.cmp(&ny),
+                });
+                out.extend(group.into_iter().take(k.min(usize::MAX as f64) as usize));
+            }
+            return Ok(Value::Vector(out));
+        }
+
+        struct Group {
+            value: f64,
+            count: usize,
+        }
+        let mut groups: BTreeMap<Labels, Group> = BTreeMap::new();
+        for s in vec {
+            let labels = group_labels(&s.labels);
+            let Some(g) = groups.get_mut(&labels) else {
+                let value = if a.op == AggOp::Count { 1.0 } else { s.v };
+                groups.insert(labels, Group { value, count: 1 });
+                continue;
+            };
+            g.count += 1;
+            match a.op {
+                AggOp::Sum | AggOp::Avg => g.value += s.v,
+                AggOp::Count => g.value += 1.0,
+                // NaN only wins if all values are NaN
+                AggOp::Min if g.value > s.v || g.value.is_nan() => g.value = s.v,
+                AggOp::Max if g.value < s.v || g.value.is_nan() => g.value = s.v,
+                _ => {}
+            }
+        }
+        let out = groups
+            .into_iter()
+            .map(|(labels, g)| {
+                let v = if a.op == AggOp::Avg { g.value / g.count as f64 } else { g.value };
+                Sample { labels, t: ts, v }
+            })
+            .collect();
+        Ok(Value::Vector(out))
+    }
+}
+
+fn bool_value(b: bool) -> f64 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+// apply returns the result of l op r and, for comparisons, whether it holds.
+fn apply(op: BinOp, l: f64, r: f64) -> (f64, bool) {
+    match op {
+        BinOp::Add => (l + r, true),
+        BinOp::Sub => (l - r, true),
+        BinOp::Mul => (l * r, true),
+        BinOp::Div => (l / r, true),
+        BinOp::Mod => (l % r, true),
+        BinOp::Pow => (l.powf(r), true),
+        BinOp::Eql => (l, l == r),
+        BinOp::Neq => (l, l != r),
+        BinOp::Gtr => (l, l > r),
+        BinOp::Lss => (l, l < r),
+        BinOp::Gte => (l, l >= r),
+        BinOp::Lte => (l, l <= r),
+    }
+}
+
+fn drop_metric_name(labels: &Labels) -> Labels {
+    Labels::new(labels.iter().filter(|l| l.name != METRIC_NAME).cloned().collect())
+}
+
+// vector_scalar applies op to every sample and a scalar, swapped means the
+// scalar is on the left.
+fn vector_scalar(b: &BinaryExpr, vec: Vec<Sample>, scalar: f64, swapped: bool) -> Vec<Sample> {
+    let mut out = Vec::with_capacity(vec.len());
+    for s in vec {
+        let (l, r) = if swapped { (scalar, s.v) } else { (s.v, scalar) };
+        let (mut v, keep) = apply(b.op, l, r);
+        if b.op.is_comparison() {
+            if b.return_bool {
+                v = bool_value(keep);
+            } else if !keep {
+                continue;
+            } else {
+                // a filter keeps the value of the vector
+                v = s.v;
+            }
+        }
+        let labels = if !b.op.is_comparison() || b.return_bool { drop_metric_name(&s.labels) } else { s.labels };
+        out.push(Sample { labels, t: s.t, v });
+    }
+    out
+}
+
+// signature returns the labels two samples have to share to match.
+fn signature(labels: &Labels, matching: Option<&VectorMatching>) -> Labels {
+    let keep = |l: &&Label| match matching {
+        Some(m) if m.on => m.labels.contains(&l.name),
+        Some(m) => l.name != METRIC_NAME && !m.labels.contains(&l.name),
+        None => l.name != METRIC_NAME,
+    };
+    Labels::new(labels.iter().filter(keep).cloned().collect())
+}
+
+// vector_vector matches the samples of two vectors one to one.
+fn vector_vector(b: &BinaryExpr, lhs: Vec<Sample>, rhs: Vec<Sample>) -> Result<Vec<Sample>, Error> {
+    let matching = b.matching.as_ref();
+    let mut right: HashMap<Labels, &Sample> = HashMap::new();
+    for s in &rhs {
+        let sig = signature(&s.labels, matching);
+        if right.insert(sig.clone(), s).is_some() {
+            return Err(Error::Eval(format!(
+                "found duplicate series for the match group {} on the right hand-side of the operation: \
+                 many-to-many matching not allowed",
+                sig
+            )));
+        }
+    }
+
+    let mut matched: HashMap<Labels, ()> = HashMap::new();
+    let mut result_labels: HashMap<Labels, ()> = HashMap::new();
+    let mut out = Vec::new();
+    for ls in lhs {
+        let sig = signature(&ls.labels, matching);
+        let Some(rs) = right.get(&sig) else { continue };
+        let (mut v, keep) = apply(b.op, ls.v, rs.v);
+        if b.op.is_comparison() {
+            if b.return_bool {
+                v = bool_value(keep);
+            } else if !keep {
+                continue;
+            }
+        }
+        if matched.insert(sig.clone(), ()).is_some() {
+            return Err(Error::Eval(format!(
+                "found duplicate series for the match group {} on the left hand-side of the operation: \
+                 many-to-many matching not allowed",
+                sig
+            )));
+        }
+
+        let drop_name = !b.op.is_comparison() || b.return_bool;
+        let keep_label = |l: &&Label| match matching {
+            Some(m) if m.on => m.labels.contains(&l.name),
+            Some(m) => !m.labels.contains(&l.name),
+            None => true,
+        } && !(drop_name && l.name == METRIC_NAME);
+        let labels = Labels::new(ls.labels.iter().filter(keep_label).cloned().collect());
+        if result_labels.insert(labels.clone(), ()).is_some() {
+            return Err(Error::Eval(format!("vector cannot contain metrics with the same labelset {}", labels)));
+        }
+        out.push(Sample { labels, t: ls.t, v });
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+fn test_head() -> crate::head::Head {
+    let head = crate::head::Head::new();
+    let series = [
+        (&[("__name__", "http_requests"), ("job", "api"), ("instance", "0")][..], 1.0),
+        (&[("__name__", "http_requests"), ("job", "api"), ("instance", "1")][..], 2.0),
+        (&[("__name__", "http_requests"), ("job", "web"), ("instance", "0")][..], 3.0),
+        (&[("__name__", "http_limits"), ("job", "api"), ("instance", "0")][..], 100.0),
+        (&[("__name__", "http_limits"), ("job", "api"), ("instance", "1")][..], 100.0),
+        (&[("__name__", "http_limits"), ("job", "web"), ("instance", "0")][..], 50.0),
+    ];
+    // every 10s from 0 to 10m, growing by the factor each step
+    for (labels, factor) in series {
+        let labels = Labels::from_pairs(labels);
+        for i in 0..=60 {
+            head.append(&labels, i * 10_000, i as f64 * factor).unwrap();
+        }
+    }
+    head
+}
+
+#[cfg(test)]
+fn vector_result(v: Value) -> Vec<(String, f64)> {
+    match v {
+        Value::Vector(vec) => vec.into_iter().map(|s| (s.labels.to_string(), s.v)).collect(),
+        v => panic!("not a vector: {:?}", v),
+    }
+}
+
+#[test]
+fn test_instant_query() {
+    let head = test_head();
+    let engine = Engine::new();
+    let query = |q: &str, t: i64| engine.instant_query(&head, q, t).unwrap_or_else(|err| panic!("{}: {}", q, err));
+    let vector = |q: &str, t: i64| vector_result(query(q, t));
+
+    assert_eq!(Value::Scalar(7.0), query("1 + 2 * 3", 0));
+    assert_eq!(Value::Scalar(-512.0), query("-2 ^ 3 ^ 2", 0));
+    assert_eq!(Value::Scalar(1.0), query("1 < bool 2", 0));
+    assert_eq!(
+        vec![(r#"{__name__="http_requests", instance="0", job="web"}"#.to_string(), 30.0)],
+        vector(r#"http_requests{job="web"}"#, 100_000)
+    );
+    // between samples the last one counts, the lookback delta ends the series
+    assert_eq!(vector("http_requests", 105_000), vector("http_requests", 100_000));
+    assert_eq!(3, vector("http_requests", 600_000 + 299_999).len());
+    assert!(vector("http_requests", 600_000 + 300_000).is_empty());
+    assert_eq!(vector(r#"http_requests offset 1m"#, 160_000), vector("http_requests", 100_000));
+
+    let m = match query(r#"http_requests{instance="1"}[30s]"#, 100_000) {
+        Value::Matrix(m) => m,
+        v => panic!("{:?}", v),
+    };
+    assert_eq!(vec![(80_000, 16.0), (90_000, 18.0), (100_000, 20.0)], m[0].samples);
+
+    // arithmetic drops the metric name, filters keep it and the left value
+    assert_eq!(
+        vec![
+            (r#"{instance="0", job="api"}"#.to_string(), 40.0),
+            (r#"{instance="0", job="web"}"#.to_string(), 120.0),
+            (r#"{instance="1", job="api"}"#.to_string(), 80.0),
+        ],
+        vector("http_requests * 2", 200_000)
+    );
+    assert_eq!(vec![(r#"{__name__="http_requests", instance="0", job="web"}"#.to_string(), 60.0)], vector("50 < http_requests", 200_000));
+    assert_eq!(
+        vec![0.0, 1.0, 0.0],
+        vector("http_requests > bool 50", 200_000).into_iter().map(|s| s.1).collect::<Vec<_>>()
+    );
+    assert_eq!(vec![-20.0, -60.0, -40.0], vector("-http_requests", 200_000).into_iter().map(|s| s.1).collect::<Vec<_>>());
+
+    // one to one matching
+    assert_eq!(
+        vec![
+            (r#"{instance="0", job="api"}"#.to_string(), 0.01),
+            (r#"{instance="0", job="web"}"#.to_string(), 0.06),
+            (r#"{instance="1", job="api"}"#.to_string(), 0.02),
+        ],
+        vector("http_requests / http_limits", 200_000)
+    );
+    assert_eq!(vec![(r#"{job="web"}"#.to_string(), 0.06)], vector(r#"http_requests / on(job) http_limits{job="web"}"#, 200_000));
+    assert_eq!(
+        vec![(r#"{__name__="http_requests", job="web"}"#.to_string(), 60.0)],
+        vector(r#"http_requests{job="web"} < ignoring(instance) http_limits{instance="0"}"#, 200_000)
+    );
+    let err = engine.instant_query(&head, "http_requests + on(job) http_limits", 200_000).unwrap_err();
+    assert!(err.to_string().contains("many-to-many"), "{}", err);
+
+    // aggregations
+    assert_eq!(vec![(r#"{job="api"}"#.to_string(), 60.0), (r#"{job="web"}"#.to_string(), 60.0)], vector("sum by (job) (http_requests)", 200_000));
+    assert_eq!(vec![("{}".to_string(), 40.0)], vector("avg(http_requests)", 200_000));
+    assert_eq!(vec![("{}".to_string(), 3.0)], vector("count(http_requests)", 200_000));
+    assert_eq!(
+        vec![(r#"{instance="0"}"#.to_string(), 20.0), (r#"{instance="1"}"#.to_string(), 40.0)],
+        vector("min without (job) (http_requests)", 200_000)
+    );
+    assert_eq!(vec![(r#"{job="api"}"#.to_string(), 40.0), (r#"{job="web"}"#.to_string(), 60.0)], vector("max(http_requests) by (job)", 200_000));
+    assert_eq!(
+        vec![
+            (r#"{__name__="http_requests", instance="1", job="api"}"#.to_string(), 40.0),
+            (r#"{__name__="http_requests", instance="0", job="web"}"#.to_string(), 60.0),
+        ],
+        vector("topk by (job) (1, http_requests)", 200_000)
+    );
+    assert!(vector("topk(0, http_requests)", 200_000).is_empty());
+    assert_eq!(vec![("{}".to_string(), 120.0)], vector("sum(http_requests) / count(http_limits) * 3", 200_000));
+}
+
+#[test]
+fn test_range_query() {
+    let head = test_head();
+    let engine = Engine::new();
+    let got = engine.range_query(&head, "sum by (job) (http_requests)", 0, 60_000, 30_000).unwrap();
+    assert_eq!(2, got.len());
+    assert_eq!(Labels::from_pairs(&[("job", "api")]), got[0].labels);
+    assert_eq!(vec![(0, 0.0), (30_000, 9.0), (60_000, 18.0)], got[0].samples);
+    assert_eq!(vec![(0, 0.0), (30_000, 9.0), (60_000, 18.0)], got[1].samples);
+
+    // a series only shows up at the steps it has a sample
+    let got = engine.range_query(&head, "http_requests > 50", 0, 300_000, 100_000).unwrap();
+    assert_eq!(2, got.len());
+    assert_eq!(vec![(200_000, 60.0), (300_000, 90.0)], got[0].samples);
+    assert_eq!(vec![(300_000, 60.0)], got[1].samples);
+
+    let got = engine.range_query(&head, "1 + 1", 0, 10, 5).unwrap();
+    assert_eq!(vec![Series { labels: Labels::default(), samples: vec![(0, 2.0), (5, 2.0), (10, 2.0)] }], got);
+
+    assert!(engine.range_query(&head, "http_requests[1m]", 0, 10, 5).is_err());
+    assert!(engine.range_query(&head, "http_requests", 0, 10, 0).is_err());
+    assert!(engine.range_query(&head, "http_requests", 10, 0, 5).is_err());
+}
+
+#[test]
+fn test_functions() {
+    let head = test_head();
+    let engine = Engine::new();
+    let vector = |q: &str, t: i64| vector_result(engine.instant_query(&head, q, t).unwrap_or_else(|err| panic!("{}: {}", q, err)));
+    let approx = |want: &[(&str, f64)], got: Vec<(String, f64)>| {
+        assert_eq!(want.len(), got.len(), "{:?}", got);
+        for ((wl, wv), (gl, gv)) in want.iter().zip(&got) {
+            assert_eq!(wl, gl);
+            assert!((wv - gv).abs() < 1e-9, "{:?}", got);
+        }
+    };
+
+    // 6 samples in the last minute, extrapolated by the 10s to the start
+    approx(&[(r#"{instance="1", job="api"}"#, 12.0)], vector(r#"increase(http_requests{instance="1"}[1m])"#, 300_000));
+    approx(&[(r#"{instance="1", job="api"}"#, 0.2)], vector(r#"rate(http_requests{instance="1"}[1m])"#, 300_000));
+    approx(&[(r#"{instance="1", job="api"}"#, 0.2)], vector(r#"irate(http_requests{instance="1"}[1m] offset 1m)"#, 300_000));
+    approx(&[(r#"{instance="1", job="api"}"#, 12.0)], vector(r#"delta(http_requests{instance="1"}[1m])"#, 300_000));
+    approx(&[(r#"{job="api"}"#, 0.3), (r#"{job="web"}"#, 0.3)], vector("sum by (job) (rate(http_requests[1m]))", 300_000));
+    approx(&[(r#"{instance="0", job="web"}"#, 0.0)], vector(r#"resets(http_requests{job="web"}[10m])"#, 300_000));
+    approx(&[(r#"{instance="0", job="web"}"#, 5.0)], vector(r#"changes(http_requests{job="web"}[1m]) - 0"#, 300_000));
+    // too few samples for a rate
+    assert!(vector("rate(http_requests[10s])", 300_000).is_empty());
+    assert!(vector("rate(http_requests[1m])", 2_000_000).is_empty());
+
+    let got = engine.range_query(&head, r#"rate(http_requests{job="web"}[1m])"#, 0, 120_000, 60_000).unwrap();
+    assert_eq!(1, got.len());
+    assert_eq!(vec![60_000, 120_000], got[0].samples.iter().map(|s| s.0).collect::<Vec<_>>());
+}
@@ -0,0 +1,184 @@
+// The functions over range vectors. Each takes the samples of one series in
+// a window (range_start, range_end], sorted by time, and returns None if it
+// has no value for them. rate, increase and delta extrapolate the change
+// over the samples to the whole window like Prometheus does.
+
+// rate returns the per second increase of a counter.
+pub fn rate(samples: &[(i64, f64)], range_start: i64, range_end: i64) -> Option<f64> {
+    extrapolated_rate(samples, range_start, range_end, true, true)
+}
+
+// increase returns the increase of a counter.
+pub fn increase(samples: &[(i64, f64)], range_start: i64, range_end: i64) -> Option<f64> {
+    extrapolated_rate(samples, range_start, range_end, true, false)
+}
+
+// delta returns the change of a gauge.
+pub fn delta(samples: &[(i64, f64)], range_start: i64, range_end: i64) -> Option<f64> {
+    extrapolated_rate(samples, range_start, range_end, false, false)
+}
+
+// irate returns the per second increase of a counter between the last two samples.
+pub fn irate(samples: &[(i64, f64)]) -> Option<f64> {
+    let [.., (pt, pv), (lt, lv)] = *samples else { return None };
+    if lt == pt {
+        return None;
+    }
+    // after a reset the counter increased from 0
+    let v = if lv < pv { lv } else { lv - pv };
+    Some(v / ((lt - pt) as f64 / 1000.0))
+}
+
+// resets returns how often a counter was reset, that is how often a value is
+// lower than the one before.
+pub fn resets(samples: &[(i64, f64)]) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+    Some(samples.windows(2).filter(|w| w[1].1 < w[0].1).count() as f64)
+}
+
+// changes returns how often the value changed.
+pub fn changes(samples: &[(i64, f64)]) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+    let changed = |w: &&[(i64, f64)]| w[1].1 != w[0].1 && !(w[1].1.is_nan() && w[0].1.is_nan());
+    Some(samples.windows(2).filter(changed).count() as f64)
+}
+
+// extrapolated_rate computes the change over the samples and extrapolates it
+// to the window. Towards either end of the window it extrapolates to the
+// boundary if the first or last sample is closer to it than 1.1 times the
+// average distance between samples, else half that distance. A counter is
+// not extrapolated below zero.
+fn extrapolated_rate(samples: &[(i64, f64)], range_start: i64, range_end: i64, is_counter: bool, is_rate: bool) -> Option<f64> {
+    if samples.len() < 2 {
+        return None;
+    }
+    let (first_t, first_v) = samples[0];
+    let (last_t, last_v) = samples[samples.len() - 1];
+    let mut result = last_v - first_v;
+    if is_counter {
+        // add the value before every reset
+        let mut last = 0.0;
+        for &(_, v) in samples {
+            if v < last {
+                result += last;
+            }
+            last = v;
+        }
+    }
+
+    let mut duration_to_start = (first_t - range_start) as f64 / 1000.0;
+    let duration_to_end = (range_end - last_t) as f64 / 1000.0;
+    let sampled_interval = (last_t - first_t) as f64 / 1000.0;
+    let average_interval = sampled_interval / (samples.len() - 1) as f64;
+    if is_counter && result > 0.0 && first_v >= 0.0 {
+        // the counter would have reached zero this far before the first sample
+        let duration_to_zero = sampled_interval * (first_v / result);
+        if duration_to_zero < duration_to_start {
+            duration_to_start = duration_to_zero;
+        }
+    }
+
+    let threshold = average_interval * 1.1;
+    let mut interval = sampled_interval;
+    interval += if duration_to_start < threshold { duration_to_start } else { average_interval / 2.0 };
+    interval += if duration_to_end < threshold { duration_to_end } else { average_interval / 2.0 };
+    result *= interval / sampled_interval;
+    if is_rate {
+        result /= (range_end - range_start) as f64 / 1000.0;
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+const MINUTE: i64 = 60 * 1000;
+
+// load returns a chunk with a sample every 5m, like load 5m in the tests of Prometheus.
+#[cfg(test)]
+fn load(values: &[f64]) -> crate::xor::XORChunk {
+    let mut c = crate::xor::XORChunk::new();
+    for (i, &v) in values.iter().enumerate() {
+        c.append(i as i64 * 5 * MINUTE, v).unwrap();
+    }
+    c
+}
+
+// series returns values like 0+10x5 0+10x4 does in the tests of Prometheus.
+#[cfg(test)]
+fn series(parts: &[(f64, f64, usize)]) -> Vec<f64> {
+    parts.iter().flat_map(|&(start, inc, n)| (0..=n).map(move |i| start + inc * i as f64)).collect()
+}
+
+// window returns the samples of c in [mint, maxt].
+#[cfg(test)]
+fn window(c: &crate::xor::XORChunk, mint: i64, maxt: i64) -> Vec<(i64, f64)> {
+    c.iter_range(mint, maxt).collect()
+}
+
+#[test]
+fn test_counter_functions() {
+    // the cases are from the function tests of Prometheus 2, which select
+    // the samples of a range including both ends
+    let foo = load(&series(&[(0.0, 10.0, 10)]));
+    let bar = load(&series(&[(0.0, 10.0, 5), (0.0, 10.0, 4)]));
+    let dings = load(&series(&[(10.0, 10.0, 10)]));
+    let bumms = load(&series(&[(1.0, 10.0, 10)]));
+    let at = 50 * MINUTE;
+    let w = |c, range: i64| window(c, at - range, at);
+
+    let m50 = 50 * MINUTE;
+    assert_eq!(Some(100.0), increase(&w(&foo, m50), at - m50, at));
+    assert_eq!(Some(90.0), increase(&w(&bar, m50), at - m50, at));
+    assert_eq!(Some(100.0), increase(&w(&dings, m50), at - m50, at));
+    assert_eq!(Some(100.0), increase(&w(&bumms, m50), at - m50, at));
+    assert_eq!(Some(100.0 / 3000.0), rate(&w(&foo, m50), at - m50, at));
+
+    // extrapolated to the end of the range, and to where the counter was 0
+    // at the start
+    let approx = |want: f64, got: Option<f64>| assert!((want - got.unwrap()).abs() < 1e-9, "{} != {:?}", want, got);
+    let at = 45 * MINUTE;
+    approx(60.0, increase(&window(&foo, 16 * MINUTE, at), 15 * MINUTE, at));
+    // too far from the start, extrapolated by half the interval
+    approx(55.0, increase(&window(&foo, 16 * MINUTE, at), 10 * MINUTE, at));
+    approx(90.0, increase(&window(&dings, 0, 40 * MINUTE), -10 * MINUTE, 40 * MINUTE));
+
+    let reset_middle = load(&series(&[(0.0, 10.0, 4), (0.0, 10.0, 5)]));
+    let at = 50 * MINUTE;
+    approx(0.03, rate(&window(&reset_middle, 0, at), 0, at));
+    let reset_end = load(&series(&[(0.0, 10.0, 9), (0.0, 10.0, 1)]));
+    assert_eq!(Some(0.0), rate(&window(&reset_end, 45 * MINUTE, at), 45 * MINUTE, at));
+
+    // a gauge isn't corrected for resets
+    assert_eq!(Some(100.0), delta(&window(&foo, 0, at), 0, at));
+    assert_eq!(Some(40.0), delta(&window(&bar, 0, at), 0, at));
+    assert_eq!(Some(-10.0), delta(&[(0, 10.0), (1000, 0.0)], 0, 1000));
+    assert_eq!(None, rate(&window(&foo, at, at), at - 5 * MINUTE, at));
+
+    let bar = load(&series(&[(0.0, 10.0, 5), (0.0, 10.0, 5)]));
+    assert_eq!(Some(10.0 / 300.0), irate(&window(&foo, 0, at)));
+    assert_eq!(Some(10.0 / 300.0), irate(&window(&bar, 0, at)));
+    assert_eq!(Some(0.0), irate(&window(&bar, 0, 30 * MINUTE)));
+    assert_eq!(None, irate(&window(&bar, 0, 0)));
+
+    let foo = load(&[1.0, 2.0, 3.0, 0.0, 1.0, 0.0, 0.0, 1.0, 2.0, 0.0]);
+    let bar = load(&[1.0, 2.0, 3.0, 4.0, 5.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+    let biz = load(&[0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
+    let cases = [
+        (5, [0.0, 0.0, 0.0], [0.0, 0.0, 0.0]),
+        (20, [1.0, 0.0, 0.0], [3.0, 3.0, 0.0]),
+        (30, [2.0, 1.0, 0.0], [4.0, 5.0, 1.0]),
+        (50, [3.0, 1.0, 0.0], [8.0, 9.0, 1.0]),
+    ];
+    for (range, want_resets, want_changes) in cases {
+        let mint = at - range * MINUTE;
+        for (i, c) in [&foo, &bar, &biz].into_iter().enumerate() {
+            assert_eq!(Some(want_resets[i]), resets(&window(c, mint, at)), "resets {}m {}", range, i);
+            assert_eq!(Some(want_changes[i]), changes(&window(c, mint, at)), "changes {}m {}", range, i);
+        }
+    }
+    assert_eq!(None, resets(&[]));
+    assert_eq!(Some(0.0), changes(&[(0, f64::NAN), (1, f64::NAN)]));
+}
@@ -0,0 +1,316 @@
+// The lexer splits a query into tokens. Keywords are lexed as identifiers,
+// the parser tells them apart by where they are.
+use super::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    Ident(String),
+    Number(f64),
+    String(String),
+    // a duration like 1h30m in milliseconds
+    Duration(i64),
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Assign, // = in label matchers
+    Eql,
+    Neq,
+    Lss,
+    Gtr,
+    Lte,
+    Gte,
+    EqlRegex,
+    NeqRegex,
+    Eof,
+}
+
+// Item is a token with the byte offset it starts at.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Item {
+    pub(crate) token: Token,
+    pub(crate) pos: usize,
+}
+
+pub(crate) fn lex(input: &str) -> Result<Vec<Item>, Error> {
+    let b = input.as_bytes();
+    let mut items: Vec<Item> = Vec::new();
+    let mut pos = 0;
+    // durations only appear in brackets and behind offset
+    let mut in_brackets = false;
+    while pos < b.len() {
+        let c = b[pos];
+        let start = pos;
+        if c.is_ascii_whitespace() {
+            pos += 1;
+            continue;
+        }
+        if c == b'#' {
+            while pos < b.len() && b[pos] != b'\n' {
+                pos += 1;
+            }
+            continue;
+        }
+        let is_offset = |item: Option<&Item>| matches!(item, Some(Item { token: Token::Ident(id), .. }) if id.eq_ignore_ascii_case("offset"));
+        // offset -5m
+        let after_offset = match items.len() {
+            0 => false,
+            n if items[n - 1].token == Token::Sub => n >= 2 && is_offset(items.get(n - 2)),
+            n => is_offset(items.get(n - 1)),
+        };
+        let token = if c.is_ascii_digit() || (c == b'.' && b.get(pos + 1).is_some_and(u8::is_ascii_digit)) {
+            let end = scan_number(b, pos);
+            let is_duration = end < b.len() && b[end].is_ascii_alphabetic() && !is_hex(&b[pos..end]);
+            if (in_brackets || after_offset) && is_duration {
+                pos = scan_duration(b, pos);
+                Token::Duration(parse_duration(&input[start..pos]).ok_or_else(|| Error::parse(start, "bad duration"))?)
+            } else {
+                pos = end;
+                Token::Number(parse_number(&input[start..pos]).ok_or_else(|| Error::parse(start, "bad number"))?)
+            }
+        } else if c.is_ascii_alphabetic() || c == b'_' || c == b':' {
+            while pos < b.len() && (b[pos].is_ascii_alphanumeric() || b[pos] == b'_' || b[pos] == b':') {
+                pos += 1;
+            }
+            let id = &input[start..pos];
+            if id.eq_ignore_ascii_case("inf") || id.eq_ignore_ascii_case("nan") {
+                Token::Number(if id.eq_ignore_ascii_case("inf") { f64::INFINITY } else { f64::NAN })
+            } else {
+                Token::Ident(id.to_string())
+            }
+        } else if c == b'"' || c == b'\'' || c == b'`' {
+            let (s, end) = scan_string(input, pos)?;
+            pos = end;
+            Token::String(s)
+        } else {
+            let next = b.get(pos + 1).copied();
+            let (token, n) = match (c, next) {
+                (b'=', Some(b'=')) => (Token::Eql, 2),
+                (b'=', Some(b'~')) => (Token::EqlRegex, 2),
+                (b'!', Some(b'=')) => (Token::Neq, 2),
+                (b'!', Some(b'~')) => (Token::NeqRegex, 2),
+                (b'<', Some(b'=')) => (Token::Lte, 2),
+                (b'>', Some(b'=')) => (Token::Gte, 2),
+                (b'=', _) => (Token::Assign, 1),
+                (b'<', _) => (Token::Lss, 1),
+                (b'>', _) => (Token::Gtr, 1),
+                (b'(', _) => (Token::LeftParen, 1),
+                (b')', _) => (Token::RightParen, 1),
+                (b'{', _) => (Token::LeftBrace, 1),
+                (b'}', _) => (Token::RightBrace, 1),
+                (b'[', _) => (Token::LeftBracket, 1),
+                (b']', _) => (Token::RightBracket, 1),
+                (b',', _) => (Token::Comma, 1),
+                (b'+', _) => (Token::Add, 1),
+                (b'-', _) => (Token::Sub, 1),
+                (b'*', _) => (Token::Mul, 1),
+                (b'/', _) => (Token::Div, 1),
+                (b'%', _) => (Token::Mod, 1),
+                (b'^', _) => (Token::Pow, 1),
+                _ => {
+                    let ch = input[pos..].chars().next().unwrap();
+                    return Err(Error::parse(pos, &format!("unexpected character {:?}", ch)));
+                }
+            };
+            match token {
+                Token::LeftBracket => in_brackets = true,
+                Token::RightBracket => in_brackets = false,
+                _ => {}
+            }
+            pos += n;
+            token
+        };
+        items.push(Item { token, pos: start });
+    }
+    items.push(Item { token: Token::Eof, pos: b.len() });
+    Ok(items)
+}
+
+fn is_hex(b: &[u8]) -> bool {
+    b.len() > 2 && b[0] == b'0' && (b[1] == b'x' || b[1] == b'X')
+}
+
+// scan_number returns the end of the number starting at pos.
+fn scan_number(b: &[u8], mut pos: usize) -> usize {
+    if b[pos] == b'0' && matches!(b.get(pos + 1), Some(b'x' | b'X')) && b.get(pos + 2).is_some_and(u8::is_ascii_hexdigit) {
+        pos += 2;
+        while pos < b.len() && b[pos].is_ascii_hexdigit() {
+            pos += 1;
+        }
+        return pos;
+    }
+    while pos < b.len() && (b[pos].is_ascii_digit() || b[pos] == b'.') {
+        pos += 1;
+    }
+    if pos < b.len() && (b[pos] == b'e' || b[pos] == b'E') {
+        let mut end = pos + 1;
+        if end < b.len() && (b[end] == b'+' || b[end] == b'-') {
+            end += 1;
+        }
+        if end < b.len() && b[end].is_ascii_digit() {
+            pos = end;
+            while pos < b.len() && b[pos].is_ascii_digit() {
+                pos += 1;
+            }
+        }
+    }
+    pos
+}
+
+fn parse_number(s: &str) -> Option<f64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok().map(|n| n as f64),
+        None => s.parse().ok(),
+    }
+}
+
+fn scan_duration(b: &[u8], mut pos: usize) -> usize {
+    while pos < b.len() && b[pos].is_ascii_alphanumeric() {
+        pos += 1;
+    }
+    pos
+}
+
+// DURATION_UNITS are the units of durations with their length in
+// milliseconds, from largest to smallest.
+pub(crate) const DURATION_UNITS: [(&str, i64); 7] = [
+    ("y", 365 * 24 * 60 * 60 * 1000),
+    ("w", 7 * 24 * 60 * 60 * 1000),
+    ("d", 24 * 60 * 60 * 1000),
+    ("h", 60 * 60 * 1000),
+    ("m", 60 * 1000),
+    ("s", 1000),
+    ("ms", 1),
+];
+
+// parse_duration parses a duration like 1h30m into milliseconds. The units
+// are ms, s, m, h, d, w and y, each at most once and from largest to smallest.
+pub(crate) fn parse_duration(s: &str) -> Option<i64> {
+    if s.is_empty() {
+        return None;
+    }
+    let mut rest = s;
+    let mut total: i64 = 0;
+    let mut last_unit = None;
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        let n: i64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit_len = rest.bytes().take_while(u8::is_ascii_alphabetic).count();
+        let unit = DURATION_UNITS.iter().position(|(u, _)| *u == &rest[..unit_len])?;
+        if last_unit.is_some_and(|last| unit <= last) {
+            return None;
+        }
+        last_unit = Some(unit);
+        total = total.checked_add(n.checked_mul(DURATION_UNITS[unit].1)?)?;
+        rest = &rest[unit_len..];
+    }
+    Some(total)
+}
+
+// scan_string reads a string starting at its quote and returns it with the
+// offset behind it. Strings in backticks are raw, the others take the escapes of Go.
+fn scan_string(input: &str, start: usize) -> Result<(String, usize), Error> {
+    let quote = input.as_bytes()[start] as char;
+    let mut out = String::new();
+    let mut chars = input[start + 1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == quote {
+            return Ok((out, start + 1 + i + 1));
+        }
+        if c == '\n' && quote != '`' {
+            break;
+        }
+        if c != '\\' || quote == '`' {
+            out.push(c);
+            continue;
+        }
+        let Some((_, e)) = chars.next() else { break };
+        let esc = match e {
+            'a' => '\u{7}',
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'v' => '\u{b}',
+            '\\' => '\\',
+            '"' | '\'' if e == quote => e,
+            'x' | 'u' | 'U' => {
+                let n = match e {
+                    'x' => 2,
+                    'u' => 4,
+                    _ => 8,
+                };
+                let hex: String = (&mut chars).take(n).map(|(_, c)| c).collect();
+                let code = u32::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == n);
+                code.and_then(char::from_u32)
+                    .ok_or_else(|| Error::parse(start + 1 + i, "invalid escape sequence"))?
+            }
+            _ => return Err(Error::parse(start + 1 + i, "invalid escape sequence")),
+        };
+        out.push(esc);
+    }
+    Err(Error::parse(start, "unterminated string"))
+}
+
+#[test]
+fn test_lex() {
+    let tokens = |s: &str| lex(s).unwrap().into_iter().map(|i| i.token).collect::<Vec<_>>();
+    use Token::*;
+    assert_eq!(
+        vec![
+            Ident("sum".into()),
+            LeftParen,
+            Ident("rate".into()),
+            LeftParen,
+            Ident("http_requests:total".into()),
+            LeftBrace,
+            Ident("job".into()),
+            EqlRegex,
+            String("api|web".into()),
+            Comma,
+            Ident("code".into()),
+            Neq,
+            String("500".into()),
+            RightBrace,
+            LeftBracket,
+            Duration(90_000),
+            RightBracket,
+            Ident("offset".into()),
+            Duration(3_600_000),
+            RightParen,
+            RightParen,
+            Gte,
+            Number(1.5e3),
+            Eof,
+        ],
+        tokens(r#"sum(rate(http_requests:total{job=~"api|web", code!="500"}[1m30s] offset 1h)) >= 1.5e3"#)
+    );
+    assert_eq!(vec![Number(255.0), Pow, Number(0.5), Mod, Number(f64::INFINITY), Eof], tokens("0xff ^ .5 % Inf"));
+    assert_eq!(vec![String("a\"b\n\u{e9}".into()), String(r"a\n".into()), String("it's".into()), Eof], tokens(r#""a\"b\né" `a\n` 'it\'s'"#));
+    // a number followed by a unit outside brackets isn't a duration
+    assert!(matches!(tokens("5m")[..], [Number(n), Ident(_), Eof] if n == 5.0));
+
+    assert_eq!(Some(5_400_000), parse_duration("1h30m"));
+    assert_eq!(Some(1), parse_duration("1ms"));
+    assert_eq!(Some(86_400_000 * 365 + 1000), parse_duration("1y1s"));
+    for bad in ["", "1", "5x", "1m1h", "1m1m", "m"] {
+        assert_eq!(None, parse_duration(bad), "{}", bad);
+    }
+    for bad in ["\"abc", "'\\q'", "a @ b", "x[5x]"] {
+        assert!(lex(bad).is_err(), "{}", bad);
+    }
+}
@@ -0,0 +1,52 @@
+// A PromQL engine for a subset of the language: instant and range vector
+// selectors with label matchers and offset, arithmetic and comparison
+// operators with on/ignoring, the aggregations sum, avg, min, max, count
+// and topk with by/without, and the functions rate, irate, increase, delta,
+// resets and changes. Queries run over a Querier, see querier.rs.
+//
+//   let value = Engine::new().instant_query(&head, r#"sum by (job) (http_requests{code="500"})"#, t)?;
+use crate::querier;
+use std::fmt;
+
+pub mod ast;
+pub mod engine;
+pub mod functions;
+mod lexer;
+pub mod parser;
+
+pub use ast::Expr;
+pub use engine::{Engine, Sample, Value};
+pub use parser::parse;
+
+#[derive(Debug)]
+pub enum Error {
+    // the query doesn't parse, pos is the byte offset of the error
+    Parse { pos: usize, msg: String },
+    // the query can't be evaluated
+    Eval(String),
+    Querier(querier::Error),
+}
+
+impl Error {
+    pub(crate) fn parse(pos: usize, msg: &str) -> Error {
+        Error::Parse { pos, msg: msg.to_string() }
+    }
+}
+
+impl From<querier::Error> for Error {
+    fn from(err: querier::Error) -> Error {
+        Error::Querier(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse { pos, msg } => write!(f, "promql: parse error at {}: {}", pos, msg),
+            Error::Eval(msg) => write!(f, "promql: {}", msg),
+            Error::Querier(err) => write!(f, "promql: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,456 @@
+// A recursive descent parser with precedence climbing for the operators.
+// Types are checked while parsing, so the engine only sees valid trees.
+use super::ast::*;
+use super::lexer::{lex, Item, Token};
+use super::Error;
+use crate::index::{MatchType, Matcher};
+use crate::labels::METRIC_NAME;
+
+// parse parses a query.
+pub fn parse(input: &str) -> Result<Expr, Error> {
+    let mut p = Parser { items: lex(input)?, pos: 0 };
+    let e = p.expr(0)?;
+    match p.peek() {
+        Token::Eof => Ok(e),
+        _ => Err(p.unexpected("end of input")),
+    }
+}
+
+struct Parser {
+    items: Vec<Item>,
+    pos: usize,
+}
+
+fn is_keyword(token: &Token, keyword: &str) -> bool {
+    matches!(token, Token::Ident(id) if id.eq_ignore_ascii_case(keyword))
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.items[self.pos].token
+    }
+
+    fn peek_at(&self, n: usize) -> &Token {
+        &self.items[(self.pos + n).min(self.items.len() - 1)].token
+    }
+
+    fn offset(&self) -> usize {
+        self.items[self.pos].pos
+    }
+
+    fn next(&mut self) -> Token {
+        let t = self.items[self.pos].token.clone();
+        if self.pos < self.items.len() - 1 {
+            self.pos += 1;
+        }
+        t
+    }
+
+    fn unexpected(&self, want: &str) -> Error {
+        let got = match self.peek() {
+            Token::Eof => "end of input".to_string(),
+            t => format!("{:?}", t),
+        };
+        Error::parse(self.offset(), &format!("unexpected {}, expected {}", got, want))
+    }
+
+    fn expect(&mut self, token: Token, want: &str) -> Result<(), Error> {
+        if *self.peek() != token {
+            return Err(self.unexpected(want));
+        }
+        self.next();
+        Ok(())
+    }
+
+    fn binop(&self) -> Option<BinOp> {
+        Some(match self.peek() {
+            Token::Add => BinOp::Add,
+            Token::Sub => BinOp::Sub,
+            Token::Mul => BinOp::Mul,
+            Token::Div => BinOp::Div,
+            Token::Mod => BinOp::Mod,
+            Token::Pow => BinOp::Pow,
+            Token::Eql => BinOp::Eql,
+            Token::Neq => BinOp::Neq,
+            Token::Gtr => BinOp::Gtr,
+            Token::Lss => BinOp::Lss,
+            Token::Gte => BinOp::Gte,
+            Token::Lte => BinOp::Lte,
+            _ => return None,
+        })
+    }
+
+    // expr parses operators that bind at least as tightly as min_prec.
+    fn expr(&mut self, min_prec: u8) -> Result<Expr, Error> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.binop().filter(|op| op.precedence() >= min_prec) {
+            let pos = self.offset();
+            self.next();
+            let mut return_bool = false;
+            if is_keyword(self.peek(), "bool") {
+                if !op.is_comparison() {
+                    return Err(Error::parse(self.offset(), "bool modifier can only be used on comparison operators"));
+                }
+                self.next();
+                return_bool = true;
+            }
+            let mut matching = None;
+            if is_keyword(self.peek(), "on") || is_keyword(self.peek(), "ignoring") {
+                let on = is_keyword(&self.next(), "on");
+                matching = Some(VectorMatching { on, labels: self.label_list()? });
+            }
+            // ^ is right associative
+            let next_prec = if op == BinOp::Pow { op.precedence() } else { op.precedence() + 1 };
+            let rhs = self.expr(next_prec)?;
+            lhs = check_binary(pos, BinaryExpr { op, lhs: Box::new(lhs), rhs: Box::new(rhs), matching, return_bool })?;
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        match self.peek() {
+            Token::Sub | Token::Add => {
+                let neg = *self.peek() == Token::Sub;
+                let pos = self.offset();
+                self.next();
+                // -a^b is -(a^b)
+                let e = self.expr(BinOp::Pow.precedence())?;
+                check_operand(pos, &e, "unary expression")?;
+                Ok(match e {
+                    Expr::Number(v) if neg => Expr::Number(-v),
+                    e if neg => Expr::Neg(Box::new(e)),
+                    e => e,
+                })
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, Error> {
+        let pos = self.offset();
+        match self.peek().clone() {
+            Token::Number(v) => {
+                self.next();
+                Ok(Expr::Number(v))
+            }
+            Token::LeftParen => {
+                self.next();
+                let e = self.expr(0)?;
+                self.expect(Token::RightParen, "\")\"")?;
+                if *self.peek() == Token::LeftBracket {
+                    return Err(Error::parse(self.offset(), "subqueries are not supported"));
+                }
+                Ok(Expr::Paren(Box::new(e)))
+            }
+            Token::Ident(id) => {
+                let next = self.peek_at(1);
+                let is_agg = *next == Token::LeftParen || is_keyword(next, "by") || is_keyword(next, "without");
+                match AggOp::from_name(&id) {
+                    Some(op) if is_agg => {
+                        self.next();
+                        self.aggregate(pos, op)
+                    }
+                    _ if *next == Token::LeftParen => match Function::from_name(&id) {
+                        Some(func) => {
+                            self.next();
+                            self.call(pos, func)
+                        }
+                        None => Err(Error::parse(pos, &format!("unknown function {:?}", id))),
+                    },
+                    _ => {
+                        self.next();
+                        self.selector(pos, Some(id))
+                    }
+                }
+            }
+            Token::LeftBrace => self.selector(pos, None),
+            Token::String(_) => Err(Error::parse(pos, "string literals are not supported")),
+            _ => Err(self.unexpected("an expression")),
+        }
+    }
+
+    // label_list parses (name, ...).
+    fn label_list(&mut self) -> Result<Vec<String>, Error> {
+        self.expect(Token::LeftParen, "\"(\"")?;
+        let mut labels = Vec::new();
+        loop {
+            match self.next() {
+                Token::RightParen => return Ok(labels),
+                Token::Ident(id) => labels.push(id),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.unexpected("a label name"));
+                }
+            }
+            match self.next() {
+                Token::Comma => {}
+                Token::RightParen => return Ok(labels),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.unexpected("\",\" or \")\""));
+                }
+            }
+        }
+    }
+
+    fn grouping(&mut self, agg: &mut AggregateExpr) -> Result<(), Error> {
+        if is_keyword(self.peek(), "by") || is_keyword(self.peek(), "without") {
+            agg.without = is_keyword(&self.next(), "without");
+            agg.grouping = self.label_list()?;
+        }
+        Ok(())
+    }
+
+    fn aggregate(&mut self, pos: usize, op: AggOp) -> Result<Expr, Error> {
+        let mut agg = AggregateExpr {
+            op,
+            expr: Box::new(Expr::Number(0.0)),
+            param: None,
+            grouping: Vec::new(),
+            without: false,
+        };
+        // the grouping goes before or after the arguments
+        self.grouping(&mut agg)?;
+        self.expect(Token::LeftParen, "\"(\"")?;
+        let first = self.expr(0)?;
+        if op.has_param() {
+            self.expect(Token::Comma, "\",\"")?;
+            agg.param = Some(Box::new(first));
+            agg.expr = Box::new(self.expr(0)?);
+        } else {
+            agg.expr = Box::new(first);
+        }
+        self.expect(Token::RightParen, "\")\"")?;
+        if agg.grouping.is_empty() && !agg.without {
+            self.grouping(&mut agg)?;
+        }
+
+        if agg.expr.value_type() != ValueType::Vector {
+            let msg = format!("expected type instant vector in aggregation, got {}", agg.expr.value_type());
+            return Err(Error::parse(pos, &msg));
+        }
+        if let Some(p) = &agg.param {
+            if p.value_type() != ValueType::Scalar {
+                let msg = format!("expected type scalar in aggregation parameter, got {}", p.value_type());
+                return Err(Error::parse(pos, &msg));
+            }
+        }
+        Ok(Expr::Aggregate(agg))
+    }
+
+    fn call(&mut self, pos: usize, func: Function) -> Result<Expr, Error> {
+        self.expect(Token::LeftParen, "\"(\"")?;
+        let mut args = Vec::new();
+        if *self.peek() != Token::RightParen {
+            loop {
+                args.push(self.expr(0)?);
+                if *self.peek() != Token::Comma {
+                    break;
+                }
+                self.next();
+            }
+        }
+        self.expect(Token::RightParen, "\")\"")?;
+
+        let types = func.arg_types();
+        if args.len() != types.len() {
+            let msg = format!("expected {} argument(s) in call to {:?}, got {}", types.len(), func.to_string(), args.len());
+            return Err(Error::parse(pos, &msg));
+        }
+        for (arg, &want) in args.iter().zip(types) {
+            if arg.value_type() != want {
+                let msg = format!("expected type {} in call to function {:?}, got {}", want, func.to_string(), arg.value_type());
+                return Err(Error::parse(pos, &msg));
+            }
+        }
+        Ok(Expr::Call(Call { func, args }))
+    }
+
+    fn selector(&mut self, pos: usize, name: Option<String>) -> Result<Expr, Error> {
+        let mut matchers = Vec::new();
+        if let Some(name) = &name {
+            matchers.push(Matcher::new(MatchType::Equal, METRIC_NAME, name).unwrap());
+        }
+        if *self.peek() == Token::LeftBrace {
+            self.next();
+            loop {
+                if *self.peek() == Token::RightBrace {
+                    self.next();
+                    break;
+                }
+                let m = self.matcher()?;
+                if m.name == METRIC_NAME && name.is_some() {
+                    return Err(Error::parse(pos, "metric name must not be set twice"));
+                }
+                matchers.push(m);
+                match self.next() {
+                    Token::Comma => {}
+                    Token::RightBrace => break,
+                    _ => {
+                        self.pos -= 1;
+                        return Err(self.unexpected("\",\" or \"}\""));
+                    }
+                }
+            }
+        }
+        if matchers.iter().all(|m| m.matches("")) {
+            return Err(Error::parse(pos, "vector selector must contain at least one non-empty matcher"));
+        }
+
+        let mut selector = VectorSelector { matchers, offset: 0 };
+        let mut range = None;
+        if *self.peek() == Token::LeftBracket {
+            self.next();
+            match self.next() {
+                Token::Duration(d) if d > 0 => range = Some(d),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.unexpected("a positive duration"));
+                }
+            }
+            self.expect(Token::RightBracket, "\"]\"")?;
+        }
+        if is_keyword(self.peek(), "offset") {
+            self.next();
+            let neg = *self.peek() == Token::Sub;
+            if neg {
+                self.next();
+            }
+            match self.next() {
+                Token::Duration(d) => selector.offset = if neg { -d } else { d },
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.unexpected("a duration"));
+                }
+            }
+        }
+        Ok(match range {
+            Some(range) => Expr::Matrix(MatrixSelector { selector, range }),
+            None => Expr::Vector(selector),
+        })
+    }
+
+    fn matcher(&mut self) -> Result<Matcher, Error> {
+        let Token::Ident(name) = self.next() else {
+            self.pos -= 1;
+            return Err(self.unexpected("a label name"));
+        };
+        let kind = match self.next() {
+            Token::Assign => MatchType::Equal,
+            Token::Neq => MatchType::NotEqual,
+            Token::EqlRegex => MatchType::Regex,
+            Token::NeqRegex => MatchType::NotRegex,
+            _ => {
+                self.pos -= 1;
+                return Err(self.unexpected("a label matching operator"));
+            }
+        };
+        let pos = self.offset();
+        let Token::String(value) = self.next() else {
+            self.pos -= 1;
+            return Err(self.unexpected("a string"));
+        };
+        Matcher::new(kind, &name, &value).map_err(|err| Error::parse(pos, &err.to_string()))
+    }
+}
+
+fn check_operand(pos: usize, e: &Expr, what: &str) -> Result<(), Error> {
+    match e.value_type() {
+        ValueType::Scalar | ValueType::Vector => Ok(()),
+        t => Err(Error::parse(pos, &format!("{} only allowed on scalars and instant vectors, got {}", what, t))),
+    }
+}
+
+fn check_binary(pos: usize, b: BinaryExpr) -> Result<Expr, Error> {
+    check_operand(pos, &b.lhs, "binary expression")?;
+    check_operand(pos, &b.rhs, "binary expression")?;
+    let scalars = b.lhs.value_type() == ValueType::Scalar && b.rhs.value_type() == ValueType::Scalar;
+    if scalars && b.op.is_comparison() && !b.return_bool {
+        return Err(Error::parse(pos, "comparisons between scalars must use bool modifier"));
+    }
+    let vectors = b.lhs.value_type() == ValueType::Vector && b.rhs.value_type() == ValueType::Vector;
+    if b.matching.is_some() && !vectors {
+        return Err(Error::parse(pos, "vector matching only allowed between instant vectors"));
+    }
+    Ok(Expr::Binary(b))
+}
+
+#[test]
+fn test_parse() {
+    let cases = [
+        ("foo", "foo"),
+        ("foo{}", "foo"),
+        (r#"{__name__="foo",job!="a",}"#, r#"foo{job!="a"}"#),
+        (r#"foo{job=~"a.*"}[5m] offset 1h"#, r#"foo{job=~"a.*"}[5m] offset 1h"#),
+        ("foo offset -90s", "foo offset -1m30s"),
+        ("1 + 2 * 3", "1 + 2 * 3"),
+        ("-2 ^ 2", "-2 ^ 2"),
+        ("2 ^ 3 ^ 2", "2 ^ 3 ^ 2"),
+        ("-foo", "-foo"),
+        ("(1 + 2) * 3", "(1 + 2) * 3"),
+        ("a > bool on(job, instance) b", "a > bool on(job, instance) b"),
+        ("a / ignoring(code) b", "a / ignoring(code) b"),
+        ("sum(foo) by (job)", "sum by (job) (foo)"),
+        ("SUM without(a) (foo)", "sum without (a) (foo)"),
+        ("topk by (job) (3, foo)", "topk by (job) (3, foo)"),
+        ("count(foo) + 0x10", "count(foo) + 16"),
+        ("1 == bool Inf", "1 == bool +Inf"),
+        ("sum(rate(foo[5m] offset 1m)) / 2", "sum(rate(foo[5m] offset 1m)) / 2"),
+        ("changes((foo[1h]))", "changes((foo[1h]))"),
+        // functions are only called with parens
+        ("rate", "rate"),
+        // keywords are fine as metric and label names
+        ("sum + count", "sum + count"),
+        ("offset{by=\"x\"}", "offset{by=\"x\"}"),
+    ];
+    for (input, want) in cases {
+        let e = parse(input).unwrap_or_else(|err| panic!("{}: {}", input, err));
+        assert_eq!(want, e.to_string(), "{}", input);
+        assert_eq!(e, parse(&e.to_string()).unwrap(), "{}", input);
+    }
+
+    let e = parse("1 + 2 * 3 > bool 4").unwrap();
+    let Expr::Binary(b) = &e else { panic!() };
+    assert_eq!(BinOp::Gtr, b.op);
+    assert!(matches!(&*b.lhs, Expr::Binary(l) if l.op == BinOp::Add));
+    let Expr::Binary(b) = parse("2 ^ 3 ^ 2").unwrap() else { panic!() };
+    assert!(matches!(*b.rhs, Expr::Binary(_)));
+    assert_eq!(ValueType::Matrix, parse("foo[1m]").unwrap().value_type());
+    assert_eq!(ValueType::Scalar, parse("-(1 + 2)").unwrap().value_type());
+
+    let errors = [
+        "",
+        "foo{",
+        r#"foo{a="b""#,
+        r#"{a=""}"#,
+        r#"{a=~".*"}"#,
+        r#"foo{__name__="bar"}"#,
+        "foo[5]",
+        "foo[0s]",
+        "foo[5m][5m]",
+        "(foo)[5m]",
+        "foo offset 5",
+        "1 > 2",
+        "1 + on(a) foo",
+        "foo + bool bar",
+        "foo[5m] + 1",
+        "-foo[5m]",
+        "sum(foo[5m])",
+        "topk(foo, bar)",
+        "topk(3)",
+        "sum by (a",
+        "rate(foo)",
+        "rate(foo[5m], 1)",
+        "irate()",
+        "Rate(foo[5m])",
+        "unknown(foo[5m])",
+        r#"foo{a=~"("}"#,
+        r#""str""#,
+        "foo bar",
+    ];
+    for input in errors {
+        assert!(parse(input).is_err(), "{}", input);
+    }
+    let Err(Error::Parse { pos, msg }) = parse("foo + ") else { panic!() };
+    assert_eq!((6, "unexpected end of input, expected an expression"), (pos, msg.as_str()));
+}
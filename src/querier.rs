@@ -0,0 +1,182 @@
+// A Querier selects series by label matchers and loads their samples in a
+// time range. It is what query engines run on: the head and blocks are
+// queriers, and MergeQuerier combines several of them into one.
+use crate::block::{self, BlockReader};
+use crate::head::{self, Head};
+use crate::index::{IndexReader, Matcher};
+use crate::labels::Labels;
+use crate::merge::{DuplicatePolicy, MergeIterator};
+use crate::xor::{self, SampleIterator};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Chunk(xor::Error),
+    Head(head::Error),
+    Block(block::Error),
+}
+
+impl From<xor::Error> for Error {
+    fn from(err: xor::Error) -> Error {
+        Error::Chunk(err)
+    }
+}
+
+impl From<head::Error> for Error {
+    fn from(err: head::Error) -> Error {
+        Error::Head(err)
+    }
+}
+
+impl From<block::Error> for Error {
+    fn from(err: block::Error) -> Error {
+        Error::Block(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Chunk(err) => write!(f, "querier: {}", err),
+            Error::Head(err) => write!(f, "querier: {}", err),
+            Error::Block(err) => write!(f, "querier: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Series is a series with its samples, sorted by time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub labels: Labels,
+    pub samples: Vec<(i64, f64)>,
+}
+
+pub trait Querier {
+    // select returns the series the matchers select that have samples with
+    // mint <= t <= maxt, with those samples, sorted by labels.
+    fn select(&self, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<Vec<Series>, Error>;
+}
+
+// collect reads the samples of it in [mint, maxt], the iterator may start before mint.
+fn collect<I: SampleIterator>(mut it: I, mint: i64, maxt: i64) -> Result<Vec<(i64, f64)>, Error> {
+    let samples = (&mut it).skip_while(|s| s.0 < mint).take_while(|s| s.0 <= maxt).collect();
+    match it.err() {
+        Some(err) => Err(err.clone().into()),
+        None => Ok(samples),
+    }
+}
+
+impl Querier for Head {
+    fn select(&self, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<Vec<Series>, Error> {
+        let mut out = Vec::new();
+        for sref in IndexReader::select(self, matchers) {
+            let Some(s) = self.series(sref) else { continue };
+            if s.max_time() < mint || s.min_time() > maxt {
+                continue;
+            }
+            let snap = s.snapshot()?;
+            let samples = collect(snap.iter_range(mint, maxt), mint, maxt)?;
+            if !samples.is_empty() {
+                out.push(Series { labels: s.labels().clone(), samples });
+            }
+        }
+        out.sort_by(|a, b| a.labels.cmp(&b.labels));
+        Ok(out)
+    }
+}
+
+impl Querier for BlockReader {
+    fn select(&self, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<Vec<Series>, Error> {
+        let mut out = Vec::new();
+        if self.meta().max_time <= mint || self.meta().min_time > maxt {
+            return Ok(out);
+        }
+        // refs are sorted by labels
+        for sref in IndexReader::select(self, matchers) {
+            let chunks = self.series_chunks(sref, mint, maxt)?;
+            let samples = collect(chunks.iter(), mint, maxt)?;
+            if !samples.is_empty() {
+                out.push(Series { labels: self.series(sref).unwrap().0.clone(), samples });
+            }
+        }
+        Ok(out)
+    }
+}
+
+// MergeQuerier merges the series of several queriers, like blocks and the
+// head. Of samples with the same timestamp the one of the later querier wins.
+pub struct MergeQuerier<'a> {
+    queriers: Vec<&'a dyn Querier>,
+}
+
+impl<'a> MergeQuerier<'a> {
+    pub fn new(queriers: Vec<&'a dyn Querier>) -> MergeQuerier<'a> {
+        MergeQuerier { queriers }
+    }
+}
+
+struct SliceIterator<'a>(std::slice::Iter<'a, (i64, f64)>);
+
+impl Iterator for SliceIterator<'_> {
+    type Item = (i64, f64);
+
+    fn next(&mut self) -> Option<(i64, f64)> {
+        self.0.next().copied()
+    }
+}
+
+impl SampleIterator for SliceIterator<'_> {
+    fn err(&self) -> Option<&xor::Error> {
+        None
+    }
+}
+
+impl Querier for MergeQuerier<'_> {
+    fn select(&self, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<Vec<Series>, Error> {
+        let mut all: BTreeMap<Labels, Vec<Vec<(i64, f64)>>> = BTreeMap::new();
+        for q in &self.queriers {
+            for s in q.select(matchers, mint, maxt)? {
+                all.entry(s.labels).or_default().push(s.samples);
+            }
+        }
+        let mut out = Vec::with_capacity(all.len());
+        for (labels, mut lists) in all {
+            let samples = if lists.len() == 1 {
+                lists.pop().unwrap()
+            } else {
+                let iters = lists.iter().map(|l| SliceIterator(l.iter())).collect();
+                MergeIterator::new(iters, DuplicatePolicy::LastWins).collect()
+            };
+            out.push(Series { labels, samples });
+        }
+        Ok(out)
+    }
+}
+
+#[test]
+fn test_merge_querier() {
+    use crate::index::MatchType;
+
+    let old = Head::new();
+    let new = Head::new();
+    let a = Labels::from_pairs(&[("__name__", "m"), ("i", "a")]);
+    let b = Labels::from_pairs(&[("__name__", "m"), ("i", "b")]);
+    for t in 0..10 {
+        old.append(&a, t * 10, 1.0).unwrap();
+        new.append(&a, 50 + t * 10, 2.0).unwrap();
+    }
+    new.append(&b, 0, 3.0).unwrap();
+
+    let q = MergeQuerier::new(vec![&old, &new]);
+    let m = [Matcher::new(MatchType::Equal, "__name__", "m").unwrap()];
+    let got = q.select(&m, 30, 70).unwrap();
+    assert_eq!(1, got.len());
+    assert_eq!(a, got[0].labels);
+    assert_eq!(vec![(30, 1.0), (40, 1.0), (50, 2.0), (60, 2.0), (70, 2.0)], got[0].samples);
+    let got = q.select(&m, i64::MIN, i64::MAX).unwrap();
+    assert_eq!(vec![a, b], got.iter().map(|s| s.labels.clone()).collect::<Vec<_>>());
+    assert_eq!(15, got[0].samples.len());
+}
@@ -0,0 +1,521 @@
+// Chunks are persisted in segment files laid out like the chunks directory of
+// a Prometheus block, so either side can read what the other wrote. Segments
+// are numbered from 000001 up and start with an 8 byte header:
+//
+//   [magic u32 0x85BD40DD][version u8 = 1][padding 3 bytes]
+//
+// followed by the chunks, each stored as
+//
+//   [len uvarint][encoding u8][data, len bytes][crc32c of encoding and data u32]
+//
+// A chunk is addressed by a ChunkRef, which packs the segment number and the
+// offset of the chunk in that segment.
+//
+// Prometheus doesn't know the footer of sealed chunks, so they are written
+// without it by default and read back as plain chunks, which have to be
+// decoded for their meta and can't seek. A writer with footers stores sealed
+// chunks as they are under their own encoding instead, which Prometheus
+// can't read.
+use crate::bstream::{Bstream, BstreamReader};
+use crate::crc32;
+use crate::xor::{self, ChunkView, XORChunk};
+#[cfg(test)]
+use crate::test_dir;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const MAGIC: u32 = 0x85BD40DD;
+pub const FORMAT_V1: u8 = 1;
+pub const SEGMENT_HEADER_LEN: u64 = 8;
+pub const DEFAULT_SEGMENT_SIZE: u64 = 512 * 1024 * 1024;
+
+// ENCODING_XOR is the Prometheus encoding byte of XOR chunks.
+pub const ENCODING_XOR: u8 = 1;
+// ENCODING_XOR_SEALED marks sealed XOR chunks stored with their footer.
+pub const ENCODING_XOR_SEALED: u8 = 0x41;
+
+const MAX_UVARINT_LEN: usize = 10;
+const CRC_LEN: usize = 4;
+
+// ChunkRef is the segment number in the upper 32 bits and the offset of the
+// chunk in the segment in the lower 32 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChunkRef(pub u64);
+
+impl ChunkRef {
+    pub fn new(segment: u32, offset: u32) -> ChunkRef {
+        ChunkRef((segment as u64) << 32 | offset as u64)
+    }
+
+    pub fn segment(&self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.0 as u32
+    }
+}
+
+impl fmt::Display for ChunkRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:06}:{}", self.segment(), self.offset())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Chunk(xor::Error),
+    // the segment doesn't start with a valid header
+    InvalidSegment(u32),
+    InvalidRef(ChunkRef),
+    UnknownEncoding(ChunkRef, u8),
+    ChecksumMismatch(ChunkRef),
+    // the chunk is too large to be addressed by a ChunkRef
+    ChunkTooLarge(usize),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<xor::Error> for Error {
+    fn from(err: xor::Error) -> Error {
+        Error::Chunk(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "segment: {}", err),
+            Error::Chunk(err) => write!(f, "segment: {}", err),
+            Error::InvalidSegment(seq) => write!(f, "segment: invalid header in segment {:06}", seq),
+            Error::InvalidRef(r) => write!(f, "segment: invalid chunk ref {}", r),
+            Error::UnknownEncoding(r, e) => write!(f, "segment: unknown encoding {} of chunk {}", e, r),
+            Error::ChecksumMismatch(r) => write!(f, "segment: checksum mismatch of chunk {}", r),
+            Error::ChunkTooLarge(n) => write!(f, "segment: chunk of {} bytes doesn't fit in a segment", n),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// segment_path returns the path of segment seq in dir.
+pub fn segment_path(dir: &Path, seq: u32) -> PathBuf {
+    dir.join(format!("{:06}", seq))
+}
+
+// segment_files returns the numbers and paths of all segments in dir, sorted.
+pub fn segment_files(dir: &Path) -> io::Result<Vec<(u32, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else { continue };
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        if let Ok(seq) = name.parse::<u32>() {
+            segments.push((seq, entry.path()));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+// chunk_data returns the encoding and the bytes of a chunk that are written
+// to a segment. Unless footers are kept, that's the header and the samples:
+// the footer of a sealed chunk and the sealed bit in its header are left out.
+fn chunk_data(chunk: &XORChunk, footers: bool) -> (u8, Cow<'_, [u8]>) {
+    if footers && chunk.is_sealed() {
+        return (ENCODING_XOR_SEALED, Cow::Borrowed(chunk.bytes()));
+    }
+    // leave out the unused byte the bit stream may have at its end
+    let data = &chunk.bytes()[..chunk.bit_len().div_ceil(8)];
+    if !chunk.is_sealed() {
+        return (ENCODING_XOR, Cow::Borrowed(data));
+    }
+    let mut data = data.to_vec();
+    data[..2].copy_from_slice(&chunk.num_samples().to_be_bytes());
+    (ENCODING_XOR, Cow::Owned(data))
+}
+
+// SegmentWriter writes chunks to the segments of a directory. It never
+// appends to a segment that already exists but starts a new one behind it.
+pub struct SegmentWriter {
+    dir: PathBuf,
+    seq: u32,
+    f: Option<BufWriter<File>>,
+    offset: u64, // size of the current segment
+    segment_size: u64,
+    footers: bool,
+}
+
+impl SegmentWriter {
+    pub fn new(dir: impl AsRef<Path>) -> Result<SegmentWriter, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let seq = segment_files(&dir)?.last().map_or(0, |(seq, _)| *seq);
+        Ok(SegmentWriter {
+            dir,
+            seq,
+            f: None,
+            offset: 0,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            footers: false,
+        })
+    }
+
+    // with_segment_size sets the size after which a new segment is started.
+    // A chunk is never split, so a segment can be larger when its first chunk is.
+    pub fn with_segment_size(mut self, segment_size: u64) -> SegmentWriter {
+        self.segment_size = segment_size.clamp(SEGMENT_HEADER_LEN + 1, u32::MAX as u64);
+        self
+    }
+
+    // with_footers keeps the footers of sealed chunks, see the top of the file.
+    pub fn with_footers(mut self, footers: bool) -> SegmentWriter {
+        self.footers = footers;
+        self
+    }
+
+    // write_chunk appends the chunk to the current segment and returns its ref.
+    pub fn write_chunk(&mut self, chunk: &XORChunk) -> Result<ChunkRef, Error> {
+        let (enc, data) = chunk_data(chunk, self.footers);
+        let mut b = Bstream::new(Vec::with_capacity(data.len() + MAX_UVARINT_LEN + 1 + CRC_LEN));
+        b.write_uvarint(data.len() as u64);
+        b.write_aligned_bytes(&[enc]);
+        b.write_aligned_bytes(&data);
+        let crc = crc32::update(crc32::checksum(&[enc]), &data);
+        b.write_aligned_bytes(&crc.to_be_bytes());
+        let record = b.read_bytes();
+
+        if SEGMENT_HEADER_LEN + record.len() as u64 > u32::MAX as u64 {
+            return Err(Error::ChunkTooLarge(data.len()));
+        }
+        if self.f.is_none() || (self.offset > SEGMENT_HEADER_LEN && self.offset + record.len() as u64 > self.segment_size) {
+            self.cut()?;
+        }
+        let r = ChunkRef::new(self.seq, self.offset as u32);
+        self.f.as_mut().unwrap().write_all(record)?;
+        self.offset += record.len() as u64;
+        Ok(r)
+    }
+
+    // cut finishes the current segment and starts the next one.
+    fn cut(&mut self) -> Result<(), Error> {
+        self.close_segment()?;
+        let seq = self.seq + 1;
+        let mut f = BufWriter::new(File::create(segment_path(&self.dir, seq))?);
+        f.write_all(&MAGIC.to_be_bytes())?;
+        f.write_all(&[FORMAT_V1, 0, 0, 0])?;
+        self.seq = seq;
+        self.f = Some(f);
+        self.offset = SEGMENT_HEADER_LEN;
+        Ok(())
+    }
+
+    fn close_segment(&mut self) -> Result<(), Error> {
+        if let Some(mut f) = self.f.take() {
+            f.flush()?;
+            f.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+
+    // flush writes buffered chunks to the current segment, which makes them
+    // visible to readers.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if let Some(f) = &mut self.f {
+            f.flush()?;
+        }
+        Ok(())
+    }
+
+    // sync flushes and fsyncs the current segment.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        if let Some(f) = &mut self.f {
+            f.flush()?;
+            f.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+
+    // close syncs the current segment and closes it.
+    pub fn close(mut self) -> Result<(), Error> {
+        self.close_segment()
+    }
+}
+
+// Segment is an open segment file. Mapped segments hand out chunks as slices
+// of the mapping, buffered ones read every chunk into a Vec.
+enum Segment {
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+    Buffered { f: Mutex<BufReader<File>>, len: u64 },
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        match self {
+            #[cfg(feature = "mmap")]
+            Segment::Mapped(m) => m.len() as u64,
+            Segment::Buffered { len, .. } => *len,
+        }
+    }
+}
+
+// SegmentReader reads chunks from the segments of a directory. Only the
+// chunks that were in a segment when it was opened can be read.
+pub struct SegmentReader {
+    segments: BTreeMap<u32, Segment>,
+}
+
+impl SegmentReader {
+    // open opens all segments in dir and checks their headers. With the mmap
+    // feature segments are mapped, if that fails they are read like open_buffered does.
+    pub fn open(dir: impl AsRef<Path>) -> Result<SegmentReader, Error> {
+        SegmentReader::open_with(dir.as_ref(), cfg!(feature = "mmap"))
+    }
+
+    // open_buffered opens all segments in dir without mapping them.
+    pub fn open_buffered(dir: impl AsRef<Path>) -> Result<SegmentReader, Error> {
+        SegmentReader::open_with(dir.as_ref(), false)
+    }
+
+    #[cfg_attr(not(feature = "mmap"), allow(unused_variables))]
+    fn open_with(dir: &Path, mmap: bool) -> Result<SegmentReader, Error> {
+        let mut segments = BTreeMap::new();
+        for (seq, path) in segment_files(dir)? {
+            let mut f = File::open(path)?;
+            let len = f.metadata()?.len();
+            let mut header = [0u8; SEGMENT_HEADER_LEN as usize];
+            if len < SEGMENT_HEADER_LEN {
+                return Err(Error::InvalidSegment(seq));
+            }
+            f.read_exact(&mut header)?;
+            if u32::from_be_bytes(header[..4].try_into().unwrap()) != MAGIC || header[4] != FORMAT_V1 {
+                return Err(Error::InvalidSegment(seq));
+            }
+            #[cfg(feature = "mmap")]
+            if mmap {
+                // Safety: segments are only ever appended to, and only the
+                // bytes that existed when the file was mapped are read.
+                // Truncating a segment while it is mapped is not supported.
+                if let Ok(m) = unsafe { memmap2::Mmap::map(&f) } {
+                    segments.insert(seq, Segment::Mapped(m));
+                    continue;
+                }
+            }
+            segments.insert(seq, Segment::Buffered { f: Mutex::new(BufReader::new(f)), len });
+        }
+        Ok(SegmentReader { segments })
+    }
+
+    // segments returns the numbers of all segments.
+    pub fn segments(&self) -> impl Iterator<Item = u32> + '_ {
+        self.segments.keys().copied()
+    }
+
+    // is_mapped returns whether all segments are mapped.
+    pub fn is_mapped(&self) -> bool {
+        #[cfg(feature = "mmap")]
+        return self.segments.values().all(|s| matches!(s, Segment::Mapped(_)));
+        #[cfg(not(feature = "mmap"))]
+        false
+    }
+
+    // chunk returns a view of the chunk at r after checking its checksum,
+    // which borrows the bytes of a mapped segment and decodes nothing yet.
+    pub fn chunk(&self, r: ChunkRef) -> Result<ChunkView<'_>, Error> {
+        Ok(ChunkView::new(self.chunk_bytes(r)?)?)
+    }
+
+    // chunk_bytes returns the data of the chunk at r after checking its
+    // checksum. Data of a mapped segment is borrowed from the mapping, so it
+    // can be given to XORIterator::new without copying it:
+    //
+    //   let b = reader.chunk_bytes(r)?;
+    //   for (t, v) in XORIterator::new(&b) { ... }
+    pub fn chunk_bytes(&self, r: ChunkRef) -> Result<Cow<'_, [u8]>, Error> {
+        let segment = self.segments.get(&r.segment()).ok_or(Error::InvalidRef(r))?;
+        let offset = r.offset() as u64;
+        if offset < SEGMENT_HEADER_LEN || offset >= segment.len() {
+            return Err(Error::InvalidRef(r));
+        }
+        match segment {
+            #[cfg(feature = "mmap")]
+            Segment::Mapped(m) => {
+                let offset = offset as usize;
+                let head = &m[offset..m.len().min(offset + MAX_UVARINT_LEN)];
+                let (start, len) = record_bounds(r, head, m.len() as u64)?;
+                let record = &m[start as usize..(start + 1 + len) as usize + CRC_LEN];
+                check_record(r, record)?;
+                Ok(Cow::Borrowed(&record[1..record.len() - CRC_LEN]))
+            }
+            Segment::Buffered { f, len: segment_len } => {
+                let mut f = f.lock().unwrap();
+                f.seek(SeekFrom::Start(offset))?;
+                let mut head = Vec::with_capacity(MAX_UVARINT_LEN);
+                (&mut *f).take(MAX_UVARINT_LEN as u64).read_to_end(&mut head)?;
+                let (start, len) = record_bounds(r, &head, *segment_len)?;
+                let mut record = vec![0u8; 1 + len as usize + CRC_LEN];
+                f.seek(SeekFrom::Start(start))?;
+                f.read_exact(&mut record)?;
+                drop(f);
+                check_record(r, &record)?;
+                record.truncate(1 + len as usize);
+                record.remove(0);
+                Ok(Cow::Owned(record))
+            }
+        }
+    }
+}
+
+// record_bounds reads the data length from head, the bytes at the offset of
+// chunk r, and returns the offset of its encoding byte and the data length.
+fn record_bounds(r: ChunkRef, head: &[u8], segment_len: u64) -> Result<(u64, u64), Error> {
+    let mut br = BstreamReader::new(head);
+    let len = br.read_uvarint().map_err(|_| Error::InvalidRef(r))?;
+    let start = r.offset() as u64 + br.position() as u64 / 8;
+    // len comes from disk, a corrupt one must not overflow
+    let end = len.checked_add(start + 1 + CRC_LEN as u64).ok_or(Error::InvalidRef(r))?;
+    if end > segment_len {
+        return Err(Error::InvalidRef(r));
+    }
+    Ok((start, len))
+}
+
+// check_record checks the checksum and encoding of [encoding][data][crc].
+fn check_record(r: ChunkRef, record: &[u8]) -> Result<(), Error> {
+    let (body, crc) = record.split_at(record.len() - CRC_LEN);
+    if crc32::checksum(body) != u32::from_be_bytes(crc.try_into().unwrap()) {
+        return Err(Error::ChecksumMismatch(r));
+    }
+    if body[0] != ENCODING_XOR && body[0] != ENCODING_XOR_SEALED {
+        return Err(Error::UnknownEncoding(r, body[0]));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_segment_write_read() {
+    use crate::xor::{SealOptions, XORIterator};
+
+    let dir = test_dir("segment");
+    let mut chunks = Vec::new();
+    for i in 0..20 {
+        let mut c = XORChunk::new();
+        for j in 0..(i * 7 + 1) {
+            c.append(i * 100_000 + j * 1000 + j % 3, (j * i) as f64 / 3.0).unwrap();
+        }
+        if i % 2 == 0 {
+            c.seal(SealOptions { index_interval: Some(4), aggregates: true }).unwrap();
+        }
+        chunks.push(c);
+    }
+
+    let mut w = SegmentWriter::new(&dir).unwrap().with_segment_size(1024);
+    let refs: Vec<_> = chunks.iter().map(|c| w.write_chunk(c).unwrap()).collect();
+    w.close().unwrap();
+    assert!(refs.last().unwrap().segment() > 1);
+    assert_eq!(ChunkRef::new(1, SEGMENT_HEADER_LEN as u32), refs[0]);
+
+    for r in [SegmentReader::open(&dir).unwrap(), SegmentReader::open_buffered(&dir).unwrap()] {
+        for (c, cr) in chunks.iter().zip(&refs) {
+            let got = r.chunk(*cr).unwrap();
+            // sealed chunks are stored like Prometheus stores them, they lose
+            // their footer
+            assert!(!got.is_sealed());
+            assert!(got.footer().unwrap().index.is_none());
+            assert_eq!(c.num_samples().to_be_bytes(), got.bytes()[..2]);
+            assert_eq!(c.bit_len().div_ceil(8), got.byte_len());
+            assert_eq!(c.meta(), got.meta().unwrap());
+            assert!(c.iterator().eq(got.iterator()));
+            assert_eq!(c.meta(), got.to_chunk().unwrap().meta());
+            assert!(c.iterator().eq(XORIterator::new(&r.chunk_bytes(*cr).unwrap())));
+        }
+        assert!(matches!(r.chunk(ChunkRef::new(1, 3)), Err(Error::InvalidRef(_))));
+        assert!(matches!(r.chunk(ChunkRef::new(99, 8)), Err(Error::InvalidRef(_))));
+    }
+    let r = SegmentReader::open(&dir).unwrap();
+    assert_eq!(cfg!(feature = "mmap"), r.is_mapped());
+    assert!(!SegmentReader::open_buffered(&dir).unwrap().is_mapped());
+    if cfg!(feature = "mmap") {
+        assert!(matches!(r.chunk_bytes(refs[1]).unwrap(), Cow::Borrowed(_)));
+    }
+
+    // with footers sealed chunks come back as they were written
+    let footers = dir.join("footers");
+    let mut w = SegmentWriter::new(&footers).unwrap().with_footers(true);
+    let refs: Vec<_> = chunks.iter().map(|c| w.write_chunk(c).unwrap()).collect();
+    w.close().unwrap();
+    for r in [SegmentReader::open(&footers).unwrap(), SegmentReader::open_buffered(&footers).unwrap()] {
+        for (c, cr) in chunks.iter().zip(&refs) {
+            let got = r.chunk(*cr).unwrap();
+            assert_eq!(c.is_sealed(), got.is_sealed());
+            assert_eq!(c.bytes(), got.bytes());
+            assert_eq!(c.meta(), got.meta().unwrap());
+            assert!(c.seek(i64::MIN).eq(got.seek(i64::MIN)));
+            if c.is_sealed() {
+                assert_eq!(Some(4), got.footer().unwrap().index.map(|i| i.interval()));
+                // the meta comes from the footer, the samples aren't decoded
+                let mut b = got.bytes().to_vec();
+                b[2..c.bit_len() / 8].fill(0xff);
+                assert_eq!(c.meta(), ChunkView::new(b).unwrap().meta().unwrap());
+            }
+        }
+    }
+
+    // a new writer starts a new segment behind the existing ones
+    let last = r.segments().last().unwrap();
+    let mut w = SegmentWriter::new(&dir).unwrap();
+    assert_eq!(last + 1, w.write_chunk(&chunks[1]).unwrap().segment());
+    w.close().unwrap();
+
+    // corrupt the data of the first chunk
+    let path = segment_path(&dir, 1);
+    let mut b = fs::read(&path).unwrap();
+    b[SEGMENT_HEADER_LEN as usize + 4] ^= 0xff;
+    fs::write(&path, b).unwrap();
+    for r in [SegmentReader::open(&dir).unwrap(), SegmentReader::open_buffered(&dir).unwrap()] {
+        assert!(matches!(r.chunk(refs[0]), Err(Error::ChecksumMismatch(_))));
+    }
+    // and its length, to the largest one a uvarint holds
+    let mut b = fs::read(&path).unwrap();
+    let at = SEGMENT_HEADER_LEN as usize;
+    b[at..at + 10].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+    fs::write(&path, b).unwrap();
+    for r in [SegmentReader::open(&dir).unwrap(), SegmentReader::open_buffered(&dir).unwrap()] {
+        assert!(matches!(r.chunk(refs[0]), Err(Error::InvalidRef(_))));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_segment_prometheus_layout() {
+    let dir = test_dir("segment-layout");
+    let mut c = XORChunk::new();
+    c.append(1000, 1.0).unwrap();
+    c.append(2000, 2.0).unwrap();
+    let mut w = SegmentWriter::new(&dir).unwrap();
+    w.write_chunk(&c).unwrap();
+    w.close().unwrap();
+
+    let b = fs::read(segment_path(&dir, 1)).unwrap();
+    let data = &c.bytes()[..c.bit_len().div_ceil(8)];
+    assert_eq!([0x85, 0xbd, 0x40, 0xdd, 1, 0, 0, 0], b[..8]);
+    assert_eq!(data.len() as u8, b[8]);
+    assert_eq!(ENCODING_XOR, b[9]);
+    assert_eq!(data, &b[10..10 + data.len()]);
+    assert_eq!(crc32::checksum(&b[9..10 + data.len()]).to_be_bytes(), b[10 + data.len()..]);
+    fs::remove_dir_all(&dir).unwrap();
+}
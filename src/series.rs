@@ -0,0 +1,697 @@
+// A ConcurrentSeries is appended to by ingestion threads while query threads
+// read it. Appends are serialised by a mutex around the open chunk, reads
+// never take it:
+//
+// Every append copies the bytes it changed into a fixed size buffer of atomic
+// bytes and then publishes the number of samples and the byte length of the
+// chunk in a single atomic store. A reader loads that pair first and copies
+// only those bytes, which the writer never changes again except for the bits
+// behind the last published sample and the sample count in the header. The
+// reader patches the header and decodes exactly the published samples, so it
+// sees the chunk as it was at that append no matter what the writer does next.
+//
+// The open chunk is cut into a sealed one every samples_per_chunk samples,
+// which bounds the size of the buffer.
+//
+// XOR chunks only take samples in order. With an out of order window, samples
+// older than the last one but within the window of it go into a sorted buffer
+// instead, which readers merge with the chunks. compact_ooo rebuilds the
+// chunks with the buffered samples merged in.
+use crate::xor::{ChunkMeta, Error, SampleIterator, SealOptions, XORChunk, XORIterator, MAX_SAMPLES};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Mutex, RwLock};
+
+pub const DEFAULT_SAMPLES_PER_CHUNK: u16 = 120;
+
+// MAX_SAMPLE_LEN is the most bytes a sample other than the first two can take:
+// a 4 bit dod prefix with a 64 bit dod and a 77 bit value.
+const MAX_SAMPLE_LEN: usize = 19;
+// HEAD_ROOM covers the header and the first two samples, which start with varints.
+const HEAD_ROOM: usize = 64;
+
+// PublishedChunk is the reader side of the open chunk.
+struct PublishedChunk {
+    bytes: Vec<AtomicU8>,
+    // number of samples << 32 | number of bytes they take
+    published: AtomicU64,
+}
+
+impl PublishedChunk {
+    fn new(samples_per_chunk: u16) -> PublishedChunk {
+        let n = HEAD_ROOM + samples_per_chunk as usize * MAX_SAMPLE_LEN;
+        PublishedChunk {
+            bytes: (0..n).map(|_| AtomicU8::new(0)).collect(),
+            published: AtomicU64::new(0),
+        }
+    }
+
+    // publish copies the header of chunk and its bytes from byte from on
+    // and makes its samples visible.
+    fn publish(&self, chunk: &XORChunk, from: usize) {
+        let len = chunk.bit_len().div_ceil(8);
+        let bytes = chunk.bytes();
+        for i in (0..2).chain(from..len) {
+            self.bytes[i].store(bytes[i], Ordering::Relaxed);
+        }
+        let num = chunk.num_samples() as u64;
+        self.published.store(num << 32 | len as u64, Ordering::Release);
+    }
+
+    // load copies the chunk as of the last publish.
+    fn load(&self) -> Result<Option<XORChunk>, Error> {
+        let published = self.published.load(Ordering::Acquire);
+        let num = (published >> 32) as u16;
+        if num == 0 {
+            return Ok(None);
+        }
+        let len = published as u32 as usize;
+        let mut bytes: Vec<u8> = self.bytes[..len].iter().map(|b| b.load(Ordering::Relaxed)).collect();
+        [bytes[0], bytes[1]] = num.to_be_bytes();
+        XORChunk::from_bytes(bytes).map(Some)
+    }
+}
+
+// Writer is the state behind the append lock.
+struct Writer {
+    chunk: XORChunk,
+    head: Arc<PublishedChunk>,
+}
+
+// Chunks is what readers see: the sealed chunks and the open one.
+struct Chunks {
+    sealed: Vec<Arc<XORChunk>>,
+    head: Arc<PublishedChunk>,
+}
+
+pub struct ConcurrentSeries {
+    writer: Mutex<Writer>,
+    // only written when a chunk is cut
+    chunks: RwLock<Chunks>,
+    samples_per_chunk: u16,
+    // time range of the published samples
+    min_time: AtomicI64,
+    max_time: AtomicI64,
+    // sorted out of order samples, locked after chunks. Snapshots share them,
+    // an append copies them only while a snapshot holds on to them.
+    ooo: Mutex<Arc<Vec<(i64, f64)>>>,
+    ooo_window: i64,
+}
+
+impl Default for ConcurrentSeries {
+    fn default() -> ConcurrentSeries {
+        ConcurrentSeries::new()
+    }
+}
+
+impl ConcurrentSeries {
+    pub fn new() -> ConcurrentSeries {
+        ConcurrentSeries::with_samples_per_chunk(DEFAULT_SAMPLES_PER_CHUNK)
+    }
+
+    pub fn with_samples_per_chunk(samples_per_chunk: u16) -> ConcurrentSeries {
+        let samples_per_chunk = samples_per_chunk.clamp(1, MAX_SAMPLES);
+        let head = Arc::new(PublishedChunk::new(samples_per_chunk));
+        ConcurrentSeries {
+            writer: Mutex::new(Writer {
+                chunk: XORChunk::new(),
+                head: head.clone(),
+            }),
+            chunks: RwLock::new(Chunks {
+                sealed: Vec::new(),
+                head,
+            }),
+            samples_per_chunk,
+            min_time: AtomicI64::new(i64::MAX),
+            max_time: AtomicI64::new(i64::MIN),
+            ooo: Mutex::new(Arc::new(Vec::new())),
+            ooo_window: 0,
+        }
+    }
+
+    // with_ooo_window accepts samples up to window milliseconds older than
+    // the newest one. Without a window they are rejected.
+    pub fn with_ooo_window(mut self, window: i64) -> ConcurrentSeries {
+        self.ooo_window = window.max(0);
+        self
+    }
+
+    // with_chunks loads the chunks and out of order samples of a snapshot
+    // into an empty series. A last chunk that isn't sealed becomes the open
+    // one, appends continue in it.
+    pub(crate) fn with_chunks(self, mut sealed: Vec<XORChunk>, ooo: Vec<(i64, f64)>) -> Result<ConcurrentSeries, Error> {
+        let mut open = match sealed.last() {
+            Some(c) if !c.is_sealed() => sealed.pop().unwrap(),
+            _ => XORChunk::new(),
+        };
+        // the chunk has to fit into the buffer of readers
+        if open.num_samples() > self.samples_per_chunk {
+            open.seal(SealOptions::default())?;
+            sealed.push(core::mem::take(&mut open));
+        }
+        {
+            let mut w = self.writer.lock().unwrap();
+            w.head.publish(&open, 0);
+            w.chunk = open;
+            let chunks = sealed.iter().chain([&w.chunk]).filter(|c| c.num_samples() > 0);
+            for c in chunks {
+                self.min_time.fetch_min(c.min_time(), Ordering::Relaxed);
+                self.max_time.fetch_max(c.max_time(), Ordering::Relaxed);
+            }
+            if let Some(first) = ooo.first() {
+                self.min_time.fetch_min(first.0, Ordering::Relaxed);
+            }
+            self.chunks.write().unwrap().sealed = sealed.into_iter().map(Arc::new).collect();
+            *self.ooo.lock().unwrap() = Arc::new(ooo);
+        }
+        Ok(self)
+    }
+
+    // min_time returns the timestamp of the first sample, i64::MAX if there is none.
+    pub fn min_time(&self) -> i64 {
+        self.min_time.load(Ordering::Acquire)
+    }
+
+    // max_time returns the timestamp of the last sample, i64::MIN if there is none.
+    pub fn max_time(&self) -> i64 {
+        self.max_time.load(Ordering::Acquire)
+    }
+
+    // append adds a sample behind the last one. Samples that aren't newer
+    // than the last one are rejected, which is what concurrent writers racing
+    // each other on the same series run into, unless they are older than it
+    // but within the out of order window.
+    pub fn append(&self, t: i64, v: f64) -> Result<(), Error> {
+        let mut w = self.writer.lock().unwrap();
+        self.check(t)?;
+        let max = self.max_time.load(Ordering::Acquire);
+        if max != i64::MIN && t <= max {
+            let mut ooo = self.ooo.lock().unwrap();
+            let ooo = Arc::make_mut(&mut ooo);
+            match ooo.binary_search_by_key(&t, |s| s.0) {
+                Ok(i) => ooo[i].1 = v,
+                Err(i) => ooo.insert(i, (t, v)),
+            }
+            self.min_time.fetch_min(t, Ordering::AcqRel);
+            return Ok(());
+        }
+        if w.chunk.num_samples() >= self.samples_per_chunk {
+            self.cut(&mut w)?;
+        }
+
+        let from = w.chunk.bit_len() / 8;
+        w.chunk.append(t, v)?;
+        w.head.publish(&w.chunk, from);
+        self.min_time.fetch_min(t, Ordering::AcqRel);
+        self.max_time.store(t, Ordering::Release);
+        Ok(())
+    }
+
+    // check returns the error append would return for a sample at t. It only
+    // holds until the next append, callers that log samples before appending
+    // them serialise the appends.
+    pub fn check(&self, t: i64) -> Result<(), Error> {
+        // compare with the whole series, the open chunk is empty after a
+        // restore that ended with a sealed chunk
+        self.check_after(t, self.max_time.load(Ordering::Acquire))
+    }
+
+    // check_after is check for when the newest sample is at max, which lets
+    // callers check a sample behind others they haven't appended yet.
+    pub(crate) fn check_after(&self, t: i64, max: i64) -> Result<(), Error> {
+        if max != i64::MIN && t <= max && (t == max || t < max.saturating_sub(self.ooo_window)) {
+            return Err(Error::OutOfOrderSample(t));
+        }
+        // the open chunk may end at max, appending would fail on the delta
+        if max != i64::MIN && t > max && t.checked_sub(max).is_none() {
+            return Err(Error::TimestampOverflow(t));
+        }
+        Ok(())
+    }
+
+    // contains says whether the series has a sample at t with the value v.
+    pub fn contains(&self, t: i64, v: f64) -> Result<bool, Error> {
+        if t < self.min_time() || t > self.max_time() {
+            return Ok(false);
+        }
+        let snap = self.snapshot()?;
+        let mut it = snap.iter_range(t, t);
+        // compare the bits, staleness markers are NaNs
+        let found = it.next().is_some_and(|s| s.1.to_bits() == v.to_bits());
+        match it.err() {
+            Some(err) => Err(err.clone()),
+            None => Ok(found),
+        }
+    }
+
+    // cut seals the open chunk and starts a new one.
+    fn cut(&self, w: &mut Writer) -> Result<(), Error> {
+        let mut chunk = core::mem::take(&mut w.chunk);
+        chunk.seal(SealOptions::default())?;
+        w.head = Arc::new(PublishedChunk::new(self.samples_per_chunk));
+        let mut chunks = self.chunks.write().unwrap();
+        chunks.sealed.push(Arc::new(chunk));
+        chunks.head = w.head.clone();
+        Ok(())
+    }
+
+    // snapshot returns the series as of the last append that finished. It
+    // only holds the read lock on the chunk list to clone it, the out of
+    // order samples aren't copied.
+    pub fn snapshot(&self) -> Result<SeriesSnapshot, Error> {
+        let (mut chunks, head, ooo) = {
+            let c = self.chunks.read().unwrap();
+            (c.sealed.clone(), c.head.clone(), self.ooo.lock().unwrap().clone())
+        };
+        if let Some(chunk) = head.load()? {
+            chunks.push(Arc::new(chunk));
+        }
+        Ok(SeriesSnapshot { chunks, ooo })
+    }
+
+    // compact_ooo merges the out of order samples into the chunks, which it
+    // rebuilds, and returns how many there were. Appends wait for it.
+    pub fn compact_ooo(&self) -> Result<usize, Error> {
+        if self.ooo.lock().unwrap().is_empty() {
+            return Ok(0);
+        }
+        Ok(self.rewrite(i64::MAX, i64::MIN)?.0)
+    }
+
+    // delete drops the samples with mint <= t <= maxt, out of order ones
+    // included, and returns how many there were. The chunks are rebuilt like
+    // by compact_ooo. The time range of the series stays as it is, so
+    // samples older than a deleted newest one are still rejected.
+    pub fn delete(&self, mint: i64, maxt: i64) -> Result<usize, Error> {
+        if mint > maxt || maxt < self.min_time() || mint > self.max_time() {
+            return Ok(0);
+        }
+        Ok(self.rewrite(mint, maxt)?.1)
+    }
+
+    // rewrite rebuilds the chunks from all samples but those in [mint, maxt],
+    // with the out of order samples merged in. It returns how many out of
+    // order samples there were and how many samples it dropped.
+    fn rewrite(&self, mint: i64, maxt: i64) -> Result<(usize, usize), Error> {
+        let mut w = self.writer.lock().unwrap();
+        let mut chunks = self.chunks.write().unwrap();
+        let mut ooo = self.ooo.lock().unwrap();
+        let all = chunks.sealed.iter().map(|c| c.bytes() as &[u8]).chain([w.chunk.bytes()]).collect();
+        let mut it = SeriesIterator::new(all).with_ooo(&ooo);
+        let mut samples: Vec<_> = (&mut it).collect();
+        if let Some(err) = it.err() {
+            return Err(err.clone());
+        }
+        let n = samples.len();
+        samples.retain(|s| s.0 < mint || s.0 > maxt);
+        let deleted = n - samples.len();
+        if deleted == 0 && ooo.is_empty() {
+            return Ok((0, 0));
+        }
+
+        let mut sealed = Vec::new();
+        let mut chunk = XORChunk::new();
+        for (t, v) in samples {
+            if chunk.num_samples() >= self.samples_per_chunk {
+                chunk.seal(SealOptions::default())?;
+                sealed.push(Arc::new(core::mem::take(&mut chunk)));
+            }
+            chunk.append(t, v)?;
+        }
+        let head = Arc::new(PublishedChunk::new(self.samples_per_chunk));
+        head.publish(&chunk, 0);
+        *w = Writer { chunk, head: head.clone() };
+        *chunks = Chunks { sealed, head };
+        let n = ooo.len();
+        *ooo = Arc::new(Vec::new());
+        Ok((n, deleted))
+    }
+}
+
+// SeriesSnapshot is an immutable view on a ConcurrentSeries.
+#[derive(Clone)]
+pub struct SeriesSnapshot {
+    chunks: Vec<Arc<XORChunk>>,
+    ooo: Arc<Vec<(i64, f64)>>,
+}
+
+impl SeriesSnapshot {
+    // chunks returns the chunks without the out of order samples.
+    pub fn chunks(&self) -> impl Iterator<Item = &XORChunk> {
+        self.chunks.iter().map(|c| c.as_ref())
+    }
+
+    // ooo_samples returns the out of order samples, sorted.
+    pub fn ooo_samples(&self) -> &[(i64, f64)] {
+        &self.ooo
+    }
+
+    // num_samples counts the out of order samples too, even those with the
+    // timestamp of a sample in the chunks.
+    pub fn num_samples(&self) -> usize {
+        self.chunks.iter().map(|c| c.num_samples() as usize).sum::<usize>() + self.ooo.len()
+    }
+
+    // meta returns the time range of all samples. bit_len is the sum over the chunks.
+    pub fn meta(&self) -> ChunkMeta {
+        let mut meta = ChunkMeta { bit_len: 0, ..ChunkMeta::default() };
+        for c in &self.chunks {
+            meta.min_time = meta.min_time.min(c.min_time());
+            meta.max_time = meta.max_time.max(c.max_time());
+            meta.bit_len += c.bit_len();
+        }
+        if let (Some(first), Some(last)) = (self.ooo.first(), self.ooo.last()) {
+            meta.min_time = meta.min_time.min(first.0);
+            meta.max_time = meta.max_time.max(last.0);
+        }
+        meta
+    }
+
+    pub fn iter(&self) -> SeriesIterator<'_> {
+        SeriesIterator::new(self.chunks.iter().map(|c| c.bytes() as &[u8]).collect()).with_ooo(&self.ooo)
+    }
+
+    // iter_range iterates over the samples with mint <= t <= maxt. It skips
+    // the chunks outside the range.
+    pub fn iter_range(&self, mint: i64, maxt: i64) -> SeriesIterator<'_> {
+        let chunks = self
+            .chunks
+            .iter()
+            .filter(|c| c.max_time() >= mint && c.min_time() <= maxt)
+            .map(|c| c.bytes() as &[u8])
+            .collect();
+        let ooo = &self.ooo[self.ooo.partition_point(|s| s.0 < mint)..self.ooo.partition_point(|s| s.0 <= maxt)];
+        let mut it = SeriesIterator::new(chunks).with_ooo(ooo);
+        (it.mint, it.maxt) = (mint, maxt);
+        it
+    }
+}
+
+// SeriesIterator iterates over the samples of a list of chunks with
+// ascending, non overlapping time ranges, given by their bytes, merged with
+// sorted out of order samples. Out of order samples replace the samples of
+// the chunks with the same timestamp.
+pub struct SeriesIterator<'a> {
+    chunks: Vec<&'a [u8]>,
+    next: usize, // next chunk to read
+    it: Option<XORIterator<'a>>,
+    err: Option<Error>,
+    ooo: &'a [(i64, f64)],
+    // sample of the chunks behind the next out of order one
+    pending: Option<(i64, f64)>,
+    mint: i64,
+    maxt: i64,
+}
+
+impl<'a> SeriesIterator<'a> {
+    pub fn new(chunks: Vec<&'a [u8]>) -> SeriesIterator<'a> {
+        SeriesIterator {
+            chunks,
+            next: 0,
+            it: None,
+            err: None,
+            ooo: &[],
+            pending: None,
+            mint: i64::MIN,
+            maxt: i64::MAX,
+        }
+    }
+
+    pub fn with_ooo(mut self, ooo: &'a [(i64, f64)]) -> SeriesIterator<'a> {
+        self.ooo = ooo;
+        self
+    }
+
+    fn next_merged(&mut self) -> Option<(i64, f64)> {
+        let s = self.pending.take().or_else(|| self.next_in_order());
+        if self.err.is_some() {
+            return None;
+        }
+        let Some((&o, rest)) = self.ooo.split_first() else {
+            return s;
+        };
+        match s {
+            Some(s) if s.0 < o.0 => Some(s),
+            s => {
+                self.ooo = rest;
+                self.pending = s.filter(|s| s.0 != o.0);
+                Some(o)
+            }
+        }
+    }
+
+    fn next_in_order(&mut self) -> Option<(i64, f64)> {
+        loop {
+            if let Some(it) = &mut self.it {
+                if let Some(s) = it.next() {
+                    return Some(s);
+                }
+                if let Some(err) = it.err() {
+                    self.err = Some(err.clone());
+                    return None;
+                }
+            }
+            if self.err.is_some() || self.next == self.chunks.len() {
+                return None;
+            }
+            let b = self.chunks[self.next];
+            self.it = Some(match self.it.take() {
+                Some(mut it) => {
+                    it.reset(b);
+                    it
+                }
+                None => XORIterator::new(b),
+            });
+            self.next += 1;
+        }
+    }
+}
+
+impl Iterator for SeriesIterator<'_> {
+    type Item = (i64, f64);
+
+    fn next(&mut self) -> Option<(i64, f64)> {
+        loop {
+            let s = self.next_merged()?;
+            if s.0 > self.maxt {
+                return None;
+            }
+            if s.0 >= self.mint {
+                return Some(s);
+            }
+        }
+    }
+}
+
+impl SampleIterator for SeriesIterator<'_> {
+    fn err(&self) -> Option<&Error> {
+        self.err.as_ref()
+    }
+}
+
+#[test]
+fn test_concurrent_series() {
+    let s = ConcurrentSeries::with_samples_per_chunk(10);
+    for i in 0..25 {
+        s.append(i * 1000, i as f64).unwrap();
+    }
+    assert_eq!(Err(Error::OutOfOrderSample(24000)), s.append(24000, 0.0));
+    assert_eq!((0, 24000), (s.min_time(), s.max_time()));
+
+    let snap = s.snapshot().unwrap();
+    assert_eq!(3, snap.chunks().count());
+    assert!(snap.chunks().take(2).all(|c| c.is_sealed()));
+    assert_eq!(25, snap.num_samples());
+    assert_eq!((0, 24000), (snap.meta().min_time, snap.meta().max_time));
+
+    // later appends don't show up in the snapshot
+    s.append(25000, 25.0).unwrap();
+    let got: Vec<_> = snap.iter().collect();
+    let want: Vec<_> = (0..25).map(|i| (i * 1000, i as f64)).collect();
+    assert_eq!(want, got);
+    assert_eq!(26, s.snapshot().unwrap().iter().count());
+
+    // check rejects what append would, so it can be logged before
+    let s = ConcurrentSeries::new();
+    s.append(-1, 0.0).unwrap();
+    assert_eq!(Err(Error::TimestampOverflow(i64::MAX)), s.check(i64::MAX));
+    assert_eq!(Err(Error::TimestampOverflow(i64::MAX)), s.append(i64::MAX, 0.0));
+}
+
+#[test]
+fn test_concurrent_series_ooo() {
+    let s = ConcurrentSeries::with_samples_per_chunk(4).with_ooo_window(100);
+    for t in [100, 200, 300, 400, 500, 600] {
+        s.append(t, 1.0).unwrap();
+    }
+    assert_eq!(Err(Error::OutOfOrderSample(600)), s.append(600, 2.0));
+    assert_eq!(Err(Error::OutOfOrderSample(499)), s.append(499, 2.0));
+    s.append(550, 2.0).unwrap();
+    s.append(500, 2.0).unwrap();
+    s.append(520, 3.0).unwrap();
+    // the last one with a timestamp wins
+    s.append(520, 2.0).unwrap();
+    s.append(700, 1.0).unwrap();
+    // the window moves with the newest sample
+    assert_eq!(Err(Error::OutOfOrderSample(550)), s.append(550, 2.0));
+    s.append(650, 2.0).unwrap();
+
+    let want = [(100, 1.0), (200, 1.0), (300, 1.0), (400, 1.0), (500, 2.0), (520, 2.0), (550, 2.0), (600, 1.0), (650, 2.0), (700, 1.0)];
+    let snap = s.snapshot().unwrap();
+    assert_eq!(&[(500, 2.0), (520, 2.0), (550, 2.0), (650, 2.0)], snap.ooo_samples());
+    assert_eq!(want.to_vec(), snap.iter().collect::<Vec<_>>());
+    assert_eq!(want[4..8].to_vec(), snap.iter_range(450, 600).collect::<Vec<_>>());
+    assert_eq!((100, 700), (snap.meta().min_time, snap.meta().max_time));
+    // snapshots share the out of order samples until the next one comes in
+    assert_eq!(snap.ooo_samples().as_ptr(), s.snapshot().unwrap().ooo_samples().as_ptr());
+    s.append(690, 2.0).unwrap();
+    assert_eq!(4, snap.ooo_samples().len());
+    assert_eq!(5, s.snapshot().unwrap().ooo_samples().len());
+
+    // an out of order sample before all others
+    let s2 = ConcurrentSeries::new().with_ooo_window(1000);
+    s2.append(500, 1.0).unwrap();
+    s2.append(0, 0.0).unwrap();
+    assert_eq!((0, 500), (s2.min_time(), s2.max_time()));
+
+    assert_eq!(5, s.compact_ooo().unwrap());
+    assert_eq!(0, s.compact_ooo().unwrap());
+    let compacted = s.snapshot().unwrap();
+    assert!(compacted.ooo_samples().is_empty());
+    assert_eq!(3, compacted.chunks().count());
+    let mut all = want.to_vec();
+    all.insert(9, (690, 2.0));
+    assert_eq!(all, compacted.iter().collect::<Vec<_>>());
+    // the old snapshot is unchanged and appends go on behind the rebuilt chunks
+    assert_eq!(want.to_vec(), snap.iter().collect::<Vec<_>>());
+    s.append(800, 1.0).unwrap();
+    s.append(750, 2.0).unwrap();
+    assert_eq!(13, s.snapshot().unwrap().iter().count());
+}
+
+#[test]
+fn test_concurrent_series_delete() {
+    let s = ConcurrentSeries::with_samples_per_chunk(4).with_ooo_window(1000);
+    for t in 1..=10 {
+        s.append(t * 100, t as f64).unwrap();
+    }
+    s.append(250, 2.5).unwrap();
+    let snap = s.snapshot().unwrap();
+    assert_eq!(0, s.delete(2000, 3000).unwrap());
+    assert_eq!(0, s.delete(300, 200).unwrap());
+    assert_eq!(4, s.delete(200, 400).unwrap());
+    let got: Vec<_> = s.snapshot().unwrap().iter().map(|s| s.0).collect();
+    assert_eq!(vec![100, 500, 600, 700, 800, 900, 1000], got);
+    assert!(s.snapshot().unwrap().ooo_samples().is_empty());
+    // snapshots from before are unchanged
+    assert_eq!(11, snap.iter().count());
+
+    // deleting the newest samples doesn't let older ones in again
+    assert_eq!(2, s.delete(900, 1000).unwrap());
+    assert_eq!((100, 1000), (s.min_time(), s.max_time()));
+    assert_eq!(Err(Error::OutOfOrderSample(1000)), s.append(1000, 0.0));
+    s.append(1100, 11.0).unwrap();
+    let got: Vec<_> = s.snapshot().unwrap().iter().map(|s| s.0).collect();
+    assert_eq!(vec![100, 500, 600, 700, 800, 1100], got);
+}
+
+#[test]
+fn test_concurrent_series_ooo_boundary() {
+    // at a chunk boundary, before and after the cut
+    let s = ConcurrentSeries::with_samples_per_chunk(2);
+    s.append(100, 1.0).unwrap();
+    s.append(200, 1.0).unwrap();
+    assert_eq!(Err(Error::OutOfOrderSample(150)), s.append(150, 1.0));
+    s.append(300, 1.0).unwrap();
+    assert_eq!(Err(Error::OutOfOrderSample(200)), s.append(200, 1.0));
+
+    // after a restore that ends with a sealed chunk the open chunk is empty
+    let mut sealed = XORChunk::new();
+    sealed.append(100, 1.0).unwrap();
+    sealed.append(200, 1.0).unwrap();
+    sealed.seal(SealOptions::default()).unwrap();
+    let restore = |window| ConcurrentSeries::new().with_ooo_window(window).with_chunks(vec![sealed.clone()], Vec::new()).unwrap();
+    let s = restore(0);
+    assert_eq!(Err(Error::OutOfOrderSample(150)), s.append(150, 2.0));
+    assert_eq!(Err(Error::OutOfOrderSample(200)), s.append(200, 2.0));
+    s.append(300, 3.0).unwrap();
+    assert_eq!(vec![(100, 1.0), (200, 1.0), (300, 3.0)], s.snapshot().unwrap().iter().collect::<Vec<_>>());
+    // the out of order window applies
+    let s = restore(100);
+    s.append(150, 2.0).unwrap();
+    assert_eq!(Err(Error::OutOfOrderSample(99)), s.append(99, 2.0));
+    assert_eq!((Ok(()), Err(Error::OutOfOrderSample(99))), (s.check(120), s.check(99)));
+    assert_eq!(&[(150, 2.0)], s.snapshot().unwrap().ooo_samples());
+    assert_eq!(vec![(100, 1.0), (150, 2.0), (200, 1.0)], s.snapshot().unwrap().iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_concurrent_series_stress() {
+    use std::sync::atomic::{AtomicBool, AtomicI64};
+    use std::thread;
+
+    const WRITERS: usize = 4;
+    const READERS: usize = 4;
+    const SAMPLES: i64 = 20_000;
+
+    let s = Arc::new(ConcurrentSeries::new());
+    let next = Arc::new(AtomicI64::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|_| {
+            let (s, next) = (s.clone(), next.clone());
+            thread::spawn(move || {
+                let mut accepted = Vec::new();
+                loop {
+                    let t = next.fetch_add(1, Ordering::Relaxed);
+                    if t >= SAMPLES {
+                        return accepted;
+                    }
+                    // values depend on t with irregular gaps, which exercises all encodings
+                    let t = t * 10 + t % 7;
+                    match s.append(t, (t % 1013) as f64 * 0.5) {
+                        Ok(()) => accepted.push(t),
+                        Err(Error::OutOfOrderSample(_)) => {}
+                        Err(err) => panic!("{}", err),
+                    }
+                }
+            })
+        })
+        .collect();
+    let readers: Vec<_> = (0..READERS)
+        .map(|_| {
+            let (s, done) = (s.clone(), done.clone());
+            thread::spawn(move || {
+                let mut last_len = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let snap = s.snapshot().unwrap();
+                    let mut it = snap.iter();
+                    let mut prev = i64::MIN;
+                    let mut n = 0;
+                    for (t, v) in &mut it {
+                        assert!(t > prev);
+                        assert_eq!((t % 1013) as f64 * 0.5, v);
+                        prev = t;
+                        n += 1;
+                    }
+                    assert!(it.err().is_none());
+                    assert_eq!(snap.num_samples(), n);
+                    // snapshots only ever grow
+                    assert!(n >= last_len);
+                    last_len = n;
+                    thread::yield_now();
+                }
+            })
+        })
+        .collect();
+
+    let mut accepted: Vec<i64> = writers.into_iter().flat_map(|w| w.join().unwrap()).collect();
+    accepted.sort();
+    done.store(true, Ordering::Relaxed);
+    for r in readers {
+        r.join().unwrap();
+    }
+    let got: Vec<i64> = s.snapshot().unwrap().iter().map(|(t, _)| t).collect();
+    assert_eq!(accepted, got);
+}
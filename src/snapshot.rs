@@ -0,0 +1,311 @@
+// A snapshot holds the whole head: the labels and chunks of every series,
+// the open chunks with the state of their appender, so appends resume without
+// decoding them, and the out of order samples. Written on shutdown, it lets a
+// restart load the head and only replay the WAL segments written after it.
+//
+// The snapshot file is
+//
+//   [magic u32][version u8][last WAL segment + 1 uvarint, 0 if there is none]
+//   [number of series uvarint]
+//   ([series ref uvarint][labels, see Labels::encode][number of chunks uvarint]
+//    ([len uvarint][chunk])...
+//    [appender state, 32 bytes, if the last chunk isn't sealed]
+//    [number of out of order samples uvarint]([t varint][v 64 bits])...)...
+//   [crc32c of everything before u32]
+//
+// It is written aside and moved in place, so there is always a complete one.
+use crate::bstream::{Bstream, BstreamReader};
+use crate::crc32;
+use crate::head::{self, Head, SeriesRef};
+use crate::labels::Labels;
+use crate::wal::{self, ReplayStats};
+use crate::xor::{self, AppenderState, XORChunk};
+#[cfg(test)]
+use crate::test_dir;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+const MAGIC: u32 = 0x534E4150;
+const SNAPSHOT_V1: u8 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Head(head::Error),
+    Wal(wal::Error),
+    Corrupted,
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<head::Error> for Error {
+    fn from(err: head::Error) -> Error {
+        Error::Head(err)
+    }
+}
+
+impl From<wal::Error> for Error {
+    fn from(err: wal::Error) -> Error {
+        Error::Wal(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "snapshot: {}", err),
+            Error::Head(err) => write!(f, "snapshot: {}", err),
+            Error::Wal(err) => write!(f, "snapshot: {}", err),
+            Error::Corrupted => write!(f, "snapshot: corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// SnapshotSeries is a series as it is stored in a snapshot.
+struct SnapshotSeries {
+    sref: SeriesRef,
+    labels: Labels,
+    chunks: Vec<XORChunk>,
+    ooo: Vec<(i64, f64)>,
+}
+
+// write writes a snapshot of the head to path. If the head has a WAL, appends
+// wait until it is written and go into a new WAL segment afterwards.
+pub fn write(path: impl AsRef<Path>, head: &Head) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut wal = head.lock_wal();
+    let last_segment = match &mut wal {
+        Some(wal) => wal.finish_segment()?,
+        None => None,
+    };
+
+    let series = head.all_series();
+    let mut b = Bstream::new(Vec::new());
+    b.write_aligned_bytes(&MAGIC.to_be_bytes());
+    b.write_aligned_bytes(&[SNAPSHOT_V1]);
+    b.write_uvarint(last_segment.map_or(0, |seq| seq as u64 + 1));
+    b.write_uvarint(series.len() as u64);
+    for s in &series {
+        let snap = s.snapshot()?;
+        b.write_uvarint(s.series_ref().0);
+        s.labels().encode(&mut b);
+        b.write_uvarint(snap.chunks().count() as u64);
+        for c in snap.chunks() {
+            b.write_uvarint(c.bytes().len() as u64);
+            b.write_aligned_bytes(c.bytes());
+        }
+        if let Some(c) = snap.chunks().last().filter(|c| !c.is_sealed()) {
+            b.write_aligned_bytes(&c.appender_state().encode());
+        }
+        b.write_uvarint(snap.ooo_samples().len() as u64);
+        for (t, v) in snap.ooo_samples() {
+            b.write_varint(*t);
+            b.write_aligned_bytes(&v.to_bits().to_be_bytes());
+        }
+    }
+    b.pad_to_byte();
+    let crc = crc32::checksum(b.read_bytes());
+    b.write_aligned_bytes(&crc.to_be_bytes());
+
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let f = File::create(&tmp)?;
+    io::Write::write_all(&mut &f, b.read_bytes())?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn read_slice<'a>(br: &mut BstreamReader, b: &'a [u8], n: usize) -> Option<&'a [u8]> {
+    let start = br.position() / 8;
+    let s = b.get(start..start.checked_add(n)?)?;
+    br.seek((start + n) * 8).ok()?;
+    Some(s)
+}
+
+// decode returns the last WAL segment the snapshot covers and its series.
+fn decode(b: &[u8]) -> Option<(Option<u32>, Vec<SnapshotSeries>)> {
+    let (b, crc) = b.split_at(b.len().checked_sub(4)?);
+    if b.len() < 5 || crc32::checksum(b) != u32::from_be_bytes(crc.try_into().ok()?) {
+        return None;
+    }
+    if u32::from_be_bytes(b[..4].try_into().ok()?) != MAGIC || b[4] != SNAPSHOT_V1 {
+        return None;
+    }
+    let mut br = BstreamReader::new(b);
+    br.seek(5 * 8).ok()?;
+    let last_segment = match br.read_uvarint().ok()? {
+        0 => None,
+        seq => Some(u32::try_from(seq - 1).ok()?),
+    };
+    let mut series = Vec::new();
+    for _ in 0..br.read_uvarint().ok()? {
+        let sref = SeriesRef(br.read_uvarint().ok()?);
+        let labels = Labels::decode(&mut br, b)?;
+        let n_chunks = br.read_uvarint().ok()?;
+        let mut chunks = Vec::new();
+        for i in 0..n_chunks {
+            let n = br.read_uvarint().ok()? as usize;
+            let bytes = read_slice(&mut br, b, n)?.to_vec();
+            // the open chunk is loaded with the state behind it, which doesn't
+            // decode its samples
+            let c = if i + 1 == n_chunks && !xor::is_sealed(&bytes) {
+                let state = AppenderState::decode(read_slice(&mut br, b, AppenderState::ENCODED_LEN)?).ok()?;
+                XORChunk::from_bytes_with_state(bytes, state)
+            } else {
+                XORChunk::from_bytes(bytes)
+            };
+            chunks.push(c.ok()?);
+        }
+        let mut ooo = Vec::new();
+        for _ in 0..br.read_uvarint().ok()? {
+            let t = br.read_varint().ok()?;
+            let v = f64::from_bits(u64::from_be_bytes(read_slice(&mut br, b, 8)?.try_into().ok()?));
+            ooo.push((t, v));
+        }
+        series.push(SnapshotSeries { sref, labels, chunks, ooo });
+    }
+    Some((last_segment, series))
+}
+
+// RestoreStats counts what restore did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RestoreStats {
+    // series loaded from the snapshot, none if there was none
+    pub snapshot_series: usize,
+    pub wal: ReplayStats,
+}
+
+// restore loads the snapshot at path into the head, if there is one, and
+// replays the WAL segments in wal_dir written after it. Without a snapshot
+// the whole WAL is replayed. The head has to be empty and mustn't have a WAL
+// yet, see wal::replay.
+//
+// A snapshot is decoded as a whole before anything is loaded, a corrupted one
+// leaves the head alone.
+pub fn restore(path: impl AsRef<Path>, wal_dir: impl AsRef<Path>, head: &Head) -> Result<RestoreStats, Error> {
+    let mut stats = RestoreStats::default();
+    let mut first = 0;
+    match fs::read(path) {
+        Ok(b) => {
+            let (last_segment, series) = decode(&b).ok_or(Error::Corrupted)?;
+            for s in series {
+                head.restore_series(s.sref, &s.labels, s.chunks, s.ooo)?;
+                stats.snapshot_series += 1;
+            }
+            first = last_segment.map_or(0, |seq| seq + 1);
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    stats.wal = wal::replay_from(wal_dir, first, head)?;
+    Ok(stats)
+}
+
+#[test]
+fn test_snapshot_restore() {
+    use crate::wal::{SyncPolicy, Wal};
+
+    let dir = test_dir("snapshot");
+    let wal_dir = dir.join("wal");
+    let path = dir.join("snapshot");
+    let series: Vec<_> = (0..3).map(|i| Labels::from_pairs(&[("__name__", "m"), ("i", &i.to_string())])).collect();
+
+    let head = Head::with_samples_per_chunk(10)
+        .with_ooo_window(1000)
+        .with_wal(Wal::open(&wal_dir).unwrap().with_sync_policy(SyncPolicy::Never));
+    for t in 0..25 {
+        for (i, ls) in series.iter().enumerate() {
+            // series 0 ends with a full chunk
+            if i > 0 || t < 20 {
+                head.append(ls, t * 100, (t * i as i64) as f64).unwrap();
+            }
+        }
+    }
+    head.append(&series[1], 2250, -1.0).unwrap();
+    fs::create_dir_all(&dir).unwrap();
+    write(&path, &head).unwrap();
+    // appends after the snapshot are only in the WAL
+    head.append(&series[2], 2500, 25.0).unwrap();
+    let late = Labels::from_pairs(&[("__name__", "late")]);
+    head.append(&late, 100, 1.0).unwrap();
+    let want: Vec<_> = head.all_series().iter().map(|s| (s.labels().clone(), s.snapshot().unwrap().iter().collect::<Vec<_>>())).collect();
+    head.close_wal().unwrap();
+
+    let restored = Head::with_samples_per_chunk(10).with_ooo_window(1000);
+    let stats = restore(&path, &wal_dir, &restored).unwrap();
+    assert_eq!(3, stats.snapshot_series);
+    assert_eq!((1, 2, 0), (stats.wal.series, stats.wal.samples, stats.wal.skipped));
+    let got: Vec<_> = restored.all_series().iter().map(|s| (s.labels().clone(), s.snapshot().unwrap().iter().collect::<Vec<_>>())).collect();
+    assert_eq!(want, got);
+    assert_eq!((0, 2500), (restored.min_time(), restored.max_time()));
+    assert_eq!(1, restored.series_by_labels(&series[1]).unwrap().snapshot().unwrap().ooo_samples().len());
+
+    // the open chunks keep taking samples, new series get new refs
+    let s0 = restored.series_by_labels(&series[0]).unwrap();
+    restored.append_ref(s0.series_ref(), 2000, 1.0).unwrap();
+    assert_eq!(3, s0.snapshot().unwrap().chunks().count());
+    restored.append(&series[2], 2600, 26.0).unwrap();
+    assert_eq!(27, restored.series_by_labels(&series[2]).unwrap().snapshot().unwrap().iter().count());
+    let other = restored.append(&Labels::from_pairs(&[("__name__", "other")]), 0, 0.0).unwrap();
+    assert!(restored.all_series().iter().all(|s| s.series_ref() <= other));
+
+    // without a snapshot the whole WAL is replayed
+    let replayed = Head::with_samples_per_chunk(10).with_ooo_window(1000);
+    let stats = restore(dir.join("missing"), &wal_dir, &replayed).unwrap();
+    assert_eq!((0, 4), (stats.snapshot_series, stats.wal.series));
+    let got: Vec<_> = replayed.all_series().iter().map(|s| (s.labels().clone(), s.snapshot().unwrap().iter().collect::<Vec<_>>())).collect();
+    assert_eq!(want, got);
+
+    let mut b = fs::read(&path).unwrap();
+    b[20] ^= 1;
+    fs::write(&path, b).unwrap();
+    let empty = Head::new();
+    assert!(matches!(restore(&path, &wal_dir, &empty), Err(Error::Corrupted)));
+    assert_eq!(0, empty.num_series());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_snapshot_open_chunk_state() {
+    let dir = test_dir("snapshot-state");
+    let path = dir.join("snapshot");
+    let ls = Labels::from_pairs(&[("__name__", "m")]);
+    let head = Head::with_samples_per_chunk(100);
+    for t in 0..10 {
+        head.append(&ls, t * 100, t as f64).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    write(&path, &head).unwrap();
+    let snap = head.series_by_labels(&ls).unwrap().snapshot().unwrap();
+    let chunk = snap.chunks().next().unwrap();
+
+    // break every sample after the first one, which takes the header, a one
+    // byte varint and the value, into a varint that never ends: decoding the
+    // chunk fails, loading it with its state doesn't get that far
+    let mut b = fs::read(&path).unwrap();
+    let at = b.windows(chunk.byte_len()).position(|w| w == chunk.bytes()).unwrap();
+    b[at + 2 + 1 + 8..at + chunk.byte_len()].fill(0x80);
+    assert!(XORChunk::from_bytes(b[at..at + chunk.byte_len()].to_vec()).is_err());
+    let n = b.len() - 4;
+    let crc = crc32::checksum(&b[..n]);
+    b[n..].copy_from_slice(&crc.to_be_bytes());
+
+    let (_, series) = decode(&b).unwrap();
+    let got = &series[0].chunks[0];
+    assert_eq!(chunk.appender_state(), got.appender_state());
+    assert_eq!((0, 900), (got.min_time(), got.max_time()));
+    fs::remove_dir_all(&dir).unwrap();
+}
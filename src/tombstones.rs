@@ -0,0 +1,211 @@
+// Tombstones mark samples of series in a block as deleted. Blocks are
+// immutable, so deleting only writes the tombstones file of the block; the
+// samples are filtered out when they are read and dropped for good when the
+// block is compacted. The file is laid out like the one of a Prometheus block,
+//
+//   [magic u32 0x0130BA30][version u8 = 1]
+//   ([series ref uvarint][min_time varint][max_time varint])...
+//   [crc32c of the records u32]
+//
+// with a record per interval.
+use crate::bstream::{Bstream, BstreamReader};
+use crate::crc32;
+use crate::head::SeriesRef;
+use crate::xor::{Error, SampleIterator};
+use std::collections::BTreeMap;
+
+pub const TOMBSTONES_FILENAME: &str = "tombstones";
+
+// the magic of the Prometheus tombstones file
+const MAGIC: u32 = 0x0130BA30;
+const TOMBSTONES_V1: u8 = 1;
+const HEADER_LEN: usize = 5;
+
+// Interval is the time range [min_time, max_time], both inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub min_time: i64,
+    pub max_time: i64,
+}
+
+impl Interval {
+    pub fn new(min_time: i64, max_time: i64) -> Interval {
+        Interval { min_time, max_time }
+    }
+
+    pub fn contains(&self, t: i64) -> bool {
+        self.min_time <= t && t <= self.max_time
+    }
+}
+
+// Tombstones holds the deleted intervals of each series, sorted and without
+// overlaps.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tombstones {
+    series: BTreeMap<SeriesRef, Vec<Interval>>,
+}
+
+impl Tombstones {
+    pub fn new() -> Tombstones {
+        Tombstones::default()
+    }
+
+    // add marks the samples of sref in iv as deleted. Overlapping and
+    // adjacent intervals are merged.
+    pub fn add(&mut self, sref: SeriesRef, iv: Interval) {
+        if iv.min_time > iv.max_time {
+            return;
+        }
+        let list = self.series.entry(sref).or_default();
+        let mut merged = iv;
+        list.retain(|x| {
+            let touches = x.min_time <= merged.max_time.saturating_add(1) && merged.min_time <= x.max_time.saturating_add(1);
+            if touches {
+                merged.min_time = merged.min_time.min(x.min_time);
+                merged.max_time = merged.max_time.max(x.max_time);
+            }
+            !touches
+        });
+        let i = list.partition_point(|x| x.min_time < merged.min_time);
+        list.insert(i, merged);
+    }
+
+    // get returns the deleted intervals of sref, sorted.
+    pub fn get(&self, sref: SeriesRef) -> &[Interval] {
+        self.series.get(&sref).map_or(&[], |l| l.as_slice())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SeriesRef, &[Interval])> {
+        self.series.iter().map(|(sref, l)| (*sref, l.as_slice()))
+    }
+
+    // len returns the number of series with tombstones.
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut b = Bstream::new(Vec::new());
+        b.write_aligned_bytes(&MAGIC.to_be_bytes());
+        b.write_aligned_bytes(&[TOMBSTONES_V1]);
+        for (sref, list) in &self.series {
+            for iv in list {
+                b.write_uvarint(sref.0);
+                b.write_varint(iv.min_time);
+                b.write_varint(iv.max_time);
+            }
+        }
+        b.pad_to_byte();
+        let crc = crc32::checksum(&b.read_bytes()[HEADER_LEN..]);
+        b.write_aligned_bytes(&crc.to_be_bytes());
+        b.read_bytes().clone()
+    }
+
+    pub(crate) fn decode(b: &[u8]) -> Option<Tombstones> {
+        let (b, crc) = b.split_at(b.len().checked_sub(4)?);
+        if b.len() < HEADER_LEN || u32::from_be_bytes(b[..4].try_into().ok()?) != MAGIC || b[4] != TOMBSTONES_V1 {
+            return None;
+        }
+        if crc32::checksum(&b[HEADER_LEN..]) != u32::from_be_bytes(crc.try_into().ok()?) {
+            return None;
+        }
+        let mut br = BstreamReader::new(b);
+        br.seek(HEADER_LEN * 8).ok()?;
+        let mut t = Tombstones::new();
+        while br.position() < b.len() * 8 {
+            let sref = SeriesRef(br.read_uvarint().ok()?);
+            let min_time = br.read_varint().ok()?;
+            let max_time = br.read_varint().ok()?;
+            t.add(sref, Interval { min_time, max_time });
+        }
+        Some(t)
+    }
+}
+
+// DeletedIterator leaves out the samples of an iterator that are in one of
+// the deleted intervals, which have to be sorted.
+pub struct DeletedIterator<'a, I> {
+    it: I,
+    deleted: &'a [Interval],
+}
+
+impl<'a, I: SampleIterator> DeletedIterator<'a, I> {
+    pub fn new(it: I, deleted: &'a [Interval]) -> DeletedIterator<'a, I> {
+        DeletedIterator { it, deleted }
+    }
+}
+
+impl<I: SampleIterator> Iterator for DeletedIterator<'_, I> {
+    type Item = (i64, f64);
+
+    fn next(&mut self) -> Option<(i64, f64)> {
+        loop {
+            let (t, v) = self.it.next()?;
+            // samples come in order, so intervals behind t are done with
+            while self.deleted.first().is_some_and(|iv| iv.max_time < t) {
+                self.deleted = &self.deleted[1..];
+            }
+            if !self.deleted.first().is_some_and(|iv| iv.contains(t)) {
+                return Some((t, v));
+            }
+        }
+    }
+}
+
+impl<I: SampleIterator> SampleIterator for DeletedIterator<'_, I> {
+    fn err(&self) -> Option<&Error> {
+        self.it.err()
+    }
+}
+
+#[test]
+fn test_tombstones() {
+    use crate::xor::XORChunk;
+
+    let mut t = Tombstones::new();
+    let r = SeriesRef(3);
+    t.add(r, Interval::new(10, 20));
+    t.add(r, Interval::new(40, 50));
+    t.add(r, Interval::new(30, 35));
+    assert_eq!(&[Interval::new(10, 20), Interval::new(30, 35), Interval::new(40, 50)], t.get(r));
+    // adjacent and overlapping intervals are merged
+    t.add(r, Interval::new(21, 29));
+    t.add(r, Interval::new(33, 45));
+    assert_eq!(&[Interval::new(10, 50)], t.get(r));
+    t.add(SeriesRef(1), Interval::new(i64::MIN, i64::MAX));
+    t.add(SeriesRef(2), Interval::new(5, 4));
+    assert_eq!(2, t.len());
+    assert!(t.get(SeriesRef(2)).is_empty());
+
+    let b = t.encode();
+    assert_eq!(Some(t.clone()), Tombstones::decode(&b));
+    let mut bad = b.clone();
+    bad[6] ^= 1;
+    assert_eq!(None, Tombstones::decode(&bad));
+    assert_eq!(None, Tombstones::decode(&b[..3]));
+
+    // a record per interval, like Prometheus writes them
+    let mut t = Tombstones::new();
+    t.add(SeriesRef(1), Interval::new(-1, 2));
+    t.add(SeriesRef(1), Interval::new(10, 20));
+    t.add(SeriesRef(300), Interval::new(0, 64));
+    let b = t.encode();
+    let records = [1, 0x01, 0x04, 1, 0x14, 0x28, 0xac, 0x02, 0x00, 0x80, 0x01];
+    assert_eq!([0x01, 0x30, 0xba, 0x30, 1], b[..5]);
+    assert_eq!(records, b[5..b.len() - 4]);
+    assert_eq!(crc32::checksum(&records).to_be_bytes(), b[b.len() - 4..]);
+    assert_eq!(Some(t), Tombstones::decode(&b));
+
+    let mut c = XORChunk::new();
+    for ts in 0..100 {
+        c.append(ts, ts as f64).unwrap();
+    }
+    let deleted = [Interval::new(-5, 4), Interval::new(10, 89), Interval::new(95, 95)];
+    let got: Vec<_> = DeletedIterator::new(c.iterator(), &deleted).map(|(ts, _)| ts).collect();
+    assert_eq!(vec![5, 6, 7, 8, 9, 90, 91, 92, 93, 94, 96, 97, 98, 99], got);
+    assert_eq!(100, DeletedIterator::new(c.iterator(), &[]).count());
+}
@@ -0,0 +1,747 @@
+// The write-ahead log makes appends to the head durable. It is a directory of
+// numbered segments (00000000, 00000001, ...) holding a sequence of records:
+//
+//   [len u32][crc32c of payload u32][payload, len bytes]
+//
+// The first byte of a payload is the record type. A series record lists the
+// series created since the last one:
+//
+//   [1]([ref uvarint][labels, see Labels::encode])...
+//
+// A samples record lists samples, the first with its series ref and
+// timestamp, the others relative to the first one:
+//
+//   [2][ref uvarint][t varint][v f64 bits]([ref delta varint][t delta varint][v f64 bits])...
+//
+// A tombstones record lists samples deleted from the head, the intervals are
+// inclusive:
+//
+//   [3]([ref uvarint][min_time varint][max_time varint])...
+//
+// A write that was cut short by a crash leaves an incomplete record at the end
+// of the last segment, or a complete one whose payload didn't make it to disk
+// and fails the checksum. Replay skips it and Wal::open cuts it off. Any other
+// broken record is an error.
+use crate::bstream::{Bstream, BstreamReader};
+use crate::crc32;
+use crate::head::{self, Head, SeriesRef};
+use crate::labels::Labels;
+use crate::segment::segment_files;
+use crate::tombstones::Interval;
+use crate::xor;
+#[cfg(test)]
+use crate::test_dir;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_SEGMENT_SIZE: u64 = 128 * 1024 * 1024;
+
+const RECORD_HEADER_LEN: usize = 8;
+const RECORD_SERIES: u8 = 1;
+const RECORD_SAMPLES: u8 = 2;
+const RECORD_TOMBSTONES: u8 = 3;
+
+// SyncPolicy says when the WAL fsyncs the current segment. Segments are
+// always synced when they are finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // Always syncs after every record.
+    Always,
+    // Interval syncs after a record when the last sync is longer ago than the
+    // interval. There is no timer: records logged after the last sync stay
+    // unsynced until the next record comes along, Wal::sync or Wal::close
+    // (Head::sync_wal or Head::close_wal) is called. Call one of those when
+    // appends stop for a while.
+    Interval(Duration),
+    // Never leaves it to the OS, records are only written when the buffer is full.
+    Never,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> SyncPolicy {
+        SyncPolicy::Interval(Duration::from_secs(1))
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // a complete record with a wrong checksum or an invalid payload, or an
+    // incomplete one in a segment other than the last, at the given segment
+    // and offset
+    Corrupted(u32, u64),
+    // reading or rewriting the chunks of a series during replay failed
+    Chunk(xor::Error),
+    // replay found samples of a series no series record created
+    UnknownSeries(SeriesRef),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "wal: {}", err),
+            Error::Corrupted(seq, offset) => write!(f, "wal: corrupted record in segment {:08} at {}", seq, offset),
+            Error::Chunk(err) => write!(f, "wal: {}", err),
+            Error::UnknownSeries(r) => write!(f, "wal: samples of unknown series {}", r),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RefSample {
+    pub sref: SeriesRef,
+    pub t: i64,
+    pub v: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Series(Vec<(SeriesRef, Labels)>),
+    Samples(Vec<RefSample>),
+    Tombstones(Vec<(SeriesRef, Interval)>),
+}
+
+impl Record {
+    fn encode(&self) -> Vec<u8> {
+        let mut b = Bstream::new(Vec::new());
+        match self {
+            Record::Series(series) => {
+                b.write_byte(RECORD_SERIES);
+                for (sref, labels) in series {
+                    b.write_uvarint(sref.0);
+                    labels.encode(&mut b);
+                }
+            }
+            Record::Samples(samples) => {
+                b.write_byte(RECORD_SAMPLES);
+                if let Some(first) = samples.first() {
+                    b.write_uvarint(first.sref.0);
+                    b.write_varint(first.t);
+                    b.write_bits(first.v.to_bits(), 64);
+                    for s in &samples[1..] {
+                        b.write_varint(s.sref.0.wrapping_sub(first.sref.0) as i64);
+                        b.write_varint(s.t.wrapping_sub(first.t));
+                        b.write_bits(s.v.to_bits(), 64);
+                    }
+                }
+            }
+            Record::Tombstones(stones) => {
+                b.write_byte(RECORD_TOMBSTONES);
+                for (sref, iv) in stones {
+                    b.write_uvarint(sref.0);
+                    b.write_varint(iv.min_time);
+                    b.write_varint(iv.max_time);
+                }
+            }
+        }
+        b.pad_to_byte();
+        b.read_bytes().clone()
+    }
+
+    // decode returns None for a payload that isn't a valid record.
+    fn decode(payload: &[u8]) -> Option<Record> {
+        let mut br = BstreamReader::new(payload);
+        let end = payload.len() * 8;
+        match br.read_byte().ok()? {
+            RECORD_SERIES => {
+                let mut series = Vec::new();
+                while br.position() < end {
+                    let sref = SeriesRef(br.read_uvarint().ok()?);
+                    series.push((sref, Labels::decode(&mut br, payload)?));
+                }
+                Some(Record::Series(series))
+            }
+            RECORD_SAMPLES => {
+                let mut samples: Vec<RefSample> = Vec::new();
+                while br.position() < end {
+                    let s = match samples.first() {
+                        None => RefSample {
+                            sref: SeriesRef(br.read_uvarint().ok()?),
+                            t: br.read_varint().ok()?,
+                            v: f64::from_bits(br.read_bits(64).ok()?),
+                        },
+                        Some(first) => RefSample {
+                            sref: SeriesRef(first.sref.0.wrapping_add(br.read_varint().ok()? as u64)),
+                            t: first.t.wrapping_add(br.read_varint().ok()?),
+                            v: f64::from_bits(br.read_bits(64).ok()?),
+                        },
+                    };
+                    samples.push(s);
+                }
+                Some(Record::Samples(samples))
+            }
+            RECORD_TOMBSTONES => {
+                let mut stones = Vec::new();
+                while br.position() < end {
+                    let sref = SeriesRef(br.read_uvarint().ok()?);
+                    stones.push((sref, Interval::new(br.read_varint().ok()?, br.read_varint().ok()?)));
+                }
+                Some(Record::Tombstones(stones))
+            }
+            _ => None,
+        }
+    }
+}
+
+// segment_path returns the path of WAL segment seq in dir.
+pub fn segment_path(dir: &Path, seq: u32) -> PathBuf {
+    dir.join(format!("{:08}", seq))
+}
+
+// record returns the payload of the record at off, None if it is torn: the
+// segment ends before the record does, or the record ends the segment and its
+// checksum doesn't match because not all of it made it to disk. A wrong
+// checksum anywhere else is corruption.
+fn record(seq: u32, b: &[u8], off: usize) -> Result<Option<&[u8]>, Error> {
+    let Some(header) = b.get(off..off + RECORD_HEADER_LEN) else {
+        return Ok(None);
+    };
+    let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    if len == 0 || len > b.len() - off - RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+    let crc = u32::from_be_bytes(header[4..].try_into().unwrap());
+    let start = off + RECORD_HEADER_LEN;
+    let payload = &b[start..start + len];
+    if crc32::checksum(payload) != crc {
+        if start + len == b.len() {
+            return Ok(None);
+        }
+        return Err(Error::Corrupted(seq, off as u64));
+    }
+    Ok(Some(payload))
+}
+
+// read_segment calls f with every record of a segment in order.
+fn read_segment(seq: u32, path: &Path, is_last: bool, f: &mut impl FnMut(Record) -> Result<(), Error>) -> Result<(), Error> {
+    let b = fs::read(path)?;
+    let mut off = 0;
+    while off < b.len() {
+        let payload = match record(seq, &b, off)? {
+            Some(payload) => payload,
+            // a torn write, nothing of the segment after it was written. Only
+            // the last segment can end with one, Wal::open cuts it off before
+            // starting a new segment behind it.
+            None if is_last => return Ok(()),
+            None => return Err(Error::Corrupted(seq, off as u64)),
+        };
+        f(Record::decode(payload).ok_or(Error::Corrupted(seq, off as u64))?)?;
+        off += RECORD_HEADER_LEN + payload.len();
+    }
+    Ok(())
+}
+
+// read_records calls f with every record in the WAL in dir, oldest first.
+pub fn read_records(dir: impl AsRef<Path>, f: impl FnMut(Record) -> Result<(), Error>) -> Result<(), Error> {
+    read_records_from(dir, 0, f)
+}
+
+// read_records_from is like read_records but skips the segments before first.
+pub fn read_records_from(dir: impl AsRef<Path>, first: u32, mut f: impl FnMut(Record) -> Result<(), Error>) -> Result<(), Error> {
+    let dir = dir.as_ref();
+    if !dir.exists() {
+        return Ok(());
+    }
+    let segments = segment_files(dir)?;
+    let last = segments.last().map(|(seq, _)| *seq);
+    for (seq, path) in segments.into_iter().filter(|(seq, _)| *seq >= first) {
+        read_segment(seq, &path, Some(seq) == last, &mut f)?;
+    }
+    Ok(())
+}
+
+// ReplayStats counts what replay did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub series: usize,
+    pub samples: usize,
+    // samples not newer than the series they belong to or already in it
+    pub skipped: usize,
+    // samples dropped by tombstones
+    pub deleted: usize,
+}
+
+// replay appends everything in the WAL in dir to the head. The head mustn't
+// have a WAL yet, otherwise replayed samples are logged again.
+pub fn replay(dir: impl AsRef<Path>, head: &Head) -> Result<ReplayStats, Error> {
+    replay_from(dir, 0, head)
+}
+
+// replay_from is like replay but skips the segments before first.
+pub fn replay_from(dir: impl AsRef<Path>, first: u32, head: &Head) -> Result<ReplayStats, Error> {
+    let mut stats = ReplayStats::default();
+    read_records_from(dir, first, |rec| {
+        match rec {
+            Record::Series(series) => {
+                for (sref, labels) in series {
+                    head.create_with_ref(sref, &labels);
+                    stats.series += 1;
+                }
+            }
+            Record::Samples(samples) => {
+                for s in samples {
+                    // a crash during truncate leaves the samples it kept in
+                    // the WAL twice. Within the out of order window they
+                    // would be appended again, skip the ones the series has.
+                    if let Some(ms) = head.series(s.sref) {
+                        if s.t <= ms.max_time() && ms.contains(s.t, s.v).map_err(Error::Chunk)? {
+                            stats.skipped += 1;
+                            continue;
+                        }
+                    }
+                    match head.append_ref(s.sref, s.t, s.v) {
+                        Ok(()) => stats.samples += 1,
+                        Err(head::Error::Chunk(xor::Error::OutOfOrderSample(_))) => stats.skipped += 1,
+                        Err(head::Error::Chunk(err)) => return Err(Error::Chunk(err)),
+                        Err(head::Error::UnknownSeries(sref)) => return Err(Error::UnknownSeries(sref)),
+                        Err(head::Error::Wal(err)) => return Err(err),
+                    }
+                }
+            }
+            Record::Tombstones(stones) => {
+                for (sref, iv) in stones {
+                    if let Some(s) = head.series(sref) {
+                        stats.deleted += s.delete(iv.min_time, iv.max_time).map_err(Error::Chunk)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(stats)
+}
+
+// repair cuts a torn record off the end of a segment. Replay skips it in the
+// last segment but it would be corruption once another segment follows.
+fn repair(seq: u32, path: &Path) -> Result<(), Error> {
+    let b = fs::read(path)?;
+    let mut off = 0;
+    while let Some(payload) = record(seq, &b, off)? {
+        off += RECORD_HEADER_LEN + payload.len();
+    }
+    if off < b.len() {
+        OpenOptions::new().write(true).open(path)?.set_len(off as u64)?;
+    }
+    Ok(())
+}
+
+// Wal appends records to the segments of a directory. It never appends to a
+// segment that already exists but starts a new one behind it.
+pub struct Wal {
+    dir: PathBuf,
+    seq: u32,
+    f: Option<BufWriter<File>>,
+    size: u64, // size of the current segment
+    segment_size: u64,
+    sync_policy: SyncPolicy,
+    last_sync: Instant,
+}
+
+impl Wal {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Wal, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        // the first segment is 00000000
+        let seq = match segment_files(&dir)?.last() {
+            Some((seq, path)) => {
+                repair(*seq, path)?;
+                *seq
+            }
+            None => u32::MAX,
+        };
+        Ok(Wal {
+            dir,
+            seq,
+            f: None,
+            size: 0,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            sync_policy: SyncPolicy::default(),
+            last_sync: Instant::now(),
+        })
+    }
+
+    // with_segment_size sets the size after which a new segment is started.
+    pub fn with_segment_size(mut self, segment_size: u64) -> Wal {
+        self.segment_size = segment_size;
+        self
+    }
+
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Wal {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn log_series(&mut self, series: &[(SeriesRef, Labels)]) -> Result<(), Error> {
+        self.log(&Record::Series(series.to_vec()))
+    }
+
+    pub fn log_samples(&mut self, samples: &[RefSample]) -> Result<(), Error> {
+        self.log(&Record::Samples(samples.to_vec()))
+    }
+
+    pub fn log_tombstones(&mut self, stones: &[(SeriesRef, Interval)]) -> Result<(), Error> {
+        self.log(&Record::Tombstones(stones.to_vec()))
+    }
+
+    pub fn log(&mut self, rec: &Record) -> Result<(), Error> {
+        let payload = rec.encode();
+        let n = (RECORD_HEADER_LEN + payload.len()) as u64;
+        if self.f.is_none() || (self.size > 0 && self.size + n > self.segment_size) {
+            self.cut()?;
+        }
+        let f = self.f.as_mut().unwrap();
+        f.write_all(&(payload.len() as u32).to_be_bytes())?;
+        f.write_all(&crc32::checksum(&payload).to_be_bytes())?;
+        f.write_all(&payload)?;
+        self.size += n;
+        match self.sync_policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Interval(d) if self.last_sync.elapsed() >= d => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    // finish_segment syncs and closes the current segment, the next record
+    // starts a new one. It returns the last segment, None if there is none yet.
+    pub fn finish_segment(&mut self) -> Result<Option<u32>, Error> {
+        self.sync()?;
+        self.f = None;
+        Ok((self.seq != u32::MAX).then_some(self.seq))
+    }
+
+    // cut finishes the current segment and starts the next one.
+    fn cut(&mut self) -> Result<(), Error> {
+        self.sync()?;
+        let seq = self.seq.wrapping_add(1);
+        self.f = Some(BufWriter::new(File::create(segment_path(&self.dir, seq))?));
+        self.seq = seq;
+        self.size = 0;
+        Ok(())
+    }
+
+    // sync writes out buffered records and fsyncs the current segment.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        if let Some(f) = &mut self.f {
+            f.flush()?;
+            f.get_ref().sync_data()?;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    // close syncs what is still buffered, whatever the sync policy.
+    pub fn close(mut self) -> Result<(), Error> {
+        self.sync()
+    }
+
+    // truncate drops everything from the WAL that isn't needed anymore once
+    // the head has persisted all samples before mint: the series keep
+    // returns false for and all samples before mint. The rest is written to a
+    // new segment and the older segments are deleted. Tombstones are applied
+    // to the samples logged before them and not written again. A crash before
+    // the older segments are gone leaves the kept samples in the WAL twice,
+    // replay skips the second ones.
+    pub fn truncate(&mut self, mint: i64, keep: impl Fn(SeriesRef) -> bool) -> Result<(), Error> {
+        self.cut()?;
+        let old: Vec<_> = segment_files(&self.dir)?.into_iter().filter(|(seq, _)| *seq != self.seq).collect();
+        let mut series = Vec::new();
+        let mut samples = Vec::new();
+        for (seq, path) in &old {
+            read_segment(*seq, path, false, &mut |rec| {
+                match rec {
+                    Record::Series(s) => series.extend(s.into_iter().filter(|(sref, _)| keep(*sref))),
+                    Record::Samples(s) => samples.extend(s.into_iter().filter(|s| s.t >= mint && keep(s.sref))),
+                    Record::Tombstones(stones) => {
+                        let mut deleted: HashMap<SeriesRef, Vec<Interval>> = HashMap::new();
+                        for (sref, iv) in stones {
+                            deleted.entry(sref).or_default().push(iv);
+                        }
+                        samples.retain(|s| !deleted.get(&s.sref).is_some_and(|l| l.iter().any(|iv| iv.contains(s.t))));
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        if !series.is_empty() {
+            self.log(&Record::Series(series))?;
+        }
+        // keep records small enough to not stall appends on replay
+        for chunk in samples.chunks(10_000) {
+            self.log(&Record::Samples(chunk.to_vec()))?;
+        }
+        self.sync()?;
+        for (_, path) in old {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_record_encoding() {
+    let recs = [
+        Record::Series(vec![
+            (SeriesRef(1), Labels::from_pairs(&[("__name__", "up"), ("job", "api")])),
+            (SeriesRef(1 << 40), Labels::default()),
+        ]),
+        Record::Samples(vec![
+            RefSample { sref: SeriesRef(7), t: 1000, v: 1.5 },
+            RefSample { sref: SeriesRef(3), t: -5, v: f64::NAN },
+            RefSample { sref: SeriesRef(u64::MAX), t: i64::MAX, v: -0.0 },
+        ]),
+        Record::Samples(Vec::new()),
+        Record::Tombstones(vec![(SeriesRef(7), Interval::new(-10, 20)), (SeriesRef(u64::MAX), Interval::new(i64::MIN, i64::MAX))]),
+    ];
+    for rec in &recs {
+        let got = Record::decode(&rec.encode()).unwrap();
+        // NaN != NaN, compare the bits
+        assert_eq!(format!("{:?}", rec), format!("{:?}", got));
+    }
+    assert_eq!(None, Record::decode(&[9]));
+    assert_eq!(None, Record::decode(&[RECORD_SERIES, 1, 1, 5, b'a']));
+}
+
+#[test]
+fn test_wal_replay() {
+    let dir = test_dir("wal");
+    let up = Labels::from_pairs(&[("__name__", "up")]);
+    let down = Labels::from_pairs(&[("__name__", "down")]);
+    {
+        let wal = Wal::open(&dir).unwrap().with_segment_size(256).with_sync_policy(SyncPolicy::Never);
+        let head = Head::with_samples_per_chunk(10).with_wal(wal);
+        for i in 0..100 {
+            head.append(&up, i * 10, i as f64).unwrap();
+            if i % 3 == 0 {
+                head.append(&down, i * 10, -i as f64).unwrap();
+            }
+        }
+        assert!(head.append(&up, 0, 0.0).is_err());
+        head.close_wal().unwrap();
+    }
+    assert!(segment_files(&dir).unwrap().len() > 1);
+
+    // tear the last record
+    let (_, last) = segment_files(&dir).unwrap().pop().unwrap();
+    let b = fs::read(&last).unwrap();
+    fs::write(&last, &b[..b.len() - 3]).unwrap();
+
+    let head = Head::new();
+    let stats = replay(&dir, &head).unwrap();
+    assert_eq!(ReplayStats { series: 2, samples: 133, skipped: 0, deleted: 0 }, stats);
+    let up_s = head.series_by_labels(&up).unwrap();
+    let want: Vec<_> = (0..100).map(|i| (i * 10, i as f64)).collect();
+    assert_eq!(want, up_s.snapshot().unwrap().iter().collect::<Vec<_>>());
+    assert_eq!(33, head.series_by_labels(&down).unwrap().snapshot().unwrap().iter().count());
+
+    // a new series gets a ref behind the replayed ones
+    let other = head.append(&Labels::from_pairs(&[("__name__", "other")]), 0, 0.0).unwrap();
+    assert!(other > up_s.series_ref());
+
+    // opening the WAL cuts the torn record off before a new segment follows
+    let mut wal = Wal::open(&dir).unwrap();
+    wal.log_samples(&[RefSample { sref: up_s.series_ref(), t: 1000, v: 100.0 }]).unwrap();
+    wal.close().unwrap();
+    let stats = replay(&dir, &Head::new()).unwrap();
+    assert_eq!((133 + 1, 0), (stats.samples, stats.skipped));
+
+    // only the last segment may end with an incomplete record
+    let (seq, first) = segment_files(&dir).unwrap().remove(0);
+    let mut b = fs::read(&first).unwrap();
+    fs::write(&first, &b[..b.len() - 3]).unwrap();
+    assert!(matches!(replay(&dir, &Head::new()), Err(Error::Corrupted(s, off)) if s == seq && off > 0));
+
+    // corrupt a record in the middle
+    b[RECORD_HEADER_LEN + 1] ^= 0xff;
+    fs::write(&first, b).unwrap();
+    assert!(matches!(replay(&dir, &Head::new()), Err(Error::Corrupted(s, 0)) if s == seq));
+    fs::remove_dir_all(&dir).unwrap();
+
+    // samples of a series that was never logged are an error, not skipped
+    let mut wal = Wal::open(&dir).unwrap();
+    wal.log_samples(&[RefSample { sref: SeriesRef(42), t: 0, v: 0.0 }]).unwrap();
+    wal.close().unwrap();
+    assert!(matches!(replay(&dir, &Head::new()), Err(Error::UnknownSeries(SeriesRef(42)))));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_repair() {
+    let dir = test_dir("wal-repair");
+    let rec = |t| Record::Samples(vec![RefSample { sref: SeriesRef(1), t, v: 0.0 }]);
+    let mut wal = Wal::open(&dir).unwrap();
+    for t in 0..3 {
+        wal.log(&rec(t)).unwrap();
+    }
+    wal.close().unwrap();
+    let (seq, path) = segment_files(&dir).unwrap().pop().unwrap();
+    let good = fs::read(&path).unwrap();
+    let rec_len = good.len() / 3;
+
+    // the last record has its length but not its payload
+    let mut b = good.clone();
+    b[2 * rec_len + RECORD_HEADER_LEN..].fill(0);
+    fs::write(&path, &b).unwrap();
+    let mut n = 0;
+    read_records(&dir, |_| {
+        n += 1;
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(2, n);
+    Wal::open(&dir).unwrap();
+    assert_eq!(2 * rec_len, fs::read(&path).unwrap().len());
+
+    // a broken record followed by others isn't cut off
+    let mut b = good;
+    b[rec_len + RECORD_HEADER_LEN] ^= 0xff;
+    fs::write(&path, &b).unwrap();
+    assert!(matches!(Wal::open(&dir), Err(Error::Corrupted(s, off)) if s == seq && off == rec_len as u64));
+    assert_eq!(b, fs::read(&path).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_append_batch() {
+    let dir = test_dir("wal-batch");
+    let head = Head::new().with_wal(Wal::open(&dir).unwrap().with_sync_policy(SyncPolicy::Never));
+    let a = head.append(&Labels::from_pairs(&[("__name__", "a")]), 0, 0.0).unwrap();
+    let b = head.append(&Labels::from_pairs(&[("__name__", "b")]), 0, 0.0).unwrap();
+    let batch: Vec<_> = (1..100).map(|i| RefSample { sref: if i % 2 == 0 { a } else { b }, t: i * 10, v: i as f64 }).collect();
+    assert_eq!(99, head.append_batch(&batch).unwrap());
+    // rejected samples aren't logged
+    assert_eq!(0, head.append_batch(&[RefSample { sref: a, t: 0, v: 1.0 }]).unwrap());
+    head.close_wal().unwrap();
+
+    let mut n = 0;
+    read_records(&dir, |rec| {
+        if let Record::Samples(s) = rec {
+            n += 1;
+            assert!(s.len() == 1 || s == batch);
+        }
+        Ok(())
+    })
+    .unwrap();
+    // one record for each of the two appends and one for the batch
+    assert_eq!(3, n);
+    let stats = replay(&dir, &Head::new()).unwrap();
+    assert_eq!(ReplayStats { series: 2, samples: 101, skipped: 0, deleted: 0 }, stats);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_truncate() {
+    let dir = test_dir("wal-truncate");
+    let a = Labels::from_pairs(&[("__name__", "a")]);
+    let b = Labels::from_pairs(&[("__name__", "b")]);
+    let mut wal = Wal::open(&dir).unwrap().with_segment_size(128).with_sync_policy(SyncPolicy::Always);
+    wal.log_series(&[(SeriesRef(1), a.clone()), (SeriesRef(2), b)]).unwrap();
+    for t in 0..50 {
+        wal.log_samples(&[RefSample { sref: SeriesRef(1), t, v: 1.0 }, RefSample { sref: SeriesRef(2), t, v: 2.0 }])
+            .unwrap();
+    }
+    let before = segment_files(&dir).unwrap();
+    wal.truncate(40, |r| r == SeriesRef(1)).unwrap();
+    wal.log_samples(&[RefSample { sref: SeriesRef(1), t: 50, v: 1.0 }]).unwrap();
+    wal.close().unwrap();
+
+    let after = segment_files(&dir).unwrap();
+    assert!(after.iter().all(|s| !before.contains(s)));
+    let mut recs = Vec::new();
+    read_records(&dir, |rec| {
+        recs.push(rec);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(Record::Series(vec![(SeriesRef(1), a)]), recs[0]);
+    let ts: Vec<_> = recs[1..]
+        .iter()
+        .flat_map(|r| match r {
+            Record::Samples(s) => s.iter().map(|s| (s.sref, s.t)).collect(),
+            Record::Series(_) | Record::Tombstones(_) => Vec::new(),
+        })
+        .collect();
+    assert_eq!((40..=50).map(|t| (SeriesRef(1), t)).collect::<Vec<_>>(), ts);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_tombstones() {
+    let dir = test_dir("wal-tombstones");
+    let a = Labels::from_pairs(&[("__name__", "a")]);
+    let b = Labels::from_pairs(&[("__name__", "b")]);
+    let m = [crate::index::Matcher::new(crate::index::MatchType::Equal, "__name__", "a").unwrap()];
+    let wal = Wal::open(&dir).unwrap().with_sync_policy(SyncPolicy::Never);
+    let head = Head::with_samples_per_chunk(10).with_wal(wal);
+    for t in 0..50 {
+        head.append(&a, t * 10, t as f64).unwrap();
+        head.append(&b, t * 10, t as f64).unwrap();
+    }
+    assert_eq!(0, head.delete(&m, 1000, 2000).unwrap());
+    assert_eq!(1, head.delete(&m, 100, 199).unwrap());
+    let want: Vec<_> = (0..50).filter(|t| !(10..20).contains(t)).map(|t| (t * 10, t as f64)).collect();
+    assert_eq!(want, head.series_by_labels(&a).unwrap().snapshot().unwrap().iter().collect::<Vec<_>>());
+    assert_eq!(50, head.series_by_labels(&b).unwrap().snapshot().unwrap().iter().count());
+    head.close_wal().unwrap();
+
+    let replayed = Head::with_samples_per_chunk(10);
+    let stats = replay(&dir, &replayed).unwrap();
+    assert_eq!(ReplayStats { series: 2, samples: 100, skipped: 0, deleted: 10 }, stats);
+    assert_eq!(want, replayed.series_by_labels(&a).unwrap().snapshot().unwrap().iter().collect::<Vec<_>>());
+
+    // truncating drops the deleted samples instead of bringing them back
+    let mut wal = Wal::open(&dir).unwrap();
+    wal.truncate(0, |_| true).unwrap();
+    wal.close().unwrap();
+    let truncated = Head::with_samples_per_chunk(10);
+    let stats = replay(&dir, &truncated).unwrap();
+    assert_eq!((90, 0), (stats.samples, stats.deleted));
+    assert_eq!(want, truncated.series_by_labels(&a).unwrap().snapshot().unwrap().iter().collect::<Vec<_>>());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_truncate_crash() {
+    let dir = test_dir("wal-truncate-crash");
+    let a = Labels::from_pairs(&[("__name__", "a")]);
+    let wal = Wal::open(&dir).unwrap().with_sync_policy(SyncPolicy::Never);
+    let head = Head::with_samples_per_chunk(10).with_ooo_window(1000).with_wal(wal);
+    for t in 0..50 {
+        head.append(&a, t * 10, t as f64).unwrap();
+    }
+    head.append(&a, 405, -1.0).unwrap();
+    let want: Vec<_> = head.series_by_labels(&a).unwrap().snapshot().unwrap().iter().collect();
+    head.sync_wal().unwrap();
+
+    // crash after the kept samples are written but before the old segments are deleted
+    let old: Vec<_> = segment_files(&dir).unwrap().into_iter().map(|(_, path)| (fs::read(&path).unwrap(), path)).collect();
+    head.truncate_wal(200).unwrap();
+    head.close_wal().unwrap();
+    for (b, path) in old {
+        fs::write(path, b).unwrap();
+    }
+
+    let replayed = Head::with_samples_per_chunk(10).with_ooo_window(1000);
+    let stats = replay(&dir, &replayed).unwrap();
+    // 30 samples from 200 on and the out of order one are in the WAL twice
+    assert_eq!(ReplayStats { series: 2, samples: 51, skipped: 31, deleted: 0 }, stats);
+    let snap = replayed.series_by_labels(&a).unwrap().snapshot().unwrap();
+    assert_eq!(want, snap.iter().collect::<Vec<_>>());
+    assert_eq!(&[(405, -1.0)], snap.ooo_samples());
+    fs::remove_dir_all(&dir).unwrap();
+}
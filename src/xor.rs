@@ -1,8 +1,225 @@
-use crate::bstream::{Bstream,BstreamReader};
-use std::io;
-use rand::Rng;
+use crate::bstream::{Bstream,BstreamError,BstreamReader};
+use crate::footer::{encode_footer,Footer,IndexEntry,SeekIndex};
+use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+#[cfg(test)]
+use alloc::vec;
+use core::fmt;
+use core::ops::Deref;
+
+// The top bit of the 2 byte chunk header marks a sealed chunk, which carries
+// a footer behind its samples (see footer.rs). The other bits are the number
+// of samples.
+const HEADER_SEALED: u16 = 1 << 15;
+const HEADER_NUM_MASK: u16 = HEADER_SEALED - 1;
+
+// MAX_SAMPLES is the most samples a chunk holds.
+pub const MAX_SAMPLES: u16 = HEADER_NUM_MASK;
+
+// is_sealed says whether the chunk with the given bytes is sealed, without
+// loading it.
+pub fn is_sealed(bytes:&[u8]) -> bool {
+    bytes.len() >= 2 && u16::from_be_bytes([bytes[0],bytes[1]]) & HEADER_SEALED != 0
+}
+
+// footer returns the footer of the chunk with the given bytes, an empty one if
+// it isn't sealed.
+fn footer(bytes:&[u8]) -> Result<Footer<'_>, Error> {
+    if !is_sealed(bytes) {
+        return Ok(Footer::default());
+    }
+    Footer::parse(bytes)
+}
+
+// seek returns an iterator over the chunk with the given bytes whose next
+// sample is the first one with a timestamp >= t, see XORChunk::seek.
+fn seek(bytes:&[u8], t:i64) -> XORIterator<'_> {
+    let mut it = XORIterator::new(bytes);
+    match footer(bytes) {
+        Ok(footer) => {
+            if let Some(entry) = footer.index.and_then(|index| index.search(t)) {
+                it.restore(&entry);
+            }
+        },
+        Err(err) => {
+            it.err = Some(err);
+            return it;
+        }
+    }
+    while let Some((ts, _)) = it.next() {
+        if ts >= t {
+            // hand it out again instead of decoding it twice
+            it.held = true;
+            break;
+        }
+    }
+    it
+}
+
+// scan decodes all samples of a chunk that isn't sealed to recover its meta
+// and the state of its appender.
+fn scan(bytes:&[u8]) -> Result<(ChunkMeta, AppenderState), Error> {
+    let mut meta = ChunkMeta::default();
+    let mut it = XORIterator::new(bytes);
+    for (t, _) in &mut it {
+        meta.min_time = meta.min_time.min(t);
+        meta.max_time = meta.max_time.max(t);
+    }
+    if let Some(err) = it.err {
+        return Err(err);
+    }
+    meta.bit_len = it.br.position();
+    if it.num_total == 0 {
+        return Ok((meta, AppenderState::default()));
+    }
+    let state = AppenderState {
+        num_samples: it.num_total,
+        bit_len: meta.bit_len as u32,
+        t: it.t,
+        v: it.val,
+        t_delta: it.t_delta,
+        leading: it.leading,
+        trailing: it.trailing,
+    };
+    Ok((meta, state))
+}
+
+// SealOptions says what goes into the footer of a sealed chunk.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SealOptions {
+    // index_interval adds a seek index with an entry every that many samples.
+    pub index_interval: Option<u16>,
+    // aggregates adds the Aggregates of all samples.
+    pub aggregates: bool,
+}
+
+// ChunkMeta is what a chunk knows about its samples without decoding them.
+// The time range of an empty chunk is [i64::MAX, i64::MIN].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkMeta {
+    pub min_time: i64,
+    pub max_time: i64,
+    pub bit_len: usize, // bits used by the header and the samples
+}
+
+impl Default for ChunkMeta {
+    fn default() -> ChunkMeta {
+        ChunkMeta {
+            min_time: i64::MAX,
+            max_time: i64::MIN,
+            bit_len: 16,
+        }
+    }
+}
+
+// Aggregates summarises the values of a run of samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aggregates {
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: u32,
+    pub first: f64,
+    pub last: f64,
+}
+
+impl Aggregates {
+    pub fn new(v:f64) -> Aggregates {
+        Aggregates {
+            min: v,
+            max: v,
+            sum: v,
+            count: 1,
+            first: v,
+            last: v,
+        }
+    }
+
+    // add adds the value of the sample after the ones aggregated so far.
+    pub fn add(&mut self, v:f64) {
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+        self.sum += v;
+        self.count += 1;
+        self.last = v;
+    }
+}
+
+// AppenderState is what the appender carries over from one sample to the next.
+// The chunk caches it so appenders resume without decoding, and it can be
+// stored next to a partially filled chunk to load that without decoding too.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AppenderState {
+    pub num_samples: u16, // samples in the chunk when the state was taken
+    pub bit_len: u32,     // bits used by the header and the samples
+    pub t: i64,
+    pub v: f64,
+    pub t_delta: u64,
+    pub leading: u8,
+    pub trailing: u8,
+}
+
+impl Default for AppenderState {
+    fn default() -> AppenderState {
+        AppenderState {
+            num_samples: 0,
+            bit_len: 16,
+            t: 0,
+            v: 0.0,
+            t_delta: 0,
+            leading: 0xff, // no leading/trailing zero bits to reuse yet
+            trailing: 0,
+        }
+    }
+}
+
+impl AppenderState {
+    pub const ENCODED_LEN: usize = 32;
+
+    // encode returns the state as ENCODED_LEN bytes, big endian.
+    pub fn encode(&self) -> [u8; AppenderState::ENCODED_LEN] {
+        let mut buf = [0; AppenderState::ENCODED_LEN];
+        buf[0..2].copy_from_slice(&self.num_samples.to_be_bytes());
+        buf[2..6].copy_from_slice(&self.bit_len.to_be_bytes());
+        buf[6..14].copy_from_slice(&self.t.to_be_bytes());
+        buf[14..22].copy_from_slice(&self.v.to_bits().to_be_bytes());
+        buf[22..30].copy_from_slice(&self.t_delta.to_be_bytes());
+        buf[30] = self.leading;
+        buf[31] = self.trailing;
+        buf
+    }
+
+    pub fn decode(b:&[u8]) -> Result<AppenderState, Error> {
+        if b.len() != AppenderState::ENCODED_LEN {
+            return Err(Error::InvalidAppenderState);
+        }
+        Ok(AppenderState {
+            num_samples: u16::from_be_bytes([b[0], b[1]]),
+            bit_len: u32::from_be_bytes(b[2..6].try_into().unwrap()),
+            t: i64::from_be_bytes(b[6..14].try_into().unwrap()),
+            v: f64::from_bits(u64::from_be_bytes(b[14..22].try_into().unwrap())),
+            t_delta: u64::from_be_bytes(b[22..30].try_into().unwrap()),
+            leading: b[30],
+            trailing: b[31],
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct XORChunk {
-    b:Bstream
+    // shared with snapshots, appends copy it only while a snapshot holds it
+    b:Arc<Bstream>,
+    // both kept up to date by append
+    meta:ChunkMeta,
+    state:AppenderState,
+}
+
+impl Default for XORChunk {
+    fn default() -> XORChunk {
+        XORChunk::new()
+    }
 }
 
 impl XORChunk {
@@ -10,161 +227,516 @@ impl XORChunk {
         let mut stream = Vec::with_capacity(128);
         stream.resize(2,0); 
         XORChunk {
-            b: Bstream::new(stream)
+            b: Arc::new(Bstream::new(stream)),
+            meta: ChunkMeta::default(),
+            state: AppenderState::default(),
         }
     }
 
+    // from_bytes loads a chunk from its bytes. The metadata of a sealed chunk
+    // is read from its footer, any other chunk is decoded once to recover it.
+    pub fn from_bytes(bytes:Vec<u8>) -> Result<XORChunk, Error> {
+        if bytes.len() < 2 {
+            return Err(Error::Bstream(BstreamError::UnexpectedEof));
+        }
+        if u16::from_be_bytes([bytes[0],bytes[1]]) & HEADER_SEALED != 0 {
+            let meta = Footer::parse(&bytes)?.meta.ok_or(Error::InvalidFooter)?;
+            return Ok(XORChunk {
+                b: Arc::new(Bstream::new(bytes)),
+                meta,
+                state: AppenderState::default(),
+            });
+        }
+
+        let (meta, state) = scan(&bytes)?;
+        Ok(XORChunk {
+            // drop whatever follows the samples, so appends continue right behind them
+            b: Arc::new(Bstream::from_bits(bytes, meta.bit_len)),
+            meta,
+            state,
+        })
+    }
+
+    // from_bytes_with_state loads a partially filled chunk together with the
+    // appender state that was stored with it, which only decodes the first
+    // sample. If the state doesn't belong to the chunk it falls back to from_bytes.
+    pub fn from_bytes_with_state(bytes:Vec<u8>, state:AppenderState) -> Result<XORChunk, Error> {
+        if bytes.len() < 2 {
+            return Err(Error::Bstream(BstreamError::UnexpectedEof));
+        }
+        let header = u16::from_be_bytes([bytes[0],bytes[1]]);
+        if header & HEADER_SEALED != 0 || header != state.num_samples || state.bit_len as usize > bytes.len()*8 {
+            return XORChunk::from_bytes(bytes);
+        }
+        let mut meta = ChunkMeta::default();
+        if state.num_samples > 0 {
+            let mut it = XORIterator::new(&bytes);
+            match it.next() {
+                Some((t, _)) => meta.min_time = t,
+                None => return Err(it.err.unwrap_or(Error::Bstream(BstreamError::UnexpectedEof))),
+            }
+            meta.max_time = state.t;
+        }
+        meta.bit_len = state.bit_len as usize;
+        Ok(XORChunk {
+            b: Arc::new(Bstream::from_bits(bytes, meta.bit_len)),
+            meta,
+            state,
+        })
+    }
+
+    // appender_state returns the state the next append starts from. Store it
+    // next to the bytes of a partially filled chunk for from_bytes_with_state.
+    pub fn appender_state(&self) -> AppenderState {
+        self.state
+    }
+
+    // snapshot returns an immutable copy of the chunk as it is now. It shares
+    // the bytes with the chunk, so it costs the same whatever the chunk size.
+    pub fn snapshot(&self) -> ChunkSnapshot {
+        ChunkSnapshot { chunk: self.clone() }
+    }
+
     pub fn encoding() -> String {
         String::from("XOR")
     }
 
-    fn bytes(&self) -> &[u8] {
+    pub fn bytes(&self) -> &[u8] {
         self.b.read_bytes()
     }
 
-    pub fn iterator(&self) -> XORIterator {
+    pub fn iterator(&self) -> XORIterator<'_> {
         XORIterator::new(self.bytes())
     }
-    pub fn appender(&mut self) -> Result<XORAppender,Error>{
-        // To get an appender we must know the state it would have if we had
-	    // appended all existing data from scratch.
-	    // We iterate through the end and populate via the iterator's state.
-        let (num,state) = {
-            let bytes = self.b.bytes();
-            let num = u16::from_be_bytes([bytes[0],bytes[1]]);
-            let mut it = XORIterator::new(bytes);
-            for _ in &mut it {}
-        
+
+    // iterator_reuse is like iterator but recycles it when given one, which
+    // saves setting up a new iterator for every chunk in hot query paths.
+    pub fn iterator_reuse<'a>(&'a self, it:Option<XORIterator<'a>>) -> XORIterator<'a> {
+        match it {
+            Some(mut it) => {
+                it.reset(self.bytes());
+                it
+            },
+            None => self.iterator(),
+        }
+    }
+
+    pub fn meta(&self) -> ChunkMeta {
+        self.meta
+    }
+
+    pub fn num_samples(&self) -> u16 {
+        let bytes = self.bytes();
+        u16::from_be_bytes([bytes[0],bytes[1]]) & HEADER_NUM_MASK
+    }
+
+    // min_time returns the timestamp of the first sample, i64::MAX if there is none.
+    pub fn min_time(&self) -> i64 {
+        self.meta.min_time
+    }
+
+    // max_time returns the timestamp of the last sample, i64::MIN if there is none.
+    pub fn max_time(&self) -> i64 {
+        self.meta.max_time
+    }
+
+    // byte_len returns the size of the chunk in bytes, footer included.
+    pub fn byte_len(&self) -> usize {
+        self.bytes().len()
+    }
+
+    // bit_len returns the number of bits used by the header and the samples.
+    pub fn bit_len(&self) -> usize {
+        self.meta.bit_len
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        is_sealed(self.bytes())
+    }
+
+    // footer returns the footer of a sealed chunk. Chunks that aren't sealed
+    // have an empty one.
+    pub fn footer(&self) -> Result<Footer<'_>, Error> {
+        footer(self.bytes())
+    }
+
+    // seal writes the footer described by opts behind the samples. No more
+    // samples can be appended to a sealed chunk.
+    pub fn seal(&mut self, opts:SealOptions) -> Result<(), Error> {
+        if self.is_sealed() {
+            return Err(Error::ChunkSealed);
+        }
+        let interval = opts.index_interval.filter(|i| *i > 0);
+        let mut entries = Vec::new();
+        let mut aggs: Option<Aggregates> = None;
+        if interval.is_some() || opts.aggregates {
+            let mut it = self.iterator();
+            loop {
+                if let Some(interval) = interval {
+                    if it.num_read > 0 && it.num_read.is_multiple_of(interval) {
+                        entries.push(it.state());
+                    }
+                }
+                let Some((_, v)) = it.next() else {
+                    break
+                };
+                match aggs.as_mut() {
+                    Some(a) => a.add(v),
+                    None => aggs = Some(Aggregates::new(v)),
+                }
+            }
             if let Some(err) = it.err {
                 return Err(err);
             }
-            (num, (it.t, it.val, it.t_delta, it.leading, it.trailing))
-        };
-        let (t, val, t_delta, leading, trailing) = state;
-        let mut a = XORAppender {
-            b: &mut self.b,
-            t,
-            v: val,
-            t_delta,
-            leading,
-            trailing,
-        };
-        if num == 0 {
-            a.leading = 0xff;
         }
-        Ok(a)
+        if !opts.aggregates {
+            aggs = None;
+        }
+
+        let footer = encode_footer(
+            interval.map(|i| (i, entries.as_slice())),
+            Some(&self.meta),
+            aggs.as_ref(),
+        );
+        let bytes = self.bytes();
+        let header = u16::from_be_bytes([bytes[0],bytes[1]]) | HEADER_SEALED;
+        let b = Arc::make_mut(&mut self.b);
+        b.write_aligned_bytes(&footer);
+        let [byt1,byt2] = header.to_be_bytes();
+        b.modify_first_two_bytes(byt1, byt2);
+        Ok(())
     }
-}
 
-struct XORAppender<'a> {
-    b:&'a mut Bstream,
+    // seek returns an iterator whose next sample is the first one with a
+    // timestamp >= t. Sealed chunks with a seek index resume decoding from the
+    // closest index entry instead of the start of the chunk.
+    pub fn seek(&self, t:i64) -> XORIterator<'_> {
+        seek(self.bytes(), t)
+    }
 
-    t:i64, // starting time stamp
-    v:f64,
+    // iter_rev returns an iterator over the samples newest first.
+    pub fn iter_rev(&self) -> XORReverseIterator<'_> {
+        XORReverseIterator::new(self)
+    }
 
-    t_delta:u64,
+    // aggregate returns the Aggregates of the samples with mint <= t <= maxt,
+    // None if there are none. When the range covers the whole chunk the
+    // aggregates of the footer are used and nothing is decoded.
+    pub fn aggregate(&self, mint:i64, maxt:i64) -> Result<Option<Aggregates>, Error> {
+        if mint <= self.meta.min_time && self.meta.max_time <= maxt {
+            if let Some(aggs) = self.footer()?.aggregates {
+                return Ok(Some(aggs));
+            }
+        }
+        let mut it = self.iter_range(mint, maxt);
+        let mut aggs: Option<Aggregates> = None;
+        for (_, v) in &mut it {
+            match aggs.as_mut() {
+                Some(a) => a.add(v),
+                None => aggs = Some(Aggregates::new(v)),
+            }
+        }
+        if let Some(err) = it.err() {
+            return Err(err.clone());
+        }
+        Ok(aggs)
+    }
 
-    leading:u8,
-    trailing: u8,
-}
+    // iter_range returns an iterator over the samples with mint <= t <= maxt.
+    pub fn iter_range(&self, mint:i64, maxt:i64) -> XORRangeIterator<'_> {
+        XORRangeIterator::new(self.seek(mint), mint, maxt)
+    }
+
+    // decode_into appends every sample of the chunk to ts and vs and returns
+    // the number of samples decoded. It avoids the per-sample bookkeeping of
+    // iterator(), which makes it the faster way to get at all the data.
+    pub fn decode_into(&self, ts:&mut Vec<i64>, vs:&mut Vec<f64>) -> Result<usize, Error> {
+        self.iterator().decode_into(i64::MIN, i64::MAX, ts, vs)
+    }
+
+    // decode_range_into is like decode_into but only keeps samples with
+    // mint <= t <= maxt. Decoding stops at the first sample after maxt.
+    pub fn decode_range_into(&self, mint:i64, maxt:i64, ts:&mut Vec<i64>, vs:&mut Vec<f64>) -> Result<usize, Error> {
+        self.seek(mint).decode_into(mint, maxt, ts, vs)
+    }
 
-impl<'a> XORAppender<'a> {
-    pub fn Append(&mut self, t:i64, v:f64) {
-        let mut t_delta:u64 = 0;
-        let bytes = self.b.bytes();
+    // append adds a sample behind the last one. The chunk keeps the encoder
+    // state, so nothing has to be decoded first.
+    pub fn append(&mut self, t:i64, v:f64) -> Result<(), Error> {
+        if self.is_sealed() {
+            return Err(Error::ChunkSealed);
+        }
+        let bytes = self.bytes();
         let num = u16::from_be_bytes([bytes[0],bytes[1]]);
+        // one more would set the sealed bit
+        if num == HEADER_NUM_MASK {
+            return Err(Error::ChunkFull);
+        }
+        // the deltas are unsigned, an earlier timestamp would wrap around
+        if num > 0 && t <= self.state.t {
+            return Err(Error::OutOfOrderSample(t));
+        }
+        // the decoder adds the delta to an i64, so it has to fit one
+        let t_delta = match num {
+            0 => 0,
+            _ => t.checked_sub(self.state.t).ok_or(Error::TimestampOverflow(t))? as u64,
+        };
+        let b = Arc::make_mut(&mut self.b);
         if num == 0 {
-            self.b.write_varint(t);
-            self.b.write_bits(v.to_bits(),64);
+            b.write_varint(t);
+            b.write_bits(v.to_bits(),64);
         } else if num == 1 {
-            t_delta = (t - self.t) as u64;
-            self.b.write_uvarint(t_delta);
-            self.write_v_delta(v);
+            b.write_uvarint(t_delta);
+            write_v_delta(b, &mut self.state, v);
         } else {
-            t_delta = (t- self.t) as u64;
-            //let dod = (t_delta - self.t_delta) as i64;
-            let dod = t_delta.wrapping_sub(self.t_delta) as i64;
+            //let dod = (t_delta - self.state.t_delta) as i64;
+            let dod = t_delta.wrapping_sub(self.state.t_delta) as i64;
             // Gorilla has a max resolution of seconds, Prometheus milliseconds.
 		    // Thus we use higher value range steps with larger bit size.
             match dod {
                 0 => {
-                    self.b.write_bit(false);
+                    b.write_bit(false);
                 },
                 dod if bit_range(dod,14) => {
-                    self.b.write_bits(0b10,2);
-                    self.b.write_bits(dod as u64,14);
+                    b.write_bits(0b10,2);
+                    b.write_bits(dod as u64,14);
                 },
                 dod if bit_range(dod,17) => {
-                    self.b.write_bits(0b110,3);
-                    self.b.write_bits(dod as u64,17);
+                    b.write_bits(0b110,3);
+                    b.write_bits(dod as u64,17);
                 },
                 dod if bit_range(dod,20) => {
-                    self.b.write_bits(0b1110,4);
-                    self.b.write_bits(dod as u64,20);
+                    b.write_bits(0b1110,4);
+                    b.write_bits(dod as u64,20);
                 }
                 _ => {
-                    self.b.write_bits(0b1111,4);
-                    self.b.write_bits(dod as u64,64);
+                    b.write_bits(0b1111,4);
+                    b.write_bits(dod as u64,64);
                 }
             }
-            self.write_v_delta(v);
+            write_v_delta(b, &mut self.state, v);
         }
 
-        self.t = t;
-        self.v = v;
+        self.state.t = t;
+        self.state.v = v;
         // update num
         let [byt1,byt2] = u16::to_be_bytes(num +1);
-        self.b.modify_first_two_bytes(byt1, byt2);
-        self.t_delta = t_delta;
+        b.modify_first_two_bytes(byt1, byt2);
+        self.state.t_delta = t_delta;
+
+        self.state.num_samples = num + 1;
+        self.state.bit_len = b.bit_len() as u32;
+
+        self.meta.min_time = self.meta.min_time.min(t);
+        self.meta.max_time = self.meta.max_time.max(t);
+        self.meta.bit_len = b.bit_len();
+        Ok(())
     }
 
-    pub fn write_v_delta(&mut self, v:f64) {
-        let v_delta = v.to_bits() ^ self.v.to_bits();
-        if v_delta == 0 {
-            self.b.write_bit(false);
-            return;
-        }
-        // otherwise, write a '1' anyway
-        self.b.write_bit(true);
+    // appender is what samples were appended through before the chunk kept
+    // the appender state itself. It is left for existing callers.
+    #[deprecated(note = "use XORChunk::append")]
+    #[allow(deprecated)]
+    pub fn appender(&mut self) -> Result<XORAppender<'_>, Error> {
+        Ok(XORAppender { chunk: self })
+    }
+}
 
-        let mut leading = v_delta.leading_zeros() as u8;
-        let trailing = v_delta.trailing_zeros() as u8;
-        if leading >= 32 {
-            leading = 31
-        }
+#[deprecated(note = "use XORChunk::append")]
+pub struct XORAppender<'a> {
+    chunk: &'a mut XORChunk,
+}
 
-        if self.leading != 0xff && leading >= self.leading && trailing >= self.trailing {
-            self.b.write_bit(false);
-            self.b.write_bits(v_delta >> self.trailing, 64 - self.leading as i32 - self.trailing as i32);
-        } else {
-            self.leading = leading;
-            self.trailing = trailing;
-            self.b.write_bit(true);
-            self.b.write_bits(leading as u64,5);
-            // Note that if leading == trailing == 0, then sigbits == 64.  But that value doesn't actually fit into the 6 bits we have.
+#[allow(deprecated)]
+impl XORAppender<'_> {
+    pub fn append(&mut self, t:i64, v:f64) -> Result<(), Error> {
+        self.chunk.append(t, v)
+    }
+}
+
+fn write_v_delta(b:&mut Bstream, state:&mut AppenderState, v:f64) {
+    let v_delta = v.to_bits() ^ state.v.to_bits();
+    if v_delta == 0 {
+        b.write_bit(false);
+        return;
+    }
+    // otherwise, write a '1' anyway
+    b.write_bit(true);
+
+    let mut leading = v_delta.leading_zeros() as u8;
+    let trailing = v_delta.trailing_zeros() as u8;
+    if leading >= 32 {
+        leading = 31
+    }
+
+    if state.leading != 0xff && leading >= state.leading && trailing >= state.trailing {
+        b.write_bit(false);
+        b.write_bits(v_delta >> state.trailing, 64 - state.leading as i32 - state.trailing as i32);
+    } else {
+        state.leading = leading;
+        state.trailing = trailing;
+        b.write_bit(true);
+        b.write_bits(leading as u64,5);
+        // Note that if leading == trailing == 0, then sigbits == 64.  But that value doesn't actually fit into the 6 bits we have.
 		    // Luckily, we never need to encode 0 significant bits, since that would put us in the other case (vdelta == 0).
 		    // So instead we write out a 0 and adjust it back to 64 on unpacking.
 
-            // with write_bits if we write overflow bits it will just write 0
-            let sigbits = 64 - leading - trailing;
-            self.b.write_bits(sigbits as u64, 6);
-            self.b.write_bits(v_delta >> self.trailing, sigbits as i32);
+        // with write_bits if we write overflow bits it will just write 0
+        let sigbits = 64 - leading - trailing;
+        b.write_bits(sigbits as u64, 6);
+        b.write_bits(v_delta >> state.trailing, sigbits as i32);
+
+    }
+}
+
+// ChunkSnapshot is an immutable copy of a chunk as it was when the snapshot
+// was taken. It shares the bytes with the chunk, which copies them on its next
+// append or seal as long as the snapshot is around, and it can be read on any
+// thread while the chunk is appended to.
+// It derefs to the XORChunk, which gives access to all the read methods.
+#[derive(Clone)]
+pub struct ChunkSnapshot {
+    chunk: XORChunk,
+}
+
+impl Deref for ChunkSnapshot {
+    type Target = XORChunk;
+    fn deref(&self) -> &XORChunk {
+        &self.chunk
+    }
+}
+
+// ChunkView is a chunk read from elsewhere, a segment for example, without
+// copying or decoding it. The bytes are borrowed if they can be, and nothing
+// is decoded until it is asked for: the meta of a sealed chunk comes from its
+// footer and seek uses its index.
+#[derive(Debug, Clone)]
+pub struct ChunkView<'a> {
+    b: Cow<'a, [u8]>,
+}
 
+impl<'a> ChunkView<'a> {
+    pub fn new(b:impl Into<Cow<'a, [u8]>>) -> Result<ChunkView<'a>, Error> {
+        let b = b.into();
+        if b.len() < 2 {
+            return Err(Error::Bstream(BstreamError::UnexpectedEof));
         }
+        Ok(ChunkView { b })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.b
+    }
+
+    pub fn byte_len(&self) -> usize {
+        self.b.len()
+    }
+
+    pub fn num_samples(&self) -> u16 {
+        u16::from_be_bytes([self.b[0],self.b[1]]) & HEADER_NUM_MASK
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        is_sealed(&self.b)
+    }
+
+    pub fn footer(&self) -> Result<Footer<'_>, Error> {
+        footer(&self.b)
+    }
+
+    // meta returns the meta from the footer of a sealed chunk, any other
+    // chunk is decoded to recover it.
+    pub fn meta(&self) -> Result<ChunkMeta, Error> {
+        if self.is_sealed() {
+            return self.footer()?.meta.ok_or(Error::InvalidFooter);
+        }
+        Ok(scan(&self.b)?.0)
+    }
+
+    pub fn iterator(&self) -> XORIterator<'_> {
+        XORIterator::new(&self.b)
+    }
+
+    // seek is XORChunk::seek on the viewed bytes.
+    pub fn seek(&self, t:i64) -> XORIterator<'_> {
+        seek(&self.b, t)
+    }
+
+    // to_chunk copies the bytes into an XORChunk, see XORChunk::from_bytes.
+    pub fn to_chunk(&self) -> Result<XORChunk, Error> {
+        XORChunk::from_bytes(self.b.to_vec())
     }
 }
 
 // bitRange returns whether the given integer can be represented by nbits.
 fn bit_range(x:i64,nbits:u8) -> bool {
-    return -((1<<(nbits-1))-1) <= x && x <= 1<<(nbits-1)
+    -((1<<(nbits-1))-1) <= x && x <= 1<<(nbits-1)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
-    IO(io::Error),
+    Bstream(BstreamError),
+    ChunkSealed,
+    InvalidFooter,
+    DuplicateSample(i64),
+    InvalidAppenderState,
+    OutOfOrderSample(i64),
+    // the sample count in the header can't go any higher
+    ChunkFull,
+    // the sample is further from the previous one than an i64 can hold
+    TimestampOverflow(i64),
 }
-#[derive(Debug)]
-struct XORIterator<'a> {
+
+impl From<BstreamError> for Error {
+    fn from(err: BstreamError) -> Error {
+        Error::Bstream(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bstream(err) => write!(f, "xor chunk: {}", err),
+            Error::ChunkSealed => write!(f, "xor chunk: chunk is sealed"),
+            Error::InvalidFooter => write!(f, "xor chunk: invalid footer"),
+            Error::DuplicateSample(t) => write!(f, "xor chunk: duplicate sample at {}", t),
+            Error::InvalidAppenderState => write!(f, "xor chunk: invalid appender state"),
+            Error::OutOfOrderSample(t) => write!(f, "xor chunk: out of order sample at {}", t),
+            Error::ChunkFull => write!(f, "xor chunk: chunk is full"),
+            Error::TimestampOverflow(t) => write!(f, "xor chunk: timestamp {} too far from the previous one", t),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+// SampleIterator is an iterator over (timestamp, value) samples that can tell
+// why it stopped. Iterators stop at the first error, err returns it.
+pub trait SampleIterator: Iterator<Item = (i64, f64)> {
+    fn err(&self) -> Option<&Error>;
+}
+
+impl SampleIterator for XORIterator<'_> {
+    fn err(&self) -> Option<&Error> {
+        self.err.as_ref()
+    }
+}
+
+impl SampleIterator for XORRangeIterator<'_> {
+    fn err(&self) -> Option<&Error> {
+        self.it.err()
+    }
+}
+
+impl SampleIterator for XORReverseIterator<'_> {
+    fn err(&self) -> Option<&Error> {
+        self.err.as_ref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct XORIterator<'a> {
     br:BstreamReader<'a>,
     num_total:u16,
     num_read:u16,
@@ -176,122 +748,353 @@ struct XORIterator<'a> {
     trailing: u8,
 
     t_delta:u64,
-    err: Option<Error>, // todo: maybe no need this field
+    err: Option<Error>,
+    held: bool, // next returns the current sample again, set by seek
 }
 
 impl<'a> XORIterator<'a> {
     pub fn new(stream: &'a [u8]) -> XORIterator<'a> {
-        // read first 2 bytes as chunk header
-        let mut br = BstreamReader::new(stream);
-        let mut num_total:u16 = 0;
-        let mut byt = br.read_byte().unwrap();
-        num_total = num_total | byt as u16;
-        byt = br.read_byte().unwrap();
-        num_total = (num_total << 8) | byt as u16;
-        XORIterator {
-            br,
-            num_total,
+        let mut it = XORIterator {
+            br: BstreamReader::new(stream),
+            num_total:0,
             num_read:0,
             t:-1 << 63,
             val:0.0,
             leading:0,
             trailing:0,
             t_delta:0,
-            err: None
-        }
-    }
-    fn read_bit(&mut self) -> Result<u8, io::Error> {
-        match self.br.read_bit_fast() {
-            Ok(b) => Ok(b as u8),
-            Err(_) => self.br.read_bit().map(|b| b as u8)
-        }
+            err: None,
+            held: false,
+        };
+        it.reset(stream);
+        it
     }
-    fn read_bits(&mut self, n: u8) -> Result<u64, io::Error> {
-        match self.br.read_bits_fast(n) {
-            Ok(b) => Ok(b),
-            Err(_) => self.br.read_bits(n)
+
+    // reset points the iterator at the start of another chunk, so one
+    // iterator can be reused for many chunks.
+    pub fn reset(&mut self, stream: &'a [u8]) {
+        self.br.reset(stream);
+        self.num_read = 0;
+        self.t = -1 << 63;
+        self.val = 0.0;
+        self.leading = 0;
+        self.trailing = 0;
+        self.t_delta = 0;
+        self.err = None;
+        self.held = false;
+
+        // read first 2 bytes as chunk header
+        match self.br.read_bits(16) {
+            Ok(header) => self.num_total = header as u16 & HEADER_NUM_MASK,
+            Err(err) => {
+                self.num_total = 0;
+                self.err = Some(Error::Bstream(err));
+            }
         }
     }
 }
 
 impl Iterator for XORIterator<'_> {
-    type Item = ();
+    type Item = (i64, f64);
     fn next(&mut self) -> Option<Self::Item> {
+        self.step(false)
+    }
+}
+
+impl XORIterator<'_> {
+    // next_bitwise is next as it was before DOD_PREFIX_TABLE, it reads the
+    // delta-of-delta prefix one bit at a time. Only benches/decode.rs uses it,
+    // to compare the two.
+    #[doc(hidden)]
+    pub fn next_bitwise(&mut self) -> Option<(i64, f64)> {
+        self.step(true)
+    }
+
+    #[inline(always)]
+    fn step(&mut self, bitwise:bool) -> Option<(i64, f64)> {
+        if self.held {
+            self.held = false;
+            return Some((self.t, self.val));
+        }
         if self.err.is_some() || self.num_read == self.num_total {
             return None;
         }
-        
-        // read first data point
-        if self.num_read == 0 {
-            match self.br.read_varint() {
-                Ok(t) => {
-                    self.t = t;
-                },
-                Err(err) => {
-                    self.err = Some(Error::IO(err));
-                    return None;
-                }
+        let res = match self.num_read {
+            0 => self.read_first(),
+            1 => self.read_second(),
+            _ if bitwise => self.read_dod_prefix().and_then(|sz| self.read_dod_value(sz)),
+            _ => self.read_dod(),
+        };
+        if let Err(err) = res {
+            self.err = Some(Error::Bstream(err));
+            return None;
+        }
+        self.num_read +=1;
+        Some((self.t, self.val))
+    }
+}
+
+// XORRangeIterator yields the samples of a chunk with mint <= t <= maxt.
+// Samples before mint are decoded but never handed out, and decoding stops
+// at the first sample after maxt.
+#[derive(Debug)]
+pub struct XORRangeIterator<'a> {
+    it: XORIterator<'a>,
+    mint: i64,
+    maxt: i64,
+}
+
+impl<'a> XORRangeIterator<'a> {
+    pub fn new(it: XORIterator<'a>, mint:i64, maxt:i64) -> XORRangeIterator<'a> {
+        XORRangeIterator { it, mint, maxt }
+    }
+
+    pub fn err(&self) -> Option<&Error> {
+        self.it.err()
+    }
+}
+
+impl Iterator for XORRangeIterator<'_> {
+    type Item = (i64, f64);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (t, v) = self.it.next()?;
+            if t > self.maxt {
+                // Nothing after maxt is of interest, mark the iterator as exhausted.
+                self.it.num_read = self.it.num_total;
+                return None;
             }
-            match self.br.read_bits(64) {
-                Ok(val) => {
-                    self.val = f64::from_bits(val);
-                },
-                Err(err) => {
-                    self.err = Some(Error::IO(err));
-                    return None;
-                }
+            if t >= self.mint {
+                return Some((t, v));
             }
-            self.num_read +=1;
-            return Some(());
         }
-        // read second data point
-        if self.num_read == 1 {
-            match self.br.read_uvarint() {
-                Ok(t_delta) => {
-                    self.t_delta = t_delta;
-                },
-                Err(err) => {
-                    self.err = Some(Error::IO(err));
-                    return None;
-                }
+    }
+}
+
+// XORReverseIterator yields the samples of a chunk newest first. The encoding
+// can only be decoded forwards, so it decodes a block of samples at a time into
+// a buffer and hands them out backwards. Blocks start at the seek index entries
+// of a sealed chunk, without an index the whole chunk is a single block.
+// Like XORIterator it stops at the first error, which err returns.
+#[derive(Debug)]
+pub struct XORReverseIterator<'a> {
+    start: XORIterator<'a>,
+    index: Option<SeekIndex<'a>>,
+    blocks: usize, // blocks not loaded yet
+    buf: Vec<(i64, f64)>,
+    err: Option<Error>,
+}
+
+impl<'a> XORReverseIterator<'a> {
+    pub fn new(chunk: &'a XORChunk) -> XORReverseIterator<'a> {
+        let start = chunk.iterator();
+        let (index, err) = match chunk.footer() {
+            Ok(footer) => (footer.index, None),
+            Err(err) => (None, Some(err)),
+        };
+        let blocks = index.map_or(0, |index| index.len()) + 1;
+        XORReverseIterator {
+            start,
+            index,
+            blocks,
+            buf: Vec::new(),
+            err,
+        }
+    }
+
+    pub fn err(&self) -> Option<&Error> {
+        self.err.as_ref()
+    }
+
+    // load_block decodes block i into the buffer.
+    fn load_block(&mut self, i:usize) {
+        let mut it = self.start.clone();
+        let mut end = it.num_total;
+        if let Some(index) = self.index {
+            if i > 0 {
+                it.restore(&index.entry(i - 1));
+            }
+            if i < index.len() {
+                end = index.entry(i).num_read;
+            }
+        }
+        self.buf.clear();
+        while it.num_read < end {
+            match it.next() {
+                Some(sample) => self.buf.push(sample),
+                None => break,
             }
-            self.t = self.t + self.t_delta as i64;
-            return self.read_value();
         }
+        if let Some(err) = it.err {
+            // don't hand out a partial block
+            self.buf.clear();
+            self.err = Some(err);
+        }
+    }
+}
 
-        // read rest data point
-        let mut d:u8 = 0;
-        for _i in 0..4 {
-            d = d << 1;
-            let bit = self.read_bit().ok()?;
-            if bit == 0 {
+impl Iterator for XORReverseIterator<'_> {
+    type Item = (i64, f64);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(sample) = self.buf.pop() {
+                return Some(sample);
+            }
+            if self.err.is_some() || self.blocks == 0 {
+                return None;
+            }
+            self.blocks -= 1;
+            self.load_block(self.blocks);
+        }
+    }
+}
+
+// DOD_PREFIX_TABLE maps the next four bits of the stream to the length of the
+// delta-of-delta prefix they start with and the width of the payload that
+// follows it. A width of 0 means dod == 0, a width of 64 means the raw value.
+const DOD_PREFIX_TABLE: [(u8, u8); 16] = [
+    (1, 0), (1, 0), (1, 0), (1, 0), (1, 0), (1, 0), (1, 0), (1, 0), // 0xxx
+    (2, 14), (2, 14), (2, 14), (2, 14),                             // 10xx
+    (3, 17), (3, 17),                                               // 110x
+    (4, 20),                                                        // 1110
+    (4, 64),                                                        // 1111
+];
+
+impl<'a> XORIterator<'a> {
+    // at returns the sample the iterator is currently positioned at.
+    pub fn at(&self) -> (i64, f64) {
+        (self.t, self.val)
+    }
+
+    // err returns the error that stopped the iteration, if any.
+    pub fn err(&self) -> Option<&Error> {
+        self.err.as_ref()
+    }
+
+    // state returns the decoder state right before the next sample. It
+    // doesn't cover the sample an iterator returned by seek holds back.
+    pub fn state(&self) -> IndexEntry {
+        IndexEntry {
+            bit_offset: self.br.position() as u32,
+            num_read: self.num_read,
+            t: self.t,
+            val: self.val,
+            t_delta: self.t_delta,
+            leading: self.leading,
+            trailing: self.trailing,
+        }
+    }
+
+    // restore moves the iterator to a state previously taken with state on
+    // the same chunk.
+    pub fn restore(&mut self, state:&IndexEntry) {
+        if let Err(err) = self.br.seek(state.bit_offset as usize) {
+            self.err = Some(Error::Bstream(err));
+            return;
+        }
+        if state.num_read > self.num_total {
+            self.err = Some(Error::InvalidFooter);
+            return;
+        }
+        self.num_read = state.num_read;
+        self.t = state.t;
+        self.val = state.val;
+        self.t_delta = state.t_delta;
+        self.leading = state.leading;
+        self.trailing = state.trailing;
+        self.held = false;
+    }
+
+    // decode_into decodes the remaining samples and appends the ones within
+    // [mint, maxt] to ts and vs. It stops at the first timestamp after maxt
+    // and returns the number of samples appended.
+    fn decode_into(&mut self, mint:i64, maxt:i64, ts:&mut Vec<i64>, vs:&mut Vec<f64>) -> Result<usize, Error> {
+        let start = ts.len();
+        let remaining = (self.num_total - self.num_read) as usize;
+        ts.reserve(remaining);
+        vs.reserve(remaining);
+
+        // the sample seek stopped at
+        if self.held {
+            self.held = false;
+            if self.t > maxt {
+                return Ok(0);
+            }
+            if self.t >= mint {
+                ts.push(self.t);
+                vs.push(self.val);
+            }
+        }
+        // The first two samples are encoded differently, go through next for them.
+        while self.num_read < 2 && self.next().is_some() {
+            if self.t > maxt {
+                return Ok(ts.len() - start);
+            }
+            if self.t >= mint {
+                ts.push(self.t);
+                vs.push(self.val);
+            }
+        }
+        if let Some(err) = &self.err {
+            return Err(err.clone());
+        }
+
+        while self.num_read < self.num_total {
+            if let Err(err) = self.read_dod() {
+                self.err = Some(Error::Bstream(err));
+                return Err(Error::Bstream(err));
+            }
+            self.num_read +=1;
+            if self.t > maxt {
                 break
             }
-            d = d | 1;
+            if self.t >= mint {
+                ts.push(self.t);
+                vs.push(self.val);
+            }
         }
-        let mut sz:u8 = 0;
-        let mut dod:i64 = 0;
-        match d {
-            0b0 => (),// dod == 0;
-            0b10 =>{
-                sz = 14;
-            },
-            0b110 => {
-                sz = 17;
-            },
-            0b1110 => {
-                sz = 20;
-            },
-            0b1111 => {
-                // Do not use fast because it's very unlikely it will succeed.
-                let bits = self.br.read_bits(64).ok()?;
-                dod = bits as i64;
+        Ok(ts.len() - start)
+    }
+
+    // read_first reads the first sample, which is stored as is.
+    fn read_first(&mut self) -> Result<(), BstreamError> {
+        self.t = self.br.read_varint()?;
+        self.val = f64::from_bits(self.br.read_bits(64)?);
+        Ok(())
+    }
+
+    // read_second reads the second sample, whose timestamp is stored as a delta.
+    fn read_second(&mut self) -> Result<(), BstreamError> {
+        self.t_delta = self.br.read_uvarint()?;
+        self.t += self.t_delta as i64;
+        self.read_value()
+    }
+
+    // read_dod reads any later sample, whose timestamp is stored as a delta of deltas.
+    fn read_dod(&mut self) -> Result<(), BstreamError> {
+        let sz = match self.br.peek_bits(4) {
+            Ok(bits) => {
+                let (prefix_len, sz) = DOD_PREFIX_TABLE[bits as usize];
+                self.read_bits_or_fast(prefix_len)?;
+                sz
             },
-            _ => {},
-        }
+            // Fewer than four bits are left in the stream, which can only
+            // happen for the last sample. Fall back to reading bit by bit.
+            Err(_) => self.read_dod_prefix()?,
+        };
+        self.read_dod_value(sz)
+    }
 
-        if sz != 0 {
-            let mut bits = self.read_bits_or_fast(sz).ok()?;
+    // read_dod_value reads the delta-of-delta of width sz behind the prefix
+    // and the value of the sample.
+    fn read_dod_value(&mut self, sz:u8) -> Result<(), BstreamError> {
+        let mut dod:i64 = 0;
+        if sz == 64 {
+            // Do not use fast because it's very unlikely it will succeed.
+            let bits = self.br.read_bits(64)?;
+            dod = bits as i64;
+        } else if sz != 0 {
+            let mut bits = self.read_bits_or_fast(sz)?;
             if bits > (1 << (sz -1)) {
                 //bits = bits - (1 << sz);
                 bits = bits.wrapping_sub(1<<sz);
@@ -299,63 +1102,82 @@ impl Iterator for XORIterator<'_> {
             dod = bits as i64;
         }
         self.t_delta = (self.t_delta as i64+ dod) as u64;
-        self.t = self.t + (self.t_delta as i64);
-        return  self.read_value();
+        self.t += self.t_delta as i64;
+        self.read_value()
     }
-}
 
-impl<'a> XORIterator<'a> {
-    fn read_bit_or_fast(&mut self) -> Result<u8, io::Error> {
+    // read_dod_prefix reads the delta-of-delta prefix one bit at a time and
+    // returns the payload width it selects, see DOD_PREFIX_TABLE.
+    fn read_dod_prefix(&mut self) -> Result<u8, BstreamError> {
+        let mut d:u8 = 0;
+        for _i in 0..4 {
+            d <<= 1;
+            let bit = self.read_bit_or_fast()?;
+            if bit == 0 {
+                break
+            }
+            d |= 1;
+        }
+        let sz = match d {
+            0b10 => 14,
+            0b110 => 17,
+            0b1110 => 20,
+            0b1111 => 64,
+            _ => 0, // dod == 0
+        };
+        Ok(sz)
+    }
+
+    fn read_bit_or_fast(&mut self) -> Result<u8, BstreamError> {
         match self.br.read_bit_fast() {
             Ok(b) => Ok(b as u8),
             Err(_) => self.br.read_bit().map(|b| b as u8)
         }
     }
 
-    fn read_bits_or_fast(&mut self, n: u8) -> Result<u64, io::Error> {
+    fn read_bits_or_fast(&mut self, n: u8) -> Result<u64, BstreamError> {
         match self.br.read_bits_fast(n) {
             Ok(b) => Ok(b),
             Err(_) => self.br.read_bits(n)
         }
     }
 
-    pub fn read_value(&mut self) -> Option<()>{
-        let bit = self.read_bit_or_fast().ok()?;
+    fn read_value(&mut self) -> Result<(), BstreamError> {
+        let bit = self.read_bit_or_fast()?;
         if bit == 0 {
             // do nothing
         }else {
-            let bit = self.read_bit_or_fast().ok()?;
+            let bit = self.read_bit_or_fast()?;
             if bit == 0 {
                 // reuse leading/trailing zero bits
                 // do nothing
             } else {
-                let bits = self.read_bits_or_fast(5).ok()? as u8;
+                let bits = self.read_bits_or_fast(5)? as u8;
                 self.leading = bits;
             
-                let mut mbits = self.read_bits_or_fast(6).ok()? as u8;
+                let mut mbits = self.read_bits_or_fast(6)? as u8;
                 if mbits == 0 {
                     mbits = 64;
                 }
                 self.trailing = 64 - self.leading - mbits;
             }
             let mbits = 64 - self.leading - self.trailing;
-            let bits = self.read_bits_or_fast(mbits).ok()?;
+            let bits = self.read_bits_or_fast(mbits)?;
             
             let mut vbits = f64::to_bits(self.val);
-            vbits = vbits ^ (bits << self.trailing);
+            vbits ^= bits << self.trailing;
             self.val = f64::from_bits(vbits);
         }
-        self.num_read +=1;
-        return Some(());
+        Ok(())
     }
 }
 
 
-
 #[test]
 fn test_xor_chunk() {
+    use rand::Rng;
+
     let mut chunk = XORChunk::new();
-    let mut appender = chunk.appender().unwrap();
 
     #[derive(Debug,PartialEq)]
     struct DataPoint {
@@ -364,22 +1186,22 @@ fn test_xor_chunk() {
     }
 
     let mut cases = vec![];
-    let mut ts = 1234123324 as i64;
+    let mut ts = 1234123324_i64;
     let mut val = 1243535.123;
     for i in 0..300 {
-        ts = ts + rand::thread_rng().gen_range(1..10001);
+        ts += rand::thread_rng().gen_range(1..10001);
         if i % 2 == 0 {
-            val = val + rand::thread_rng().gen_range(1..1000000) as f64;
+            val += rand::thread_rng().gen_range(1..1000000) as f64;
         } else {
-            val = val - rand::thread_rng().gen_range(1..1000000) as f64;
+            val -= rand::thread_rng().gen_range(1..1000000) as f64;
         }
 
-        // Start with a new appender every 10th sample. This emulates starting
+        // Reload the chunk from its bytes every 10th sample. This emulates
 		// appending to a partially filled chunk.
         if i %10 == 0 {
-            appender = chunk.appender().unwrap();
+            chunk = XORChunk::from_bytes(chunk.bytes().to_vec()).unwrap();
         }
-        appender.Append(ts, val);
+        chunk.append(ts, val).unwrap();
         cases.push(DataPoint{
             ts,
             val
@@ -408,4 +1230,404 @@ fn test_xor_chunk() {
     }
     assert_eq!(res_2, cases);
     reader.next();
-}
\ No newline at end of file
+}
+#[test]
+fn test_xor_chunk_dod_widths() {
+    // One delta-of-delta per encoding width, ending on a zero dod so the
+    // last prefix has fewer than four bits left behind it.
+    let deltas = [10, 10, 10 + 1000, 10, 10 + 100_000, 10, 10 + 500_000, 10, 10 + (1 << 40), 10, 10];
+    let mut chunk = XORChunk::new();
+    let mut cases = vec![(0_i64, 0.5)];
+    chunk.append(0, 0.5).unwrap();
+    for (i, d) in deltas.iter().enumerate() {
+        let (t, _) = cases[i];
+        let sample = (t + d, i as f64);
+        chunk.append(sample.0, sample.1).unwrap();
+        cases.push(sample);
+    }
+
+    let mut it = chunk.iterator();
+    let mut res = vec![];
+    while it.next().is_some() {
+        res.push((it.t, it.val));
+    }
+    assert!(it.err.is_none());
+    assert_eq!(res, cases);
+
+    // the bitwise decoder benches/decode.rs compares with agrees
+    let mut it = chunk.iterator();
+    assert_eq!(cases, core::iter::from_fn(|| it.next_bitwise()).collect::<Vec<_>>());
+    assert!(it.err.is_none());
+}
+
+#[test]
+fn test_xor_chunk_decode_into() {
+    let mut chunk = XORChunk::new();
+    let mut cases = vec![];
+    for i in 0..200_i64 {
+        let sample = (1000 + i * 15 + (i * 7919) % 13, (i % 17) as f64 * 0.25);
+        chunk.append(sample.0, sample.1).unwrap();
+        cases.push(sample);
+    }
+
+    let (mut ts, mut vs) = (vec![], vec![]);
+    assert_eq!(200, chunk.decode_into(&mut ts, &mut vs).unwrap());
+    let res: Vec<_> = ts.iter().copied().zip(vs.iter().copied()).collect();
+    assert_eq!(res, cases);
+
+    // range variant appends to what is already in the columns
+    for (mint, maxt) in [(1000, 1000), (1001, 1020), (1500, 2500), (4000, 5000), (i64::MIN, 999)] {
+        let (mut ts, mut vs) = (vec![-1], vec![-1.0]);
+        let expected: Vec<_> = cases.iter().filter(|(t, _)| mint <= *t && *t <= maxt).copied().collect();
+        let n = chunk.decode_range_into(mint, maxt, &mut ts, &mut vs).unwrap();
+        assert_eq!(expected.len(), n);
+        let res: Vec<_> = ts[1..].iter().copied().zip(vs[1..].iter().copied()).collect();
+        assert_eq!(res, expected);
+    }
+
+    // a truncated chunk reports the error
+    let mut bytes = chunk.bytes().to_vec();
+    bytes.truncate(bytes.len() / 2);
+    let (mut ts, mut vs) = (vec![], vec![]);
+    let mut it = XORIterator::new(&bytes);
+    assert!(it.decode_into(i64::MIN, i64::MAX, &mut ts, &mut vs).is_err());
+    assert!(it.err.is_some());
+}
+
+#[test]
+fn test_xor_chunk_iter_range() {
+    let mut chunk = XORChunk::new();
+    let mut cases = vec![];
+    for i in 0..100_i64 {
+        let sample = (i * 10, i as f64);
+        chunk.append(sample.0, sample.1).unwrap();
+        cases.push(sample);
+    }
+
+    for (mint, maxt) in [(0, 990), (-5, 5), (15, 15), (20, 20), (333, 555), (990, 2000), (1000, 2000), (50, 40)] {
+        let expected: Vec<_> = cases.iter().filter(|(t, _)| mint <= *t && *t <= maxt).copied().collect();
+        let mut it = chunk.iter_range(mint, maxt);
+        let res: Vec<_> = it.by_ref().collect();
+        assert_eq!(res, expected);
+        assert!(it.err().is_none());
+        // an exhausted iterator stays exhausted
+        assert_eq!(None, it.next());
+    }
+}
+
+#[test]
+fn test_xor_chunk_seek() {
+    let mut cases = vec![];
+    let mut chunks = vec![];
+    for opts in [None, Some(SealOptions::default()), Some(SealOptions { index_interval: Some(16), ..Default::default() })] {
+        let mut chunk = XORChunk::new();
+        cases.clear();
+        for i in 0..1000_i64 {
+            let sample = (i * 10 + (i * 7919) % 7, ((i * 31) % 101) as f64 / 3.0);
+            chunk.append(sample.0, sample.1).unwrap();
+            cases.push(sample);
+        }
+        if let Some(opts) = opts {
+            chunk.seal(opts).unwrap();
+            assert!(chunk.is_sealed());
+            assert_eq!(Some(Error::ChunkSealed), chunk.append(i64::MAX, 0.0).err());
+            assert_eq!(Some(Error::ChunkSealed), chunk.seal(opts).err());
+        }
+        chunks.push(chunk);
+    }
+    let index = chunks[2].footer().unwrap().index.unwrap();
+    assert_eq!(16, index.interval());
+    assert_eq!(1000 / 16, index.len());
+    assert!(chunks[1].footer().unwrap().index.is_none());
+
+    for chunk in &chunks {
+        assert_eq!(cases, chunk.iterator().collect::<Vec<_>>());
+        for t in [i64::MIN, 0, 1, 5, 160, 161, 5000, 5003, 9990, 9999, 10_000] {
+            let expected: Vec<_> = cases.iter().filter(|(ts, _)| *ts >= t).copied().collect();
+            assert_eq!(expected, chunk.seek(t).collect::<Vec<_>>());
+            let expected: Vec<_> = expected.into_iter().filter(|(ts, _)| *ts <= t + 300).collect();
+            assert_eq!(expected, chunk.iter_range(t, t + 300).collect::<Vec<_>>());
+            let (mut ts, mut vs) = (vec![], vec![]);
+            chunk.decode_range_into(t, t + 300, &mut ts, &mut vs).unwrap();
+            assert_eq!(expected, ts.into_iter().zip(vs).collect::<Vec<_>>());
+        }
+    }
+
+    // a broken footer is reported by the iterator
+    let mut bytes = chunks[2].bytes().to_vec();
+    let flags = bytes.len() - 1;
+    bytes[flags] = 0x80;
+    let chunk = XORChunk { b: Arc::new(Bstream::new(bytes)), meta: chunks[2].meta(), state: AppenderState::default() };
+    let mut it = chunk.seek(5000);
+    assert_eq!(None, it.next());
+    assert_eq!(Some(&Error::InvalidFooter), it.err());
+}
+
+#[test]
+fn test_xor_chunk_iter_rev() {
+    for (n, opts) in [(0, None), (1, None), (300, None), (300, Some(SealOptions::default())), (300, Some(SealOptions { index_interval: Some(25), ..Default::default() })), (301, Some(SealOptions { index_interval: Some(7), ..Default::default() }))] {
+        let mut chunk = XORChunk::new();
+        let mut cases = vec![];
+        for i in 0..n {
+            let sample = (i * 1000 + (i * 7919) % 11, (i % 23) as f64 * 1.5);
+            chunk.append(sample.0, sample.1).unwrap();
+            cases.push(sample);
+        }
+        if let Some(opts) = opts {
+            chunk.seal(opts).unwrap();
+        }
+        cases.reverse();
+        let mut it = chunk.iter_rev();
+        assert_eq!(cases, it.by_ref().collect::<Vec<_>>());
+        assert!(it.err().is_none());
+    }
+
+    // a truncated chunk yields nothing and reports the error
+    let mut chunk = XORChunk::new();
+    for i in 0..100 {
+        chunk.append(i * 10, i as f64).unwrap();
+    }
+    let mut bytes = chunk.bytes().to_vec();
+    bytes.truncate(bytes.len() - 10);
+    let chunk = XORChunk { b: Arc::new(Bstream::new(bytes)), meta: chunk.meta(), state: AppenderState::default() };
+    let mut it = chunk.iter_rev();
+    assert_eq!(None, it.next());
+    assert!(it.err().is_some());
+}
+
+#[test]
+fn test_xor_chunk_meta() {
+    let mut chunk = XORChunk::new();
+    assert_eq!(0, chunk.num_samples());
+    assert_eq!(i64::MAX, chunk.min_time());
+    assert_eq!(i64::MIN, chunk.max_time());
+    assert_eq!(16, chunk.bit_len());
+
+    for i in 0..50 {
+        chunk.append(1000 + i * 15, i as f64).unwrap();
+    }
+    assert_eq!(50, chunk.num_samples());
+    assert_eq!(1000, chunk.min_time());
+    assert_eq!(1000 + 49 * 15, chunk.max_time());
+    assert!(chunk.bit_len() <= chunk.byte_len() * 8);
+    assert!(chunk.bit_len() > chunk.byte_len() * 8 - 16);
+
+    // an open chunk is decoded once and can be appended to right away
+    let mut loaded = XORChunk::from_bytes(chunk.bytes().to_vec()).unwrap();
+    assert_eq!(chunk.meta(), loaded.meta());
+    loaded.append(2000, -1.0).unwrap();
+    assert_eq!(2000, loaded.max_time());
+    assert_eq!(51, loaded.iterator().count());
+    assert_eq!(Some((2000, -1.0)), loaded.iterator().last());
+
+    // a sealed chunk carries its metadata in the footer
+    let meta = loaded.meta();
+    loaded.seal(SealOptions { index_interval: Some(8), ..Default::default() }).unwrap();
+    assert_eq!(Some(meta), loaded.footer().unwrap().meta);
+    let sealed = XORChunk::from_bytes(loaded.bytes().to_vec()).unwrap();
+    assert_eq!(meta, sealed.meta());
+    assert_eq!(51, sealed.num_samples());
+    assert_eq!(loaded.byte_len(), sealed.byte_len());
+
+    assert!(XORChunk::from_bytes(vec![0]).is_err());
+}
+
+#[test]
+fn test_xor_chunk_aggregate() {
+    let mut chunk = XORChunk::new();
+    assert_eq!(None, chunk.aggregate(i64::MIN, i64::MAX).unwrap());
+    let vals = [3.0, -1.5, 8.25, 0.0, 2.0, 2.0, -7.0, 4.5];
+    for (i, v) in vals.iter().enumerate() {
+        chunk.append(i as i64 * 10, *v).unwrap();
+    }
+    let expected = Aggregates { min: -7.0, max: 8.25, sum: 11.25, count: 8, first: 3.0, last: 4.5 };
+    assert_eq!(Some(expected), chunk.aggregate(0, 70).unwrap());
+
+    let mut sealed = XORChunk::from_bytes(chunk.bytes().to_vec()).unwrap();
+    sealed.seal(SealOptions { aggregates: true, ..Default::default() }).unwrap();
+    assert_eq!(Some(expected), sealed.footer().unwrap().aggregates);
+    let sealed = XORChunk::from_bytes(sealed.bytes().to_vec()).unwrap();
+    assert_eq!(Some(expected), sealed.aggregate(i64::MIN, i64::MAX).unwrap());
+
+    // partial ranges are decoded
+    for c in [&chunk, &sealed] {
+        let partial = Aggregates { min: -1.5, max: 8.25, sum: 6.75, count: 3, first: -1.5, last: 0.0 };
+        assert_eq!(Some(partial), c.aggregate(5, 30).unwrap());
+        assert_eq!(None, c.aggregate(71, 100).unwrap());
+    }
+
+    // a chunk sealed without aggregates still answers from its samples
+    chunk.seal(SealOptions::default()).unwrap();
+    assert_eq!(None, chunk.footer().unwrap().aggregates);
+    assert_eq!(Some(expected), chunk.aggregate(0, 70).unwrap());
+}
+
+#[test]
+fn test_xor_chunk_iterator_reuse() {
+    let mut chunks = vec![];
+    for n in [5_i64, 0, 120, 1] {
+        let mut chunk = XORChunk::new();
+        for i in 0..n {
+            chunk.append(n * 1000 + i, (n + i) as f64).unwrap();
+        }
+        chunks.push(chunk);
+    }
+    let broken = [0x00];
+
+    let mut it = None;
+    for chunk in &chunks {
+        let mut reused = chunk.iterator_reuse(it.take());
+        assert_eq!(chunk.iterator().collect::<Vec<_>>(), reused.by_ref().collect::<Vec<_>>());
+        assert!(reused.err().is_none());
+        it = Some(reused);
+    }
+
+    // errors don't stick to a reset iterator
+    let mut reused = it.unwrap();
+    reused.reset(&broken);
+    assert_eq!(None, reused.next());
+    assert!(reused.err().is_some());
+    let mut reused = chunks[2].iterator_reuse(Some(reused));
+    assert_eq!(120, reused.by_ref().count());
+    assert!(reused.err().is_none());
+}
+
+#[test]
+fn test_xor_chunk_appender_state() {
+    let sample = |i: i64| (5000 + i * 30 + (i * 7919) % 17, ((i * 37) % 11) as f64 * 0.75);
+
+    // appending to one chunk vs. reloading it with its state before every sample
+    let mut once = XORChunk::new();
+    for i in 0..120 {
+        let (t, v) = sample(i);
+        once.append(t, v).unwrap();
+    }
+    let mut resumed = XORChunk::new();
+    for i in 0..120 {
+        let (t, v) = sample(i);
+        resumed = XORChunk::from_bytes_with_state(resumed.bytes().to_vec(), resumed.appender_state()).unwrap();
+        resumed.append(t, v).unwrap();
+    }
+    assert_eq!(once.bytes(), resumed.bytes());
+    assert_eq!(120, once.appender_state().num_samples);
+    assert_eq!(once.bit_len(), once.appender_state().bit_len as usize);
+
+    // persist a partially filled chunk with its state and continue on it
+    let mut partial = XORChunk::new();
+    for i in 0..60 {
+        let (t, v) = sample(i);
+        partial.append(t, v).unwrap();
+    }
+    let state = AppenderState::decode(&partial.appender_state().encode()).unwrap();
+    assert_eq!(partial.appender_state(), state);
+    let mut loaded = XORChunk::from_bytes_with_state(partial.bytes().to_vec(), state).unwrap();
+    assert_eq!(partial.meta(), loaded.meta());
+    // decoding recovers the same state
+    assert_eq!(state, XORChunk::from_bytes(partial.bytes().to_vec()).unwrap().appender_state());
+    for i in 60..120 {
+        let (t, v) = sample(i);
+        loaded.append(t, v).unwrap();
+    }
+    assert_eq!(once.bytes(), loaded.bytes());
+    assert_eq!(once.meta(), loaded.meta());
+
+    // a state that doesn't belong to the chunk is ignored
+    let stale = AppenderState { num_samples: 3, ..state };
+    let loaded = XORChunk::from_bytes_with_state(partial.bytes().to_vec(), stale).unwrap();
+    assert_eq!(state, loaded.appender_state());
+    assert_eq!(Err(Error::InvalidAppenderState), AppenderState::decode(&[0; 8]));
+}
+
+#[test]
+fn test_xor_chunk_snapshot() {
+    let mut chunk = XORChunk::new();
+    for i in 0..50 {
+        chunk.append(i * 10, i as f64).unwrap();
+    }
+    let snap = chunk.snapshot();
+    assert_eq!(50, snap.num_samples());
+    assert_eq!(chunk.meta(), snap.meta());
+    assert_eq!(chunk.bit_len().div_ceil(8), snap.byte_len());
+    // nothing is copied until the chunk is appended to
+    assert_eq!(chunk.bytes().as_ptr(), snap.bytes().as_ptr());
+
+    // the chunk keeps growing while another thread reads the snapshot
+    let reader = std::thread::spawn(move || snap.iterator().collect::<Vec<_>>());
+    for i in 50..100 {
+        chunk.append(i * 10, i as f64).unwrap();
+    }
+    let expected: Vec<_> = (0..50).map(|i| (i * 10, i as f64)).collect();
+    assert_eq!(expected, reader.join().unwrap());
+    assert_eq!(100, chunk.iterator().count());
+
+    let snap = chunk.snapshot();
+    chunk.seal(SealOptions::default()).unwrap();
+    assert!(!snap.is_sealed());
+    assert_eq!(chunk.iterator().collect::<Vec<_>>(), snap.iterator().collect::<Vec<_>>());
+    assert!(chunk.snapshot().is_sealed());
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_xor_appender() {
+    let mut chunk = XORChunk::new();
+    let mut app = chunk.appender().unwrap();
+    for i in 0..10 {
+        app.append(i * 10, i as f64).unwrap();
+    }
+    assert_eq!(Err(Error::OutOfOrderSample(0)), app.append(0, 0.0));
+    assert_eq!((0..10).map(|i| (i * 10, i as f64)).collect::<Vec<_>>(), chunk.iterator().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_xor_chunk_out_of_order() {
+    let mut chunk = XORChunk::new();
+    chunk.append(1000, 1.0).unwrap();
+    assert_eq!(Err(Error::OutOfOrderSample(999)), chunk.append(999, 2.0));
+    assert_eq!(Err(Error::OutOfOrderSample(1000)), chunk.append(1000, 2.0));
+    chunk.append(2000, 2.0).unwrap();
+    assert_eq!(Err(Error::OutOfOrderSample(i64::MIN)), chunk.append(i64::MIN, 3.0));
+    chunk.append(3000, 3.0).unwrap();
+    // rejected samples leave the chunk alone
+    assert_eq!(vec![(1000, 1.0), (2000, 2.0), (3000, 3.0)], chunk.iterator().collect::<Vec<_>>());
+    assert_eq!(3, chunk.num_samples());
+}
+
+#[test]
+fn test_xor_chunk_extreme_timestamps() {
+    let samples = [(i64::MIN, 1.0), (i64::MIN + 1, 2.0), (-1, 3.0), (i64::MAX - 1, 4.0), (i64::MAX, 5.0)];
+    let mut chunk = XORChunk::new();
+    for (t, v) in samples {
+        chunk.append(t, v).unwrap();
+    }
+    assert_eq!(samples.to_vec(), chunk.iterator().collect::<Vec<_>>());
+    chunk.seal(SealOptions { index_interval: Some(2), ..Default::default() }).unwrap();
+    assert_eq!(samples.to_vec(), chunk.iterator().collect::<Vec<_>>());
+    assert_eq!(samples[3..].to_vec(), chunk.seek(0).collect::<Vec<_>>());
+    assert_eq!(samples.iter().rev().copied().collect::<Vec<_>>(), chunk.iter_rev().collect::<Vec<_>>());
+
+    // the delta from a negative timestamp to i64::MAX doesn't fit an i64
+    let mut chunk = XORChunk::new();
+    chunk.append(-1, 0.0).unwrap();
+    assert_eq!(Err(Error::TimestampOverflow(i64::MAX)), chunk.append(i64::MAX, 1.0));
+    chunk.append(i64::MAX - 1, 1.0).unwrap();
+    assert_eq!(vec![(-1, 0.0), (i64::MAX - 1, 1.0)], chunk.iterator().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_xor_chunk_full() {
+    let mut chunk = XORChunk::new();
+    for t in 0..MAX_SAMPLES as i64 {
+        chunk.append(t, t as f64).unwrap();
+    }
+    assert_eq!(Err(Error::ChunkFull), chunk.append(MAX_SAMPLES as i64, 0.0));
+    assert!(!chunk.is_sealed());
+    assert_eq!(MAX_SAMPLES, chunk.num_samples());
+    let loaded = XORChunk::from_bytes(chunk.bytes().to_vec()).unwrap();
+    assert!(!loaded.is_sealed());
+    assert_eq!(MAX_SAMPLES as usize, loaded.iterator().count());
+    assert_eq!(MAX_SAMPLES as i64 - 1, loaded.max_time());
+    // it still seals
+    chunk.seal(SealOptions::default()).unwrap();
+    assert_eq!(MAX_SAMPLES, chunk.num_samples());
+}